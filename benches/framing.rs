@@ -0,0 +1,93 @@
+//! Compares the decoding of streamed responses with the copying decoder it replaced.
+//!
+//! Run with `cargo bench --features testing`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+use textsynth::engine::framing::{self, StreamFraming};
+use textsynth::prelude::TextCompletion;
+
+/// The previous JSON lines decoder, which drained every line into a new vector and rescanned the
+/// whole buffer for each chunk.
+fn decode_copying(chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let mut payloads = Vec::new();
+
+    for chunk in chunks {
+        buffer.extend_from_slice(chunk);
+
+        while let Some(position) = buffer.iter().position(|&byte| byte == b'\n') {
+            let mut line = buffer.drain(..=position).collect::<Vec<_>>();
+            line.pop();
+
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+
+            if !line.iter().all(u8::is_ascii_whitespace) {
+                payloads.push(line);
+            }
+        }
+    }
+
+    if !buffer.iter().all(u8::is_ascii_whitespace) {
+        payloads.push(buffer);
+    }
+
+    payloads
+}
+
+/// A token by token response: one short record per chunk.
+fn token_by_token() -> Vec<u8> {
+    let mut body = (0..1000)
+        .map(|index| format!("{{\"text\": \" token{index}\", \"reached_end\": false}}\n"))
+        .collect::<String>();
+    body.push_str("{\"text\": \"\", \"reached_end\": true, \"total_tokens\": 1000}\n");
+    body.into_bytes()
+}
+
+/// A single long record split into many small chunks.
+fn long_record() -> Vec<u8> {
+    format!(
+        "{{\"text\": \"{}\", \"reached_end\": true}}\n",
+        "token ".repeat(20_000),
+    )
+    .into_bytes()
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+
+    for (name, body, chunk_size) in [
+        ("token_by_token", token_by_token(), 48),
+        ("long_record", long_record(), 16),
+    ] {
+        let chunks = body.chunks(chunk_size).collect::<Vec<_>>();
+        group.throughput(Throughput::Bytes(body.len() as u64));
+        group.bench_with_input(BenchmarkId::new("buffered", name), &chunks, |b, chunks| {
+            b.iter(|| framing::decode_chunks(StreamFraming::JsonLines, black_box(chunks)))
+        });
+        group.bench_with_input(BenchmarkId::new("copying", name), &chunks, |b, chunks| {
+            b.iter(|| decode_copying(black_box(chunks)))
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_deserialize(c: &mut Criterion) {
+    let body = token_by_token();
+    let chunks = body.chunks(48).collect::<Vec<_>>();
+
+    c.bench_function("decode_and_deserialize/token_by_token", |b| {
+        b.iter(|| {
+            framing::decode_chunks(StreamFraming::JsonLines, black_box(&chunks))
+                .iter()
+                .map(|payload| serde_json::from_slice::<TextCompletion>(payload).unwrap())
+                .collect::<Vec<_>>()
+        })
+    });
+}
+
+criterion_group!(benches, bench_decode, bench_deserialize);
+criterion_main!(benches);
@@ -0,0 +1,123 @@
+//! Compares deserializing responses with `simd-json` and `serde_json`. simd-json is faster on
+//! large bodies, but no faster on the short records of streams, even reusing its buffers, so the
+//! crate only uses it for whole response bodies.
+//!
+//! Run with `cargo bench --features testing,simd-json --bench json`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+use textsynth::engine::framing::{self, StreamFraming};
+use textsynth::prelude::TextCompletion;
+
+/// A large response: a long completion with the log probabilities of each of its tokens and their
+/// most likely alternatives.
+fn large_response() -> Vec<u8> {
+    const TOKENS: usize = 2000;
+
+    let text = (0..TOKENS)
+        .map(|index| format!(" token{index}\\n"))
+        .collect::<String>();
+    let token_logprobs = (0..TOKENS)
+        .map(|index| format!("-{}.{index}", index % 7))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let top_logprobs = (0..TOKENS)
+        .map(|index| {
+            format!(
+                "{{\" token{index}\": -0.{index}, \" other{index}\": -1.{index}, \" else{index}\": \
+                 -2.{index}}}"
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    format!(
+        "{{\"text\": \"{text}\", \"reached_end\": true, \"input_tokens\": 16, \"output_tokens\": \
+         {TOKENS}, \"token_logprobs\": [{token_logprobs}], \"top_logprobs\": [{top_logprobs}]}}"
+    )
+    .into_bytes()
+}
+
+/// A streamed response: one short record per token.
+fn streamed_response() -> Vec<u8> {
+    let mut body = (0..1000)
+        .map(|index| format!("{{\"text\": \" token{index}\\n\", \"reached_end\": false}}\n"))
+        .collect::<String>();
+    body.push_str("{\"text\": \"\", \"reached_end\": true, \"total_tokens\": 1000}\n");
+    body.into_bytes()
+}
+
+fn bench_large(c: &mut Criterion) {
+    let body = large_response();
+    let mut group = c.benchmark_group("large_response");
+    group.throughput(Throughput::Bytes(body.len() as u64));
+
+    group.bench_with_input(BenchmarkId::new("serde_json", "large"), &body, |b, body| {
+        b.iter(|| serde_json::from_slice::<TextCompletion>(black_box(body)).unwrap())
+    });
+    // simd-json parses in place, so each iteration gets a fresh buffer, untimed
+    group.bench_with_input(BenchmarkId::new("simd_json", "large"), &body, |b, body| {
+        b.iter_batched(
+            || body.clone(),
+            |mut body| {
+                simd_json::serde::from_slice::<TextCompletion>(black_box(&mut body)).unwrap()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+fn bench_streamed(c: &mut Criterion) {
+    let body = streamed_response();
+    let chunks = body.chunks(48).collect::<Vec<_>>();
+    let mut group = c.benchmark_group("streamed_response");
+    group.throughput(Throughput::Bytes(body.len() as u64));
+
+    group.bench_with_input(
+        BenchmarkId::new("serde_json", "streamed"),
+        &chunks,
+        |b, chunks| {
+            b.iter_batched(
+                || framing::decode_chunks(StreamFraming::JsonLines, chunks),
+                |payloads| {
+                    payloads
+                        .iter()
+                        .map(|payload| serde_json::from_slice::<TextCompletion>(payload).unwrap())
+                        .collect::<Vec<_>>()
+                },
+                BatchSize::SmallInput,
+            )
+        },
+    );
+    group.bench_with_input(
+        BenchmarkId::new("simd_json", "streamed"),
+        &chunks,
+        |b, chunks| {
+            b.iter_batched(
+                || framing::decode_chunks(StreamFraming::JsonLines, chunks),
+                |payloads| {
+                    let mut buffers = simd_json::Buffers::default();
+                    payloads
+                        .into_iter()
+                        .map(|payload| {
+                            let mut payload = Vec::from(payload);
+                            simd_json::serde::from_slice_with_buffers::<TextCompletion>(
+                                &mut payload,
+                                &mut buffers,
+                            )
+                            .unwrap()
+                        })
+                        .collect::<Vec<_>>()
+                },
+                BatchSize::SmallInput,
+            )
+        },
+    );
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_large, bench_streamed);
+criterion_main!(benches);
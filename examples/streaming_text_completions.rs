@@ -2,7 +2,7 @@ use anyhow::Context;
 use futures::StreamExt;
 use std::io::Write;
 use std::{env, io};
-use textsynth::prelude::EngineDefinition;
+use textsynth::prelude::{EngineDefinition, StreamResultExt};
 
 mod common;
 
@@ -22,14 +22,13 @@ async fn main() -> anyhow::Result<()> {
         .text_completion(prompt)
         .stream()
         .await
-        .context("failed to get streaming text completion")?;
+        .context("failed to get streaming text completion")?
+        .flatten_ok()
+        .boxed();
     let mut text_completions = Vec::new();
 
     while let Some(text_completion) = text_completion_stream.next().await {
-        let text_completion = text_completion
-            .context("failed to connect to textsynth api for next text completion")?
-            .context("got invalid json from textsynth api")?
-            .context("failed to get text completion")?;
+        let text_completion = text_completion.context("failed to get text completion")?;
         print!("{}", text_completion.text());
         io::stdout().flush().context("failed to flush stdout")?;
         text_completions.push(text_completion)
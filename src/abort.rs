@@ -0,0 +1,250 @@
+//! Aborting in-flight requests.
+
+use futures::channel::oneshot;
+use futures::future::{self, Either};
+use futures::{ready, Stream};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+#[derive(Debug, Default)]
+struct Inner {
+    next_id: u64,
+    senders: HashMap<u64, oneshot::Sender<()>>,
+}
+
+/// A handle to abort all in-flight requests made through the [`TextSynth`] instances it was given
+/// to, for example to shut down gracefully. See [`TextSynth::with_abort_registry`].
+///
+/// Clones of a registry share the same requests.
+///
+/// [`TextSynth`]: crate::core::TextSynth
+/// [`TextSynth::with_abort_registry`]: crate::core::TextSynth::with_abort_registry
+#[derive(Debug, Clone, Default)]
+pub struct AbortRegistry {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl AbortRegistry {
+    /// Create a new abort registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Abort all requests which are currently in flight, including streams which haven't ended
+    /// yet. Their futures resolve to, and their streams yield, an error for which
+    /// [`Error::is_cancelled`](crate::Error::is_cancelled) is `true`, after which the streams end.
+    ///
+    /// Requests made after this call aren't affected.
+    pub fn abort_all(&self) {
+        let senders = std::mem::take(&mut self.lock().senders);
+
+        for sender in senders.into_values() {
+            let _ = sender.send(());
+        }
+    }
+
+    /// The number of requests which are currently in flight.
+    pub fn in_flight(&self) -> usize {
+        self.lock().senders.len()
+    }
+
+    pub(crate) fn register(&self) -> Registration {
+        let (sender, receiver) = oneshot::channel();
+        let mut inner = self.lock();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.senders.insert(id, sender);
+
+        Registration {
+            registry: self.clone(),
+            id,
+            receiver,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().expect("abort registry lock poisoned")
+    }
+}
+
+/// An in-flight request, which is unregistered once dropped.
+#[derive(Debug)]
+pub(crate) struct Registration {
+    registry: AbortRegistry,
+    id: u64,
+    receiver: oneshot::Receiver<()>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.lock().senders.remove(&self.id);
+    }
+}
+
+/// A result which can represent a cancelled request.
+pub(crate) trait Cancelled {
+    fn cancelled() -> Self;
+}
+
+impl<T> Cancelled for reqwest::Result<crate::Result<T>> {
+    fn cancelled() -> Self {
+        Ok(Err(crate::Error::cancelled()))
+    }
+}
+
+impl<T> Cancelled for reqwest::Result<serde_json::Result<crate::Result<T>>> {
+    fn cancelled() -> Self {
+        Ok(Ok(Err(crate::Error::cancelled())))
+    }
+}
+
+/// Run the given future until it completes, returning [`None`] if the request was aborted first.
+pub(crate) async fn run<F: Future>(
+    registration: &mut Option<Registration>,
+    future: F,
+) -> Option<F::Output> {
+    let registration = match registration {
+        Some(registration) => registration,
+        None => return Some(future.await),
+    };
+
+    futures::pin_mut!(future);
+
+    match future::select(future, &mut registration.receiver).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
+
+/// Run the given future, resolving to a cancellation error if the request was aborted first.
+pub(crate) async fn abortable<F>(registry: Option<&AbortRegistry>, future: F) -> F::Output
+where
+    F: Future,
+    F::Output: Cancelled,
+{
+    let mut registration = registry.map(AbortRegistry::register);
+
+    run(&mut registration, future)
+        .await
+        .unwrap_or_else(Cancelled::cancelled)
+}
+
+/// A stream which yields the items of its inner stream until the request is aborted, in which
+/// case a cancellation error is yielded and the inner stream is dropped.
+struct AbortableStream<S> {
+    stream: Option<Pin<Box<S>>>,
+    registration: Option<Registration>,
+}
+
+impl<S> Stream for AbortableStream<S>
+where
+    S: Stream,
+    S::Item: Cancelled,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<S::Item>> {
+        let this = &mut *self;
+        let stream = match &mut this.stream {
+            Some(stream) => stream,
+            None => return Poll::Ready(None),
+        };
+
+        if let Some(registration) = &mut this.registration {
+            if Pin::new(&mut registration.receiver).poll(cx).is_ready() {
+                this.stream = None;
+                this.registration = None;
+                return Poll::Ready(Some(Cancelled::cancelled()));
+            }
+        }
+
+        let item = ready!(stream.as_mut().poll_next(cx));
+
+        if item.is_none() {
+            this.stream = None;
+            this.registration = None;
+        }
+
+        Poll::Ready(item)
+    }
+}
+
+/// Yield the items of the given stream until the request is aborted, in which case a cancellation
+/// error is yielded and the inner stream is dropped.
+pub(crate) fn abortable_stream<S>(
+    registration: Option<Registration>,
+    stream: S,
+) -> impl Stream<Item = S::Item> + Unpin
+where
+    S: Stream,
+    S::Item: Cancelled,
+{
+    AbortableStream {
+        stream: Some(Box::pin(stream)),
+        registration,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::pending;
+    use futures::{stream, StreamExt};
+
+    #[tokio::test]
+    async fn test_abortable() {
+        let registry = AbortRegistry::new();
+        let request = abortable(
+            Some(&registry),
+            pending::<reqwest::Result<crate::Result<()>>>(),
+        );
+        let abort = async {
+            tokio::task::yield_now().await;
+            assert_eq!(registry.in_flight(), 1);
+            registry.abort_all();
+        };
+
+        let (result, ()) = futures::join!(request, abort);
+        assert!(result.unwrap().unwrap_err().is_cancelled());
+        assert_eq!(registry.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_abortable_completed() {
+        let registry = AbortRegistry::new();
+        let result: reqwest::Result<crate::Result<u8>> =
+            abortable(Some(&registry), async { Ok(Ok(42)) }).await;
+        assert_eq!(result.unwrap().unwrap(), 42);
+        assert_eq!(registry.in_flight(), 0);
+
+        // aborting afterwards is a no-op
+        registry.abort_all();
+    }
+
+    #[tokio::test]
+    async fn test_abortable_stream() {
+        let registry = AbortRegistry::new();
+        let items = stream::iter([1, 2])
+            .map(|item| reqwest::Result::Ok(crate::Result::Ok(item)))
+            .chain(stream::pending());
+        let mut stream = Box::pin(abortable_stream(Some(registry.register()), items));
+
+        assert_eq!(stream.next().await.unwrap().unwrap().unwrap(), 1);
+        assert_eq!(stream.next().await.unwrap().unwrap().unwrap(), 2);
+
+        registry.abort_all();
+
+        assert!(stream
+            .next()
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap_err()
+            .is_cancelled());
+        assert!(stream.next().await.is_none());
+        assert_eq!(registry.in_flight(), 0);
+    }
+}
@@ -0,0 +1,65 @@
+//! Conversions of the nested results returned by this crate into [`anyhow::Result`]s, enabled by
+//! the `anyhow` feature.
+
+use anyhow::Context;
+
+/// Collapse a nested result returned by this crate into an [`anyhow::Result`], adding context
+/// describing which layer failed.
+pub trait IntoAnyhow {
+    /// The type of the successful value.
+    type Output;
+
+    /// Convert this nested result into an [`anyhow::Result`].
+    fn into_anyhow(self) -> anyhow::Result<Self::Output>;
+}
+
+impl<T> IntoAnyhow for reqwest::Result<crate::Result<T>> {
+    type Output = T;
+
+    fn into_anyhow(self) -> anyhow::Result<T> {
+        self.context("failed to connect to the textsynth api")?
+            .context("the textsynth api returned an error")
+    }
+}
+
+impl<T> IntoAnyhow for reqwest::Result<serde_json::Result<crate::Result<T>>> {
+    type Output = T;
+
+    fn into_anyhow(self) -> anyhow::Result<T> {
+        self.context("failed to connect to the textsynth api")?
+            .context("got invalid json from the textsynth api")?
+            .context("the textsynth api returned an error")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_into_anyhow() {
+        let result: reqwest::Result<crate::Result<u8>> = Ok(Ok(42));
+        assert_eq!(result.into_anyhow().unwrap(), 42);
+
+        let error =
+            serde_json::from_str::<crate::Error>(r#"{"status": 400, "error": "bad"}"#).unwrap();
+        let result: reqwest::Result<crate::Result<u8>> = Ok(Err(error));
+        assert_eq!(
+            result.into_anyhow().unwrap_err().to_string(),
+            "the textsynth api returned an error"
+        );
+    }
+
+    #[test]
+    fn test_into_anyhow_stream_result() {
+        let result: reqwest::Result<serde_json::Result<crate::Result<u8>>> = Ok(Ok(Ok(42)));
+        assert_eq!(result.into_anyhow().unwrap(), 42);
+
+        let result: reqwest::Result<serde_json::Result<crate::Result<u8>>> =
+            Ok(serde_json::from_str("{"));
+        assert_eq!(
+            result.into_anyhow().unwrap_err().to_string(),
+            "got invalid json from the textsynth api"
+        );
+    }
+}
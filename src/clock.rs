@@ -0,0 +1,152 @@
+//! Sources of time, so that time dependent behavior can be tested deterministically.
+
+use futures::future::BoxFuture;
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
+#[cfg(any(test, feature = "testing"))]
+use futures::channel::oneshot;
+#[cfg(any(test, feature = "testing"))]
+use std::sync::{Arc, Mutex};
+
+/// A source of time, used for rate limiting. See [`TextSynth::with_clock`].
+///
+/// [`TextSynth::with_clock`]: crate::core::TextSynth::with_clock
+pub trait Clock: Debug + Send + Sync {
+    /// The current instant.
+    fn now(&self) -> Instant;
+
+    /// Wait for the given duration.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The wall clock, which sleeps through the tokio timer. This is the default.
+#[derive(Debug, Copy, Clone, Default, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug)]
+struct MockState {
+    now: Instant,
+    sleeps: Vec<Duration>,
+    sleepers: Vec<(Instant, oneshot::Sender<()>)>,
+}
+
+/// A clock which only moves forward when [advanced](Self::advance), so that delays can be asserted
+/// without actually waiting.
+///
+/// Clones of a mock clock share the same time.
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    state: Arc<Mutex<MockState>>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl MockClock {
+    /// Create a new mock clock, starting at the current instant.
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(MockState {
+                now: Instant::now(),
+                sleeps: Vec::new(),
+                sleepers: Vec::new(),
+            })),
+        }
+    }
+
+    /// Move the clock forward by the given duration, waking up the sleeps which are due.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.lock();
+        state.now += duration;
+
+        let now = state.now;
+        let (due, pending) = std::mem::take(&mut state.sleepers)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(deadline, _)| *deadline <= now);
+        state.sleepers = pending;
+        drop(state);
+
+        for (_, sender) in due {
+            let _ = sender.send(());
+        }
+    }
+
+    /// The durations of all the sleeps started so far, in order.
+    pub fn sleeps(&self) -> Vec<Duration> {
+        self.lock().sleeps.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().expect("mock clock lock poisoned")
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.lock().now
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let mut state = self.lock();
+        state.sleeps.push(duration);
+
+        if duration.is_zero() {
+            return Box::pin(async {});
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        let deadline = state.now + duration;
+        state.sleepers.push((deadline, sender));
+
+        Box::pin(async move {
+            let _ = receiver.await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    #[test]
+    fn test_mock_clock_advance() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(clock.now() - start, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_mock_clock_sleep() {
+        let clock = MockClock::new();
+        let mut sleep = clock.sleep(Duration::from_secs(1));
+        assert!((&mut sleep).now_or_never().is_none());
+
+        clock.advance(Duration::from_millis(999));
+        assert!((&mut sleep).now_or_never().is_none());
+
+        clock.advance(Duration::from_millis(1));
+        assert!(sleep.now_or_never().is_some());
+        assert_eq!(clock.sleeps(), [Duration::from_secs(1)]);
+    }
+}
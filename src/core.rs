@@ -1,53 +1,904 @@
 //! Core functionality of `textsynth`.
-use crate::engine::definition::EngineDefinition;
+use crate::abort::{self, AbortRegistry, Cancelled, Registration};
+use crate::clock::{Clock, SystemClock};
+use crate::engine::definition::{CustomEngineDefinition, EngineDefinition, EngineListing};
+use crate::engine::tokenize::TokenizationCache;
 use crate::engine::Engine;
-use reqwest::{IntoUrl, RequestBuilder};
+use crate::error::FromEnvError;
+use crate::key_pool::KeyPool;
+use crate::rate_limit::RateLimiter;
+use crate::utils::{self, Redacted};
+#[cfg(feature = "vcr")]
+use crate::vcr::Cassette;
+use futures::{Stream, StreamExt};
+use once_cell::sync::OnceCell;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT_LANGUAGE};
+use reqwest::redirect::Policy;
+use reqwest::{Body, IntoUrl, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::env;
+use std::error::Error as StdError;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tap::Pipe;
+
+/// The base URL of the public TextSynth API.
+pub const DEFAULT_BASE_URL: &str = "https://api.textsynth.com";
+
+/// The `User-Agent` header sent by default.
+pub const DEFAULT_USER_AGENT: &str = concat!("textsynth-rs/", env!("CARGO_PKG_VERSION"));
+
+const API_KEY_VAR: &str = "TEXTSYNTH_API_KEY";
+const BASE_URL_VAR: &str = "TEXTSYNTH_BASE_URL";
+
+/// How requests are authenticated with the api key. See [`TextSynthBuilder::auth_scheme`].
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub enum AuthScheme {
+    /// The `Authorization: Bearer <api key>` header, which the API expects.
+    #[default]
+    Bearer,
+
+    /// The given header, whose value is the api key preceded by `prefix`, for gateways expecting
+    /// another scheme, such as `Authorization: Token <api key>`, with a prefix of `"Token "`, or
+    /// `api-key: <api key>`, with an empty prefix.
+    Custom {
+        /// The name of the header.
+        header: HeaderName,
+
+        /// What precedes the api key in the value of the header, including any separator.
+        prefix: String,
+    },
+}
+
+/// A function customizing every request before it is sent. See
+/// [`TextSynth::with_request_customizer`].
+pub type RequestCustomizer = Arc<dyn Fn(RequestBuilder) -> RequestBuilder + Send + Sync>;
+
+/// A function observing retries, called with the number of the attempt (starting at 1), the delay
+/// before it and the error which caused it. See [`TextSynth::with_retry_hook`].
+pub type RetryHook = Arc<dyn Fn(usize, Duration, &(dyn StdError + 'static)) + Send + Sync>;
+
+/// A function observing how long requests waited for the rate limit. See
+/// [`TextSynth::with_rate_limit_wait_hook`].
+pub type RateLimitWaitHook = Arc<dyn Fn(Duration) + Send + Sync>;
+
+/// A function warned about request bodies larger than the maximum, called with the size of the
+/// body and the maximum, in bytes. See [`TextSynth::with_oversized_request_hook`].
+pub type OversizedRequestHook = Arc<dyn Fn(usize, usize) + Send + Sync>;
+
+/// When a request was handed to the HTTP client, after any rate limit wait, stored in the
+/// extensions of its response.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SentAt(pub(crate) Instant);
 
 /// The main structure of `textsynth`.
-#[derive(Debug, Clone)]
+///
+/// # Cancellation
+/// Every future returned by this crate is cancellation safe: dropping it before it completes, for
+/// example because of a timeout, aborts the underlying request and releases its connection. No
+/// request keeps running in the background. Note that if a rate limit is set (see
+/// [`Self::with_rate_limit`]), a dropped request still counts towards it.
+#[derive(Clone)]
 pub struct TextSynth {
     /// The client to make http requests to.
     pub client: reqwest::Client,
 
     /// The api key used to authenticate into the textsynth API.
     pub api_key: String,
+
+    base_url: Cow<'static, str>,
+    user_agent: Cow<'static, str>,
+    accept_language: Option<HeaderValue>,
+    timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    max_request_bytes: Option<usize>,
+    follow_redirects: bool,
+    compression: bool,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    key_pool: Option<Arc<KeyPool>>,
+    auth_scheme: AuthScheme,
+    abort_registry: Option<AbortRegistry>,
+    clock: Option<Arc<dyn Clock>>,
+    request_customizer: Option<RequestCustomizer>,
+    retry_hook: Option<RetryHook>,
+    rate_limit_wait_hook: Option<RateLimitWaitHook>,
+    oversized_request_hook: Option<OversizedRequestHook>,
+    tokenization_cache: OnceCell<Arc<Mutex<TokenizationCache>>>,
+    #[cfg(feature = "vcr")]
+    cassette: Option<Arc<Cassette>>,
 }
 
 impl TextSynth {
     /// Creates a new [`TextSynth`] instance.
     pub const fn new_with_client(client: reqwest::Client, api_key: String) -> TextSynth {
-        TextSynth { client, api_key }
+        TextSynth {
+            client,
+            api_key,
+            base_url: Cow::Borrowed(DEFAULT_BASE_URL),
+            user_agent: Cow::Borrowed(DEFAULT_USER_AGENT),
+            accept_language: None,
+            timeout: None,
+            idle_timeout: None,
+            max_request_bytes: None,
+            follow_redirects: false,
+            compression: true,
+            rate_limiter: None,
+            key_pool: None,
+            auth_scheme: AuthScheme::Bearer,
+            abort_registry: None,
+            clock: None,
+            request_customizer: None,
+            retry_hook: None,
+            rate_limit_wait_hook: None,
+            oversized_request_hook: None,
+            tokenization_cache: OnceCell::new(),
+            #[cfg(feature = "vcr")]
+            cassette: None,
+        }
     }
 
     /// Try an create a new [`TextSynth`] instance with a default [`reqwest::Client`], returning an
     /// error if creating a default [`reqwest::Client`] fails.
-    pub fn try_new(api_key: String) -> reqwest::Result<Self> {
-        Ok(TextSynth::new_with_client(
-            reqwest::Client::builder().build()?,
-            api_key,
-        ))
+    pub fn try_new(api_key: impl Into<String>) -> reqwest::Result<Self> {
+        Self::builder(api_key).build()
     }
 
     /// Create a new [`TextSynth`] instance with a default [`reqwest::Client`], panicking if
     /// creating a default [`reqwest::Client`] fails.
-    pub fn new(api_key: String) -> TextSynth {
+    pub fn new(api_key: impl Into<String>) -> TextSynth {
         Self::try_new(api_key).expect("failed to create a new `reqwest::Client`")
     }
 
+    /// Create a new [`TextSynth`] instance configured from environment variables:
+    ///   - `TEXTSYNTH_API_KEY`: The api key. Required.
+    ///   - `TEXTSYNTH_BASE_URL`: The base URL of the server. Optional, see
+    ///     [`TextSynthBuilder::base_url`].
+    ///
+    /// Returns [`FromEnvError::MissingApiKey`] if the api key isn't set.
+    pub fn from_env() -> Result<Self, FromEnvError> {
+        Self::from_vars(|name| env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, FromEnvError> {
+        let api_key = var(API_KEY_VAR).ok_or(FromEnvError::MissingApiKey(API_KEY_VAR))?;
+        let mut builder = Self::builder(api_key);
+
+        if let Some(base_url) = var(BASE_URL_VAR) {
+            builder = builder.base_url(base_url);
+        }
+
+        builder.build().map_err(FromEnvError::Client)
+    }
+
+    /// Create a builder for a [`TextSynth`] instance.
+    pub fn builder(api_key: impl Into<String>) -> TextSynthBuilder {
+        TextSynthBuilder::new(api_key)
+    }
+
     /// Create a new engine from the given definition.
-    pub const fn engine(&self, definition: EngineDefinition) -> Engine {
+    pub const fn engine(&self, definition: EngineDefinition) -> Engine<'_> {
         Engine::new(self, definition)
     }
 
+    /// The base URL of the server requests are made to.
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// The configuration this instance was built with, which can be compared, stored and used to
+    /// build an equivalent instance. See [`TextSynthConfig`].
+    ///
+    /// For instances created from a custom client with [`Self::new_with_client`], the
+    /// configuration of the client isn't known, so the defaults are returned instead.
+    pub fn config(&self) -> TextSynthConfig {
+        TextSynthConfig {
+            api_key: self.api_key.clone(),
+            base_url: self.base_url.clone().into_owned(),
+            user_agent: self.user_agent.clone().into_owned(),
+            accept_language: self.accept_language.clone(),
+            timeout: self.timeout,
+            idle_timeout: self.idle_timeout,
+            max_request_bytes: self.max_request_bytes,
+            follow_redirects: self.follow_redirects,
+            compression: self.compression,
+            auth_scheme: self.auth_scheme.clone(),
+        }
+    }
+
+    /// List the engines available on the server.
+    ///
+    /// The server is expected to respond to `GET /v1/engines` with a body of the form
+    /// `{"engines": [{"id": "gptj_6B", "max_tokens": 2048}, ...]}`. Engines with a `max_tokens` of
+    /// zero can't be used, so they're left out, but [`CustomEngineDefinition::verify`] reports them
+    /// as errors.
+    pub async fn engines(&self) -> reqwest::Result<crate::Result<Vec<CustomEngineDefinition>>> {
+        self.engine_listing()
+            .await
+            .map(|result| result.map(EngineListing::into_definitions))
+    }
+
+    pub(crate) async fn engine_listing(&self) -> reqwest::Result<crate::Result<EngineListing>> {
+        self.abortable(async {
+            self.send(self.get(self.url("/v1/engines")))
+                .await?
+                .pipe(utils::json::<EngineListing>)
+                .await
+        })
+        .await
+    }
+
+    /// Same as [`Self::engines`], but yields the engines one by one, so that callers enumerating
+    /// the available engines don't depend on how the listing is fetched.
+    ///
+    /// The engine listing isn't paginated, so this currently fetches a single page. If fetching it
+    /// fails, the error is the only item of the stream.
+    pub fn engines_stream(
+        &self,
+    ) -> impl Stream<Item = reqwest::Result<crate::Result<CustomEngineDefinition>>> + '_ {
+        futures::stream::once(self.engines()).flat_map(|result| {
+            let items = match result {
+                Ok(Ok(definitions)) => definitions.into_iter().map(|d| Ok(Ok(d))).collect(),
+                Ok(Err(error)) => vec![Ok(Err(error))],
+                Err(error) => vec![Err(error)],
+            };
+            futures::stream::iter(items)
+        })
+    }
+
+    /// Get the remaining credits of the account, multiplied by 10^9.
+    ///
+    /// Useful to surface the remaining balance alongside [`Error::is_out_of_credits`].
+    ///
+    /// [`Error::is_out_of_credits`]: crate::Error::is_out_of_credits
+    pub async fn credits(&self) -> reqwest::Result<crate::Result<u64>> {
+        #[derive(Deserialize)]
+        struct Credits {
+            credits: u64,
+        }
+
+        self.abortable(async {
+            self.send(self.get(self.url("/v1/credits")))
+                .await?
+                .pipe(utils::json::<Credits>)
+                .await
+                .map(|result| result.map(|credits| credits.credits))
+        })
+        .await
+    }
+
+    /// Check whether the server is up by making a request to its root, which doesn't consume any
+    /// credits. Useful as a readiness probe for self-hosted servers.
+    ///
+    /// Returns whether the server responded with a success status code, or an error if the server
+    /// couldn't be reached at all. See [`Self::is_reachable`] for a variant which never fails.
+    pub async fn ping(&self) -> reqwest::Result<bool> {
+        self.send(self.get(self.url("/")))
+            .await
+            .map(|response| response.status().is_success())
+    }
+
+    /// Same as [`Self::ping`], but returns `false` instead of an error if the server couldn't be
+    /// reached.
+    pub async fn is_reachable(&self) -> bool {
+        self.ping().await.unwrap_or(false)
+    }
+
+    /// Limit the rate of requests made through this instance, and all of its clones, to the given
+    /// amount of requests per second.
+    ///
+    /// Up to `requests_per_second` requests (but at least one) can be made in a burst without
+    /// waiting, after which requests are delayed so that they're evenly spaced out. Delayed requests
+    /// are sent in the order they were made.
+    ///
+    /// # Panics
+    /// Panics if `requests_per_second` isn't finite and positive.
+    pub fn with_rate_limit(mut self, requests_per_second: f64) -> Self {
+        self.rate_limiter = Some(Arc::new(RateLimiter::new(requests_per_second)));
+        self
+    }
+
+    /// Authenticate requests made through this instance, and all of its clones, with the given api
+    /// keys instead of [`Self::api_key`], for example to spread the load over several keys.
+    ///
+    /// Keys are used round-robin: each request uses the key after the one used by the previous
+    /// request, across all clones.
+    ///
+    /// # Panics
+    /// Panics if `keys` is empty.
+    pub fn with_key_pool(mut self, keys: Vec<String>) -> Self {
+        self.key_pool = Some(Arc::new(KeyPool::new(keys)));
+        self
+    }
+
+    fn next_api_key(&self) -> &str {
+        match &self.key_pool {
+            Some(key_pool) => key_pool.next(),
+            None => &self.api_key,
+        }
+    }
+
+    /// Make requests through this instance, and all of its clones, abortable through the given
+    /// registry. See [`AbortRegistry::abort_all`] for the semantics of aborting a request.
+    ///
+    /// Health checks ([`Self::ping`] and [`Self::is_reachable`]) aren't abortable.
+    pub fn with_abort_registry(mut self, abort_registry: AbortRegistry) -> Self {
+        self.abort_registry = Some(abort_registry);
+        self
+    }
+
+    /// Measure and wait for time through the given clock instead of the [`SystemClock`], for
+    /// example a [`MockClock`](crate::clock::MockClock) in tests, so that rate limiting delays can
+    /// be asserted without actually waiting.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// Customize every request made through this instance, and all of its clones, for example to
+    /// add headers or query parameters the crate doesn't support.
+    ///
+    /// The customizer is applied after the built-in bearer auth, so it can add further
+    /// authentication but not replace the api key. It is applied before any rate limiting and
+    /// before the request is sent, so its changes are visible to the `debug-bodies` logging. If
+    /// this is called several times, the customizers are applied in the order they were set.
+    pub fn with_request_customizer(
+        mut self,
+        customizer: impl Fn(RequestBuilder) -> RequestBuilder + Send + Sync + 'static,
+    ) -> Self {
+        self.request_customizer = Some(match self.request_customizer.take() {
+            Some(previous) => Arc::new(move |request| customizer(previous(request))),
+            None => Arc::new(customizer),
+        });
+        self
+    }
+
+    /// Observe retries made through this instance, and all of its clones, for example to chart
+    /// retry rates. The hook is called before each retry with the number of the attempt (starting
+    /// at 1), the delay before it and the error which caused it.
+    ///
+    /// Currently, the only retries are the reconnections of
+    /// [`TextCompletionBuilder::stream_resilient`], which happen without delay. If this is called
+    /// several times, the hooks are called in the order they were set.
+    ///
+    /// [`TextCompletionBuilder::stream_resilient`]:
+    /// crate::engine::text_completion::TextCompletionBuilder::stream_resilient
+    pub fn with_retry_hook(
+        mut self,
+        hook: impl Fn(usize, Duration, &(dyn StdError + 'static)) + Send + Sync + 'static,
+    ) -> Self {
+        self.retry_hook = Some(match self.retry_hook.take() {
+            Some(previous) => Arc::new(move |attempt, delay, reason| {
+                previous(attempt, delay, reason);
+                hook(attempt, delay, reason);
+            }),
+            None => Arc::new(hook),
+        });
+        self
+    }
+
+    /// Observe how long requests made through this instance, and all of its clones, waited for the
+    /// rate limit set with [`Self::with_rate_limit`]. The hook is called after a request waited,
+    /// with how long it waited, and isn't called for requests which didn't wait. If this is called
+    /// several times, the hooks are called in the order they were set.
+    pub fn with_rate_limit_wait_hook(
+        mut self,
+        hook: impl Fn(Duration) + Send + Sync + 'static,
+    ) -> Self {
+        self.rate_limit_wait_hook = Some(match self.rate_limit_wait_hook.take() {
+            Some(previous) => Arc::new(move |waited| {
+                previous(waited);
+                hook(waited);
+            }),
+            None => Arc::new(hook),
+        });
+        self
+    }
+
+    /// Only warn about requests made through this instance, and all of its clones, whose body is
+    /// larger than [`TextSynthBuilder::max_request_bytes`], instead of failing them. The hook is
+    /// called with the size of the body and the maximum, in bytes, and the request is sent anyway.
+    /// If this is called several times, the hooks are called in the order they were set.
+    pub fn with_oversized_request_hook(
+        mut self,
+        hook: impl Fn(usize, usize) + Send + Sync + 'static,
+    ) -> Self {
+        self.oversized_request_hook = Some(match self.oversized_request_hook.take() {
+            Some(previous) => Arc::new(move |body_bytes, max_request_bytes| {
+                previous(body_bytes, max_request_bytes);
+                hook(body_bytes, max_request_bytes);
+            }),
+            None => Arc::new(hook),
+        });
+        self
+    }
+
+    /// Record requests made through this instance, and all of its clones, to the given cassette,
+    /// or replay them from it without sending them, depending on its [mode](Cassette::mode). This
+    /// makes tests reproducible without network access or an api key.
+    ///
+    /// Requests are recorded or replayed after rate limiting and the `debug-bodies` logging.
+    #[cfg(feature = "vcr")]
+    pub fn with_cassette(mut self, cassette: Cassette) -> Self {
+        self.cassette = Some(Arc::new(cassette));
+        self
+    }
+
+    pub(crate) fn notify_retry(
+        &self,
+        attempt: usize,
+        delay: Duration,
+        reason: &(dyn StdError + 'static),
+    ) {
+        if let Some(retry_hook) = &self.retry_hook {
+            retry_hook(attempt, delay, reason);
+        }
+    }
+
+    fn customize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.request_customizer {
+            Some(customizer) => customizer(request),
+            None => request,
+        }
+    }
+
+    pub(crate) fn clock(&self) -> &dyn Clock {
+        match &self.clock {
+            Some(clock) => clock.as_ref(),
+            None => &SystemClock,
+        }
+    }
+
+    pub(crate) fn abort_registration(&self) -> Option<Registration> {
+        self.abort_registry.as_ref().map(AbortRegistry::register)
+    }
+
+    pub(crate) async fn abortable<F>(&self, future: F) -> F::Output
+    where
+        F: Future,
+        F::Output: Cancelled,
+    {
+        abort::abortable(self.abort_registry.as_ref(), future).await
+    }
+
+    /// The tokenization cache of this instance, created on first use. Clones made afterwards share
+    /// it.
+    pub(crate) fn tokenization_cache(&self) -> &Mutex<TokenizationCache> {
+        self.tokenization_cache.get_or_init(Default::default)
+    }
+
+    pub(crate) fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base_url)
+    }
+
+    fn authenticate(&self, request: RequestBuilder) -> RequestBuilder {
+        let api_key = self.next_api_key();
+
+        match &self.auth_scheme {
+            AuthScheme::Bearer => request.bearer_auth(api_key),
+            AuthScheme::Custom { header, prefix } => {
+                let value = format!("{prefix}{api_key}");
+
+                match HeaderValue::from_str(&value) {
+                    Ok(mut value) => {
+                        value.set_sensitive(true);
+                        request.header(header, value)
+                    }
+                    // the invalid value fails the request when it's built
+                    Err(_) => request.header(header, value),
+                }
+            }
+        }
+    }
+
+    pub(crate) fn get(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.customize(self.authenticate(self.client.get(url)))
+    }
+
     pub(crate) fn post(&self, url: impl IntoUrl) -> RequestBuilder {
-        self.client.post(url).bearer_auth(&self.api_key)
+        self.customize(self.authenticate(self.client.post(url)))
+    }
+
+    pub(crate) async fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let request = request.build()?;
+        let body_bytes = request
+            .body()
+            .and_then(Body::as_bytes)
+            .map_or(0, <[u8]>::len);
+
+        match (self.max_request_bytes, &self.oversized_request_hook) {
+            (Some(max_request_bytes), Some(oversized_request_hook))
+                if body_bytes > max_request_bytes =>
+            {
+                oversized_request_hook(body_bytes, max_request_bytes);
+            }
+            (Some(max_request_bytes), None) if body_bytes > max_request_bytes => {
+                let message = format!(
+                    "the request body is {body_bytes} bytes long, more than the maximum of \
+                     {max_request_bytes} bytes"
+                );
+                return Ok(utils::error_response(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    &message,
+                ));
+            }
+            _ => {}
+        }
+
+        if let Some(rate_limiter) = &self.rate_limiter {
+            let waited = rate_limiter.acquire(self.clock()).await;
+
+            if let (false, Some(rate_limit_wait_hook)) =
+                (waited.is_zero(), &self.rate_limit_wait_hook)
+            {
+                rate_limit_wait_hook(waited);
+            }
+        }
+
+        #[cfg(feature = "debug-bodies")]
+        crate::debug_bodies::log_request(&request);
+
+        let sent_at = Instant::now();
+        let mut response = self.execute(request).await?;
+        response.extensions_mut().insert(SentAt(sent_at));
+
+        Ok(response)
+    }
+
+    /// Execute the request, replaying or recording it if a cassette is set.
+    async fn execute(&self, request: reqwest::Request) -> reqwest::Result<Response> {
+        #[cfg(feature = "vcr")]
+        if let Some(cassette) = &self.cassette {
+            return cassette.send(&self.client, request, &self.base_url).await;
+        }
+
+        let response = self.client.execute(request).await?;
+        let response = match self.idle_timeout {
+            Some(idle_timeout) => {
+                let clock = self.clock.clone().unwrap_or_else(|| Arc::new(SystemClock));
+                utils::with_idle_timeout(response, idle_timeout, clock)
+            }
+            None => response,
+        };
+
+        #[cfg(feature = "debug-bodies")]
+        let response = crate::debug_bodies::log_response(response);
+
+        Ok(response)
+    }
+}
+
+impl fmt::Debug for TextSynth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug_struct = f.debug_struct("TextSynth");
+        debug_struct
+            .field("client", &self.client)
+            .field("api_key", &Redacted)
+            .field("base_url", &self.base_url)
+            .field("user_agent", &self.user_agent)
+            .field("accept_language", &self.accept_language)
+            .field("timeout", &self.timeout)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("max_request_bytes", &self.max_request_bytes)
+            .field("follow_redirects", &self.follow_redirects)
+            .field("compression", &self.compression)
+            .field("rate_limiter", &self.rate_limiter)
+            .field("key_pool", &self.key_pool)
+            .field("auth_scheme", &self.auth_scheme)
+            .field("abort_registry", &self.abort_registry)
+            .field("clock", &self.clock)
+            .field(
+                "request_customizer",
+                &self.request_customizer.as_ref().map(|_| ".."),
+            )
+            .field("retry_hook", &self.retry_hook.as_ref().map(|_| ".."))
+            .field(
+                "rate_limit_wait_hook",
+                &self.rate_limit_wait_hook.as_ref().map(|_| ".."),
+            )
+            .field(
+                "oversized_request_hook",
+                &self.oversized_request_hook.as_ref().map(|_| ".."),
+            );
+
+        #[cfg(feature = "vcr")]
+        debug_struct.field("cassette", &self.cassette);
+
+        debug_struct.finish()
+    }
+}
+
+/// A builder for a [`TextSynth`] instance.
+#[derive(Clone)]
+#[must_use = "builders do nothing unless built"]
+pub struct TextSynthBuilder {
+    /// See [`TextSynth::api_key`].
+    pub api_key: String,
+
+    /// See [`Self::base_url`].
+    pub base_url: String,
+
+    /// See [`Self::user_agent`].
+    pub user_agent: String,
+
+    /// See [`Self::accept_language`].
+    pub accept_language: Option<HeaderValue>,
+
+    /// See [`Self::timeout`].
+    pub timeout: Option<Duration>,
+
+    /// See [`Self::idle_timeout`].
+    pub idle_timeout: Option<Duration>,
+
+    /// See [`Self::max_request_bytes`].
+    pub max_request_bytes: Option<usize>,
+
+    /// See [`Self::follow_redirects`].
+    pub follow_redirects: bool,
+
+    /// See [`Self::compression`].
+    pub compression: bool,
+
+    /// See [`Self::auth_scheme`].
+    pub auth_scheme: AuthScheme,
+}
+
+impl TextSynthBuilder {
+    /// Create a new [`TextSynth`] builder.
+    pub fn new(api_key: impl Into<String>) -> Self {
+        Self {
+            api_key: api_key.into(),
+            base_url: DEFAULT_BASE_URL.into(),
+            user_agent: DEFAULT_USER_AGENT.into(),
+            accept_language: None,
+            timeout: None,
+            idle_timeout: None,
+            max_request_bytes: None,
+            follow_redirects: false,
+            compression: true,
+            auth_scheme: AuthScheme::Bearer,
+        }
+    }
+
+    /// Set the base URL of the server to make requests to, such as a self-hosted TextSynth server.
+    /// Defaults to [`DEFAULT_BASE_URL`].
+    ///
+    /// The base URL may include a path prefix, for example if the server is behind a reverse proxy
+    /// at `https://example.com/textsynth`, and trailing slashes are ignored.
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    /// Set the `User-Agent` header sent with every request, so that the server or a gateway in front
+    /// of it can identify the client. Defaults to [`DEFAULT_USER_AGENT`].
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Set the `Accept-Language` header sent with every request. Deployments which localize their
+    /// error messages will use it to pick the language of [`crate::Error::message`].
+    pub fn accept_language(mut self, accept_language: HeaderValue) -> Self {
+        self.accept_language = Some(accept_language);
+        self
+    }
+
+    /// Set a timeout applied to every request, from when it's sent until the response body has
+    /// been fully received. By default, there is no timeout.
+    ///
+    /// This bounds the total time of a request, so it also cuts off slow requests which are still
+    /// making progress, such as long streamed completions. See [`Self::idle_timeout`] for a timeout
+    /// which doesn't.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Set a timeout applied to the time between bytes of every response body, rather than to the
+    /// total time of the request. By default, there is no idle timeout.
+    ///
+    /// Unlike [`Self::timeout`], a slow request doesn't time out as long as the server keeps
+    /// sending bytes, such as streamed text completions or the keep-alive whitespace some servers
+    /// send during long computations. It doesn't apply to the time until the response headers are
+    /// received, which only [`Self::timeout`] bounds. Both can be set.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Set the maximum size of request bodies in bytes, to catch accidentally giant prompts before
+    /// they're sent. By default, there is no limit.
+    ///
+    /// A request whose body is larger isn't sent, and fails with a `413 Payload Too Large`
+    /// [`crate::Error`] describing the size of the body, like an error returned by the API. Use
+    /// [`TextSynth::with_oversized_request_hook`] to only warn about it instead.
+    pub fn max_request_bytes(mut self, max_request_bytes: usize) -> Self {
+        self.max_request_bytes = Some(max_request_bytes);
+        self
+    }
+
+    /// Set whether redirects are followed. Defaults to `false`, in which case a redirect response
+    /// results in an error for which [`reqwest::Error::is_redirect`] is `true`.
+    ///
+    /// The API never redirects, so a redirect means something between the client and the server,
+    /// such as a proxy, is misconfigured. Following it could send the api key to another host.
+    pub fn follow_redirects(mut self, follow_redirects: bool) -> Self {
+        self.follow_redirects = follow_redirects;
+        self
+    }
+
+    /// Set whether compressed responses are requested and transparently decompressed, which
+    /// mostly benefits long streamed completions. Defaults to `true`.
+    ///
+    /// This requires the `compression` feature, which negotiates `gzip`, `deflate` and `br`
+    /// through the `Accept-Encoding` header. Without it, no encoding is negotiated and this has no
+    /// effect.
+    pub fn compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Set how requests are authenticated with the api key, for gateways in front of the API which
+    /// expect another scheme. Defaults to [`AuthScheme::Bearer`].
+    pub fn auth_scheme(mut self, auth_scheme: AuthScheme) -> Self {
+        self.auth_scheme = auth_scheme;
+        self
+    }
+
+    /// Build the [`TextSynth`] instance, returning an error if creating the underlying
+    /// [`reqwest::Client`] fails.
+    pub fn build(self) -> reqwest::Result<TextSynth> {
+        let mut headers = HeaderMap::new();
+
+        if let Some(accept_language) = &self.accept_language {
+            headers.insert(ACCEPT_LANGUAGE, accept_language.clone());
+        }
+
+        let redirect_policy = if self.follow_redirects {
+            Policy::default()
+        } else {
+            Policy::custom(|attempt| attempt.error("redirects are disabled"))
+        };
+
+        let mut client_builder = reqwest::Client::builder()
+            .user_agent(&self.user_agent)
+            .default_headers(headers)
+            .redirect(redirect_policy);
+
+        if let Some(timeout) = self.timeout {
+            client_builder = client_builder.timeout(timeout);
+        }
+
+        #[cfg(feature = "compression")]
+        {
+            client_builder = client_builder
+                .gzip(self.compression)
+                .deflate(self.compression)
+                .brotli(self.compression);
+        }
+
+        let mut text_synth = TextSynth::new_with_client(client_builder.build()?, self.api_key);
+        text_synth.base_url = Cow::Owned(self.base_url.trim_end_matches('/').to_owned());
+        text_synth.user_agent = Cow::Owned(self.user_agent);
+        text_synth.accept_language = self.accept_language;
+        text_synth.timeout = self.timeout;
+        text_synth.idle_timeout = self.idle_timeout;
+        text_synth.max_request_bytes = self.max_request_bytes;
+        text_synth.follow_redirects = self.follow_redirects;
+        text_synth.compression = self.compression;
+        text_synth.auth_scheme = self.auth_scheme;
+
+        Ok(text_synth)
+    }
+}
+
+impl fmt::Debug for TextSynthBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TextSynthBuilder")
+            .field("api_key", &Redacted)
+            .field("base_url", &self.base_url)
+            .field("user_agent", &self.user_agent)
+            .field("accept_language", &self.accept_language)
+            .field("timeout", &self.timeout)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("max_request_bytes", &self.max_request_bytes)
+            .field("follow_redirects", &self.follow_redirects)
+            .field("compression", &self.compression)
+            .field("auth_scheme", &self.auth_scheme)
+            .finish()
+    }
+}
+
+impl From<TextSynthConfig> for TextSynthBuilder {
+    fn from(config: TextSynthConfig) -> Self {
+        Self {
+            api_key: config.api_key,
+            base_url: config.base_url,
+            user_agent: config.user_agent,
+            accept_language: config.accept_language,
+            timeout: config.timeout,
+            idle_timeout: config.idle_timeout,
+            max_request_bytes: config.max_request_bytes,
+            follow_redirects: config.follow_redirects,
+            compression: config.compression,
+            auth_scheme: config.auth_scheme,
+        }
+    }
+}
+
+/// The configuration of a [`TextSynth`] instance, separate from its [`reqwest::Client`] so that it
+/// can be compared and stored. See [`TextSynth::config`].
+///
+/// Rate limits and abort registries are shared state rather than configuration, so they aren't
+/// part of it.
+#[derive(Clone, Eq, PartialEq, Hash)]
+pub struct TextSynthConfig {
+    /// See [`TextSynth::api_key`].
+    pub api_key: String,
+
+    /// See [`TextSynthBuilder::base_url`].
+    pub base_url: String,
+
+    /// See [`TextSynthBuilder::user_agent`].
+    pub user_agent: String,
+
+    /// See [`TextSynthBuilder::accept_language`].
+    pub accept_language: Option<HeaderValue>,
+
+    /// See [`TextSynthBuilder::timeout`].
+    pub timeout: Option<Duration>,
+
+    /// See [`TextSynthBuilder::idle_timeout`].
+    pub idle_timeout: Option<Duration>,
+
+    /// See [`TextSynthBuilder::max_request_bytes`].
+    pub max_request_bytes: Option<usize>,
+
+    /// See [`TextSynthBuilder::follow_redirects`].
+    pub follow_redirects: bool,
+
+    /// See [`TextSynthBuilder::compression`].
+    pub compression: bool,
+
+    /// See [`TextSynthBuilder::auth_scheme`].
+    pub auth_scheme: AuthScheme,
+}
+
+impl fmt::Debug for TextSynthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TextSynthConfig")
+            .field("api_key", &Redacted)
+            .field("base_url", &self.base_url)
+            .field("user_agent", &self.user_agent)
+            .field("accept_language", &self.accept_language)
+            .field("timeout", &self.timeout)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("max_request_bytes", &self.max_request_bytes)
+            .field("follow_redirects", &self.follow_redirects)
+            .field("compression", &self.compression)
+            .field("auth_scheme", &self.auth_scheme)
+            .finish()
+    }
+}
+
+impl TextSynthConfig {
+    /// Build a new [`TextSynth`] instance from this configuration, returning an error if creating
+    /// the underlying [`reqwest::Client`] fails.
+    pub fn build(&self) -> reqwest::Result<TextSynth> {
+        TextSynthBuilder::from(self.clone()).build()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::MockClock;
     use crate::test_utils;
+    use crate::test_utils::server::{self, Response};
+    use std::sync::atomic::{AtomicBool, Ordering};
 
     #[test]
     fn test_new_with_client() {
@@ -56,18 +907,681 @@ mod tests {
 
     #[test]
     fn test_try_new() {
-        let _ = TextSynth::try_new(test_utils::api_key().into())
+        let _ = TextSynth::try_new(test_utils::api_key())
             .expect("failed to create new textsynth client");
     }
 
     #[test]
     fn test_new() {
-        let _ = TextSynth::new(test_utils::api_key().into());
+        let _ = TextSynth::new(test_utils::api_key());
+        let _ = TextSynth::new(String::from(test_utils::api_key()));
+    }
+
+    #[test]
+    fn test_builder() {
+        let _ = TextSynth::builder(test_utils::api_key())
+            .accept_language(HeaderValue::from_static("fr"))
+            .build()
+            .expect("failed to build textsynth client");
+    }
+
+    #[test]
+    fn test_with_rate_limit() {
+        let textsynth = TextSynth::new(test_utils::api_key()).with_rate_limit(2.0);
+        let clone = textsynth.clone();
+        assert!(Arc::ptr_eq(
+            textsynth.rate_limiter.as_ref().unwrap(),
+            clone.rate_limiter.as_ref().unwrap(),
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_with_clock() {
+        let base_url = server::serve(|_| Response::json(200, r#"{"credits": 42}"#)).await;
+        let clock = MockClock::new();
+        let textsynth = test_utils::text_synth::with_base_url(&base_url)
+            .with_rate_limit(1.0)
+            .with_clock(clock.clone());
+
+        let _ = textsynth.credits().await.expect("network error");
+        assert!(clock.sleeps().is_empty());
+
+        let advance = async {
+            while clock.sleeps().is_empty() {
+                tokio::task::yield_now().await;
+            }
+
+            clock.advance(Duration::from_secs(1));
+        };
+        let (credits, ()) = futures::join!(textsynth.credits(), advance);
+        assert_eq!(credits.expect("network error").expect("api error"), 42);
+        assert_eq!(clock.sleeps(), [Duration::from_secs(1)]);
+    }
+
+    #[tokio::test]
+    async fn test_with_rate_limit_wait_hook() {
+        let base_url = server::serve(|_| Response::json(200, r#"{"credits": 42}"#)).await;
+        let clock = MockClock::new();
+        let waits = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let textsynth = test_utils::text_synth::with_base_url(&base_url)
+            .with_rate_limit(1.0)
+            .with_clock(clock.clone())
+            .with_rate_limit_wait_hook({
+                let waits = Arc::clone(&waits);
+                move |waited| waits.lock().unwrap().push(waited)
+            });
+
+        let _ = textsynth.credits().await.expect("network error");
+        assert!(waits.lock().unwrap().is_empty());
+
+        let advance = async {
+            while clock.sleeps().is_empty() {
+                tokio::task::yield_now().await;
+            }
+
+            clock.advance(Duration::from_secs(1));
+        };
+        let (credits, ()) = futures::join!(textsynth.credits(), advance);
+        assert_eq!(credits.expect("network error").expect("api error"), 42);
+        assert_eq!(*waits.lock().unwrap(), [Duration::from_secs(1)]);
+    }
+
+    #[tokio::test]
+    async fn test_with_request_customizer() {
+        let base_url = server::serve(|request| {
+            assert_eq!(request.header("x-first"), Some("1"));
+            assert_eq!(request.header("x-second"), Some("2"));
+            assert!(request.path.ends_with("?tag=test"));
+            Response::json(200, r#"{"credits": 42}"#)
+        })
+        .await;
+        let credits = test_utils::text_synth::with_base_url(&base_url)
+            .with_request_customizer(|request| request.header("x-first", "1"))
+            .with_request_customizer(|request| {
+                request.header("x-second", "2").query(&[("tag", "test")])
+            })
+            .credits()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(credits, 42);
+    }
+
+    #[tokio::test]
+    async fn test_builder_compression_disabled() {
+        let base_url = server::serve(|request| {
+            let accept_encoding = request.header("accept-encoding").unwrap_or_default();
+            assert!(!accept_encoding.contains("gzip"));
+            Response::json(200, r#"{"credits": 42}"#)
+        })
+        .await;
+        let textsynth = TextSynth::builder(test_utils::api_key())
+            .base_url(base_url)
+            .compression(false)
+            .build()
+            .unwrap();
+        assert!(!textsynth.config().compression);
+
+        let credits = textsynth
+            .credits()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(credits, 42);
+    }
+
+    #[test]
+    fn test_base_url() {
+        let textsynth = TextSynth::new(test_utils::api_key());
+        assert_eq!(textsynth.base_url(), DEFAULT_BASE_URL);
+
+        let textsynth = TextSynth::builder(test_utils::api_key())
+            .base_url("http://localhost:8080")
+            .build()
+            .unwrap();
+        assert_eq!(textsynth.base_url(), "http://localhost:8080");
+        assert_eq!(
+            textsynth.url("/v1/engines"),
+            "http://localhost:8080/v1/engines"
+        );
+    }
+
+    #[test]
+    fn test_base_url_normalization() {
+        for base_url in ["http://localhost:8080", "http://localhost:8080/"] {
+            let textsynth = TextSynth::builder(test_utils::api_key())
+                .base_url(base_url)
+                .build()
+                .unwrap();
+            assert_eq!(textsynth.base_url(), "http://localhost:8080");
+            assert_eq!(
+                textsynth.url("/v1/credits"),
+                "http://localhost:8080/v1/credits"
+            );
+        }
+
+        let textsynth = TextSynth::builder(test_utils::api_key())
+            .base_url("https://example.com/textsynth//")
+            .build()
+            .unwrap();
+        assert_eq!(
+            textsynth.url("/v1/credits"),
+            "https://example.com/textsynth/v1/credits"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_base_url_path_prefix() {
+        let base_url = server::serve(|request| {
+            assert_eq!(request.path, "/textsynth/v1/credits");
+            Response::json(200, r#"{"credits": 42}"#)
+        })
+        .await;
+        let credits = test_utils::text_synth::with_base_url(&format!("{base_url}/textsynth/"))
+            .credits()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(credits, 42);
+    }
+
+    #[tokio::test]
+    async fn test_builder_headers() {
+        let base_url = server::serve(|request| {
+            let body = serde_json::json!({
+                "user_agent": request.header("user-agent"),
+                "accept_language": request.header("accept-language"),
+            });
+            Response::json(200, body.to_string())
+        })
+        .await;
+        let headers = |textsynth: TextSynth| async move {
+            textsynth
+                .send(textsynth.get(textsynth.url("/")))
+                .await
+                .expect("network error")
+                .json::<serde_json::Value>()
+                .await
+                .expect("invalid json")
+        };
+
+        let textsynth = test_utils::text_synth::with_base_url(&base_url);
+        let body = headers(textsynth).await;
+        assert_eq!(body["user_agent"], DEFAULT_USER_AGENT);
+        assert_eq!(body["accept_language"], serde_json::Value::Null);
+
+        let textsynth = TextSynth::builder(test_utils::api_key())
+            .base_url(&base_url)
+            .user_agent("my-app/1.0")
+            .accept_language(HeaderValue::from_static("fr"))
+            .build()
+            .unwrap();
+        let body = headers(textsynth).await;
+        assert_eq!(body["user_agent"], "my-app/1.0");
+        assert_eq!(body["accept_language"], "fr");
+    }
+
+    #[tokio::test]
+    async fn test_engines() {
+        let base_url = server::serve(|request| {
+            assert_eq!(request.method, "GET");
+            assert_eq!(request.path, "/v1/engines");
+            Response::json(
+                200,
+                r#"{"engines": [{"id": "gptj_6B", "max_tokens": 2048}]}"#,
+            )
+        })
+        .await;
+        let engines = test_utils::text_synth::with_base_url(&base_url)
+            .engines()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(engines, [CustomEngineDefinition::r#static("gptj_6B", 2048)]);
+    }
+
+    #[tokio::test]
+    async fn test_engines_stream() {
+        let base_url = server::serve(|_| {
+            Response::json(
+                200,
+                r#"{"engines": [{"id": "gptj_6B", "max_tokens": 2048}, {"id": "m2m100_1_2B", "max_tokens": 1024}]}"#,
+            )
+        })
+        .await;
+        let text_synth = test_utils::text_synth::with_base_url(&base_url);
+        let engines = text_synth
+            .engines_stream()
+            .map(|result| result.expect("network error").expect("api error"))
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            engines,
+            [
+                CustomEngineDefinition::r#static("gptj_6B", 2048),
+                CustomEngineDefinition::r#static("m2m100_1_2B", 1024),
+            ]
+        );
+
+        let base_url =
+            server::serve(|_| Response::json(401, r#"{"error": "invalid api key"}"#)).await;
+        let text_synth = test_utils::text_synth::with_base_url(&base_url);
+        let items = text_synth.engines_stream().collect::<Vec<_>>().await;
+        assert_eq!(items.len(), 1);
+        assert!(matches!(&items[0], Ok(Err(error)) if error.status_code() == 401));
+    }
+
+    #[tokio::test]
+    async fn test_with_key_pool() {
+        let authorizations = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let base_url = server::serve({
+            let authorizations = Arc::clone(&authorizations);
+            move |request| {
+                let authorization = request.header("authorization").unwrap_or_default();
+                authorizations
+                    .lock()
+                    .unwrap()
+                    .push(authorization.to_owned());
+                Response::json(200, r#"{"credits": 0}"#)
+            }
+        })
+        .await;
+        let textsynth = test_utils::text_synth::with_base_url(&base_url)
+            .with_key_pool(vec!["a".into(), "b".into()]);
+        let clone = textsynth.clone();
+
+        for textsynth in [&textsynth, &clone, &textsynth] {
+            let _ = textsynth.credits().await.expect("network error");
+        }
+
+        assert_eq!(
+            *authorizations.lock().unwrap(),
+            ["Bearer a", "Bearer b", "Bearer a"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_builder_auth_scheme() {
+        let base_url = server::serve(|request| {
+            let headers = serde_json::json!({
+                "authorization": request.header("authorization"),
+                "api-key": request.header("api-key"),
+            });
+            Response::json(
+                200,
+                format!(r#"{{"text": "", "reached_end": true, "headers": {headers}}}"#),
+            )
+        })
+        .await;
+        let headers = |auth_scheme| {
+            let base_url = base_url.clone();
+            async move {
+                let text_synth = TextSynth::builder("key")
+                    .base_url(base_url)
+                    .auth_scheme(auth_scheme)
+                    .build()
+                    .unwrap();
+                let (_, raw) = text_synth
+                    .engine(test_utils::text_synth::ENGINE_DEFINITION)
+                    .text_completion("")
+                    .now_with_raw()
+                    .await
+                    .expect("network error")
+                    .expect("api error");
+                raw["headers"].clone()
+            }
+        };
+
+        assert_eq!(
+            headers(AuthScheme::Bearer).await,
+            serde_json::json!({ "authorization": "Bearer key", "api-key": null })
+        );
+        assert_eq!(
+            headers(AuthScheme::Custom {
+                header: reqwest::header::AUTHORIZATION,
+                prefix: "Token ".into(),
+            })
+            .await,
+            serde_json::json!({ "authorization": "Token key", "api-key": null })
+        );
+        assert_eq!(
+            headers(AuthScheme::Custom {
+                header: HeaderName::from_static("api-key"),
+                prefix: String::new(),
+            })
+            .await,
+            serde_json::json!({ "authorization": null, "api-key": "key" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_credits() {
+        let base_url = server::serve(|request| {
+            assert_eq!(request.path, "/v1/credits");
+            Response::json(200, r#"{"credits": 1500000000}"#)
+        })
+        .await;
+        let credits = test_utils::text_synth::with_base_url(&base_url)
+            .credits()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(credits, 1_500_000_000);
+
+        let base_url =
+            server::serve(|_| Response::json(402, r#"{"error": "not enough credits"}"#)).await;
+        let error = test_utils::text_synth::with_base_url(&base_url)
+            .credits()
+            .await
+            .expect("network error")
+            .expect_err("expected an api error");
+        assert!(error.is_out_of_credits());
+    }
+
+    #[tokio::test]
+    async fn test_with_abort_registry() {
+        let base_url = server::serve(|_| Response::hang()).await;
+        let registry = AbortRegistry::new();
+        let textsynth =
+            test_utils::text_synth::with_base_url(&base_url).with_abort_registry(registry.clone());
+        let engines = textsynth.engines();
+        let abort = async {
+            while registry.in_flight() == 0 {
+                tokio::task::yield_now().await;
+            }
+
+            registry.abort_all();
+        };
+
+        let (engines, ()) = futures::join!(engines, abort);
+        let error = engines
+            .expect("network error")
+            .expect_err("expected an error");
+        assert!(error.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let base_url = server::serve(|request| match request.path.as_str() {
+            "/" => Response::json(200, "{}"),
+            _ => Response::json(404, "{}"),
+        })
+        .await;
+        let textsynth = test_utils::text_synth::with_base_url(&base_url);
+        assert!(textsynth.ping().await.expect("network error"));
+        assert!(textsynth.is_reachable().await);
+
+        let base_url = server::serve(|_| Response::json(503, "{}")).await;
+        let textsynth = test_utils::text_synth::with_base_url(&base_url);
+        assert!(!textsynth.ping().await.expect("network error"));
+        assert!(!textsynth.is_reachable().await);
+    }
+
+    #[tokio::test]
+    async fn test_ping_unreachable() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let textsynth = test_utils::text_synth::with_base_url(&base_url);
+        assert!(textsynth.ping().await.is_err());
+        assert!(!textsynth.is_reachable().await);
+    }
+
+    #[test]
+    fn test_debug_redacts_api_key() {
+        const API_KEY: &str = "super-secret-api-key";
+
+        let builder = TextSynth::builder(API_KEY);
+        let textsynth = builder
+            .clone()
+            .build()
+            .unwrap()
+            .with_key_pool(vec![API_KEY.into()]);
+        let debug = [
+            format!("{textsynth:?}"),
+            format!("{builder:?}"),
+            format!("{:?}", textsynth.config()),
+        ];
+
+        for debug in debug {
+            assert!(!debug.contains(API_KEY), "leaked api key in {debug}");
+            assert!(debug.contains("<redacted>"));
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        let textsynth = TextSynth::builder("key")
+            .base_url("http://localhost:8080")
+            .user_agent("my-app/1.0")
+            .accept_language(HeaderValue::from_static("fr"))
+            .timeout(Duration::from_secs(30))
+            .build()
+            .unwrap();
+        let config = textsynth.config();
+        assert_eq!(config.base_url, "http://localhost:8080");
+        assert_eq!(config.user_agent, "my-app/1.0");
+        assert_eq!(config.timeout, Some(Duration::from_secs(30)));
+        assert_eq!(config.build().unwrap().config(), config);
+        assert_ne!(TextSynth::new("key").config(), config);
+    }
+
+    #[tokio::test]
+    async fn test_builder_redirects_not_followed() {
+        let followed = Arc::new(AtomicBool::new(false));
+        let other_host = server::serve({
+            let followed = Arc::clone(&followed);
+            move |_| {
+                followed.store(true, Ordering::SeqCst);
+                Response::json(200, r#"{"engines": []}"#)
+            }
+        })
+        .await;
+        let other_host = other_host.replace("127.0.0.1", "localhost");
+        let base_url = server::serve(move |request| {
+            Response::json(301, "{}").header("location", &format!("{other_host}{}", request.path))
+        })
+        .await;
+
+        let error = test_utils::text_synth::with_base_url(&base_url)
+            .engines()
+            .await
+            .expect_err("expected the redirect to fail");
+        assert!(error.is_redirect());
+        assert!(!followed.load(Ordering::SeqCst));
+
+        let engines = TextSynth::builder(test_utils::api_key())
+            .base_url(base_url)
+            .follow_redirects(true)
+            .build()
+            .unwrap()
+            .engines()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert!(engines.is_empty());
+        assert!(followed.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_builder_timeout() {
+        let base_url = server::serve(|_| Response::hang()).await;
+        let error = TextSynth::builder(test_utils::api_key())
+            .base_url(base_url)
+            .timeout(Duration::from_millis(20))
+            .build()
+            .unwrap()
+            .engines()
+            .await
+            .expect_err("expected the request to time out");
+        assert!(error.is_timeout());
+    }
+
+    #[tokio::test]
+    async fn test_builder_idle_timeout() {
+        // keep-alive whitespace, then the body, taking longer than the idle timeout in total
+        let mut chunks = vec![b" ".to_vec(); 8];
+        chunks.push(br#"{"credits": 42}"#.to_vec());
+        let base_url = server::serve(move |_| Response::chunked(200, chunks.clone())).await;
+        let text_synth = |idle_timeout| {
+            TextSynth::builder(test_utils::api_key())
+                .base_url(&base_url)
+                .idle_timeout(idle_timeout)
+                .build()
+                .unwrap()
+        };
+
+        let credits = text_synth(Duration::from_millis(50))
+            .credits()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(credits, 42);
+
+        let error = text_synth(Duration::from_millis(1))
+            .credits()
+            .await
+            .expect_err("expected the request to time out");
+        assert!(error.is_timeout());
+    }
+
+    #[tokio::test]
+    async fn test_builder_idle_timeout_clock() {
+        let chunks = vec![b" ".to_vec(), br#"{"credits": 42}"#.to_vec()];
+        let base_url = server::serve(move |_| Response::chunked(200, chunks.clone())).await;
+        let clock = MockClock::new();
+        let text_synth = TextSynth::builder(test_utils::api_key())
+            .base_url(&base_url)
+            .idle_timeout(Duration::from_nanos(1))
+            .build()
+            .unwrap()
+            .with_clock(clock.clone());
+
+        // the idle timeout only elapses once the clock is advanced
+        let credits = text_synth
+            .credits()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(credits, 42);
+        assert!(!clock.sleeps().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_builder_idle_timeout_keeps_response() {
+        let base_url = server::serve(|_| Response::json(200, r#"{"credits": 42}"#)).await;
+        let text_synth = TextSynth::builder(test_utils::api_key())
+            .base_url(&base_url)
+            .idle_timeout(Duration::from_secs(5))
+            .build()
+            .unwrap();
+        let url = format!("{base_url}/v1/credits");
+        let response = text_synth
+            .send(text_synth.get(&url))
+            .await
+            .expect("network error");
+
+        assert_eq!(response.url().as_str(), url);
+        assert!(response.remote_addr().is_some());
+        assert!(response.extensions().get::<SentAt>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_builder_max_request_bytes() {
+        let base_url =
+            server::serve(|_| Response::json(200, r#"{"text": "", "reached_end": true}"#)).await;
+        let text_synth = TextSynth::builder(test_utils::api_key())
+            .base_url(base_url)
+            .max_request_bytes(64)
+            .build()
+            .unwrap();
+        let engine = text_synth.engine(test_utils::text_synth::ENGINE_DEFINITION);
+
+        engine
+            .text_completion("short")
+            .now()
+            .await
+            .expect("network error")
+            .expect("api error");
+
+        let error = engine
+            .text_completion("long ".repeat(64))
+            .now()
+            .await
+            .expect("network error")
+            .expect_err("expected the request body to be too large");
+        assert_eq!(error.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(error.message().contains("maximum of 64 bytes"));
+    }
+
+    #[tokio::test]
+    async fn test_with_oversized_request_hook() {
+        let base_url =
+            server::serve(|_| Response::json(200, r#"{"text": "", "reached_end": true}"#)).await;
+        let warnings = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let text_synth = TextSynth::builder(test_utils::api_key())
+            .base_url(base_url)
+            .max_request_bytes(64)
+            .build()
+            .unwrap()
+            .with_oversized_request_hook({
+                let warnings = Arc::clone(&warnings);
+                move |body_bytes, max_request_bytes| {
+                    warnings
+                        .lock()
+                        .unwrap()
+                        .push((body_bytes, max_request_bytes))
+                }
+            });
+        let engine = text_synth.engine(test_utils::text_synth::ENGINE_DEFINITION);
+
+        engine
+            .text_completion("short")
+            .now()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert!(warnings.lock().unwrap().is_empty());
+
+        engine
+            .text_completion("long ".repeat(64))
+            .now()
+            .await
+            .expect("network error")
+            .expect("expected the request to be sent anyway");
+        let warnings = warnings.lock().unwrap();
+        assert!(matches!(warnings[..], [(body_bytes, 64)] if body_bytes > 64));
+    }
+
+    #[test]
+    fn test_from_vars() {
+        let textsynth = TextSynth::from_vars(|name| match name {
+            "TEXTSYNTH_API_KEY" => Some("key".into()),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(textsynth.api_key, "key");
+        assert_eq!(textsynth.base_url(), DEFAULT_BASE_URL);
+
+        let textsynth = TextSynth::from_vars(|name| match name {
+            "TEXTSYNTH_API_KEY" => Some("key".into()),
+            "TEXTSYNTH_BASE_URL" => Some("http://localhost:8080".into()),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(textsynth.base_url(), "http://localhost:8080");
+
+        let error = TextSynth::from_vars(|_| None).unwrap_err();
+        assert!(matches!(
+            error,
+            FromEnvError::MissingApiKey("TEXTSYNTH_API_KEY")
+        ));
     }
 
     #[test]
     fn test_engine() {
-        let textsynth = TextSynth::new(test_utils::api_key().into());
+        let textsynth = TextSynth::new(test_utils::api_key());
         let _ = textsynth.engine(EngineDefinition::GptJ6B);
     }
 }
@@ -0,0 +1,91 @@
+//! Trace logging of request and response bodies, enabled by the `debug-bodies` feature.
+
+use crate::utils::{self, REDACTED};
+use futures::StreamExt;
+use reqwest::header::{HeaderMap, AUTHORIZATION};
+use reqwest::{Body, Request, Response};
+
+/// Render the headers for logging, never including the value of the `Authorization` header, or of
+/// any header marked as sensitive, such as the one of a custom [`AuthScheme`].
+///
+/// [`AuthScheme`]: crate::core::AuthScheme
+fn redacted_headers(headers: &HeaderMap) -> Vec<(&str, &str)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if name == AUTHORIZATION || value.is_sensitive() {
+                REDACTED
+            } else {
+                value.to_str().unwrap_or("<non-ascii>")
+            };
+
+            (name.as_str(), value)
+        })
+        .collect()
+}
+
+pub(crate) fn log_request(request: &Request) {
+    if !log::log_enabled!(log::Level::Trace) {
+        return;
+    }
+
+    let body = request
+        .body()
+        .and_then(Body::as_bytes)
+        .map(String::from_utf8_lossy)
+        .unwrap_or_default();
+
+    log::trace!(
+        "request: {} {}, headers: {:?}, body: {body}",
+        request.method(),
+        request.url(),
+        redacted_headers(request.headers()),
+    );
+}
+
+/// Log the body of the response as it is read, without buffering it so that streaming still works.
+pub(crate) fn log_response(response: Response) -> Response {
+    if !log::log_enabled!(log::Level::Trace) {
+        return response;
+    }
+
+    let url = response.url().clone();
+    let status_code = response.status();
+
+    log::trace!(
+        "response: {url} {status_code}, headers: {:?}",
+        redacted_headers(response.headers()),
+    );
+
+    utils::map_body(response, move |response| {
+        response.bytes_stream().inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                log::trace!("response body: {url}, {}", String::from_utf8_lossy(chunk));
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::{HeaderValue, CONTENT_TYPE};
+
+    #[test]
+    fn test_redacted_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+        let headers = redacted_headers(&headers);
+        assert!(headers.contains(&("authorization", REDACTED)));
+        assert!(headers.contains(&("content-type", "application/json")));
+        assert!(!format!("{headers:?}").contains("secret"));
+
+        let mut headers = HeaderMap::new();
+        let mut value = HeaderValue::from_static("secret");
+        value.set_sensitive(true);
+        headers.insert("api-key", value);
+        assert_eq!(redacted_headers(&headers), [("api-key", REDACTED)]);
+    }
+}
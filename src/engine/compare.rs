@@ -0,0 +1,133 @@
+//! Comparing text completions, for example to A/B test engines or parameters.
+
+use crate::engine::text_completion::{TextCompletion, TextCompletionBuilder};
+
+/// Two text completions of the same prompt, along with simple statistics about how they differ.
+/// See [`compare`].
+#[derive(Debug, Clone, PartialOrd, PartialEq)]
+pub struct Comparison {
+    a: TextCompletion,
+    b: TextCompletion,
+}
+
+impl Comparison {
+    /// Compare the two given text completions.
+    pub const fn new(a: TextCompletion, b: TextCompletion) -> Self {
+        Self { a, b }
+    }
+
+    /// The first text completion.
+    pub const fn a(&self) -> &TextCompletion {
+        &self.a
+    }
+
+    /// The second text completion.
+    pub const fn b(&self) -> &TextCompletion {
+        &self.b
+    }
+
+    /// Take both text completions, in order.
+    pub fn into_inner(self) -> (TextCompletion, TextCompletion) {
+        (self.a, self.b)
+    }
+
+    /// The length of the text of the second text completion minus the one of the first, in
+    /// characters.
+    pub fn length_difference(&self) -> isize {
+        let length = |text_completion: &TextCompletion| text_completion.text().chars().count();
+        length(&self.b) as isize - length(&self.a) as isize
+    }
+
+    /// The total number of tokens of the second text completion minus the one of the first.
+    ///
+    /// Returns [`None`] if either total number of tokens is unknown.
+    pub fn total_tokens_difference(&self) -> Option<isize> {
+        Some(self.b.total_tokens()? as isize - self.a.total_tokens()? as isize)
+    }
+
+    /// The number of characters both texts start with before they diverge.
+    pub fn shared_prefix_length(&self) -> usize {
+        self.a
+            .text()
+            .chars()
+            .zip(self.b.text().chars())
+            .take_while(|(a, b)| a == b)
+            .count()
+    }
+
+    /// Whether both texts are the same.
+    pub fn is_identical(&self) -> bool {
+        self.a.text() == self.b.text()
+    }
+}
+
+/// Generate the two given text completions concurrently and compare them. The builders may use
+/// different engines, different parameters or both.
+///
+/// If either request fails, its error is returned, the one of `a` first.
+pub async fn compare(
+    a: TextCompletionBuilder<'_, '_>,
+    b: TextCompletionBuilder<'_, '_>,
+) -> reqwest::Result<crate::Result<Comparison>> {
+    let (a, b) = futures::join!(a.now(), b.now());
+
+    Ok(match (a?, b?) {
+        (Ok(a), Ok(b)) => Ok(Comparison::new(a, b)),
+        (Err(error), _) | (_, Err(error)) => Err(error),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::server::{self, Response};
+    use crate::test_utils::stream::text_completion;
+    use crate::test_utils::text_synth;
+
+    #[test]
+    fn test_comparison() {
+        let comparison = Comparison::new(
+            text_completion("the quick brown fox", true),
+            text_completion("the quick red fox jumps", true),
+        );
+        assert_eq!(comparison.shared_prefix_length(), 10);
+        assert_eq!(comparison.length_difference(), 4);
+        assert_eq!(comparison.total_tokens_difference(), Some(4));
+        assert!(!comparison.is_identical());
+
+        let comparison = Comparison::new(
+            text_completion("caf\u{e9}", true),
+            text_completion("caf\u{e9}", false),
+        );
+        assert_eq!(comparison.shared_prefix_length(), 4);
+        assert_eq!(comparison.total_tokens_difference(), None);
+        assert!(comparison.is_identical());
+    }
+
+    #[tokio::test]
+    async fn test_compare() {
+        let base_url = server::serve(|request| {
+            let text = match request.json()["temperature"].as_f64() {
+                Some(temperature) if temperature > 0.5 => "b",
+                _ => "a",
+            };
+            Response::json(
+                200,
+                format!(r#"{{"text": "{text}", "reached_end": true, "total_tokens": 5}}"#),
+            )
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let comparison = compare(
+            engine.text_completion("fn main() {").temperature(0.2),
+            engine.text_completion("fn main() {").temperature(0.8),
+        )
+        .await
+        .expect("network error")
+        .expect("api error");
+        assert_eq!(comparison.a().text(), "a");
+        assert_eq!(comparison.b().text(), "b");
+        assert_eq!(comparison.shared_prefix_length(), 0);
+    }
+}
@@ -0,0 +1,133 @@
+//! Estimating the cost of requests from their token usage.
+
+use crate::engine::definition::{
+    Boris6B, EngineDefinition, FairseqGpt13B, GptJ6B, KnownEngineDefinition,
+};
+use crate::engine::text_completion::TextCompletion;
+use std::collections::HashMap;
+
+/// The price of the tokens of an engine, in an arbitrary currency per 1000 tokens.
+#[derive(Debug, Copy, Clone, PartialOrd, PartialEq)]
+pub struct CostModel {
+    prompt_price: f64,
+    completion_price: f64,
+}
+
+impl CostModel {
+    /// Create a cost model where prompt and generated tokens have different prices per 1000
+    /// tokens.
+    pub const fn new(prompt_price: f64, completion_price: f64) -> Self {
+        Self {
+            prompt_price,
+            completion_price,
+        }
+    }
+
+    /// Create a cost model where all tokens have the same price per 1000 tokens.
+    pub const fn flat(price: f64) -> Self {
+        Self::new(price, price)
+    }
+
+    /// The price of 1000 prompt tokens.
+    pub const fn prompt_price(&self) -> f64 {
+        self.prompt_price
+    }
+
+    /// The price of 1000 generated tokens.
+    pub const fn completion_price(&self) -> f64 {
+        self.completion_price
+    }
+
+    /// The estimated cost of all the given text completions. See
+    /// [`TextCompletion::estimated_cost`].
+    pub fn estimated_cost_of<'a>(
+        &self,
+        text_completions: impl IntoIterator<Item = &'a TextCompletion>,
+    ) -> f64 {
+        text_completions
+            .into_iter()
+            .map(|text_completion| text_completion.estimated_cost(self))
+            .sum()
+    }
+}
+
+/// The [cost models](CostModel) of several engines, keyed by engine id.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PricingTable {
+    cost_models: HashMap<String, CostModel>,
+}
+
+impl PricingTable {
+    /// Create an empty pricing table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A pricing table for the engines known by this crate, in US dollars, as a starting point.
+    ///
+    /// Prices change over time and these are likely outdated: check the [pricing page] and insert
+    /// up to date prices with [`Self::insert`] before relying on the estimates.
+    ///
+    /// [pricing page]: https://textsynth.com/pricing.html
+    pub fn known_engines() -> Self {
+        let mut pricing_table = Self::new();
+        pricing_table.insert(GptJ6B::ID, CostModel::flat(0.0002));
+        pricing_table.insert(Boris6B::ID, CostModel::flat(0.0002));
+        pricing_table.insert(FairseqGpt13B::ID, CostModel::flat(0.0005));
+        pricing_table
+    }
+
+    /// Set the cost model of the engine with the given id, returning the previous one.
+    pub fn insert(
+        &mut self,
+        engine_id: impl Into<String>,
+        cost_model: CostModel,
+    ) -> Option<CostModel> {
+        self.cost_models.insert(engine_id.into(), cost_model)
+    }
+
+    /// Get the cost model of the given engine.
+    pub fn get(&self, definition: &EngineDefinition) -> Option<CostModel> {
+        self.cost_models.get(definition.id()).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::definition::CustomEngineDefinition;
+    use crate::test_utils::stream::text_completion;
+
+    fn split_usage(input_tokens: usize, output_tokens: usize) -> TextCompletion {
+        serde_json::from_value(serde_json::json!({
+            "text": "",
+            "reached_end": true,
+            "input_tokens": input_tokens,
+            "output_tokens": output_tokens,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_cost_model_estimated_cost_of() {
+        let cost_model = CostModel::new(1.0, 2.0);
+        let text_completions = [split_usage(1000, 500), split_usage(2000, 0)];
+        assert_eq!(cost_model.estimated_cost_of(&text_completions), 4.0);
+    }
+
+    #[test]
+    fn test_pricing_table() {
+        let mut pricing_table = PricingTable::known_engines();
+        assert!(pricing_table.get(&EngineDefinition::GptJ6B).is_some());
+
+        let custom = EngineDefinition::Custom(CustomEngineDefinition::new("custom", 1024));
+        assert_eq!(pricing_table.get(&custom), None);
+
+        pricing_table.insert("custom", CostModel::flat(1.0));
+        assert_eq!(pricing_table.get(&custom), Some(CostModel::flat(1.0)));
+
+        // a streamed text completion which isn't the final one has no usage yet
+        let cost = CostModel::flat(1.0).estimated_cost_of([&text_completion("a", false)]);
+        assert_eq!(cost, 0.0);
+    }
+}
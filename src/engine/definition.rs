@@ -4,6 +4,9 @@ mod private {
     pub trait Sealed {}
 }
 
+use crate::core::TextSynth;
+use crate::engine::tokenize::TokenId;
+use serde::Deserialize;
 use std::borrow::Cow;
 
 /// Declares that the implementing type represents a already known engine definition. This trait is
@@ -12,12 +15,80 @@ pub trait KnownEngineDefinition: private::Sealed {
     /// The id of this engine definition.
     const ID: &'static str;
 
+    /// The variant of [`EngineDefinition`] for this engine definition. See
+    /// [`EngineDefinition::of`].
+    const ENGINE_DEFINITION: EngineDefinition;
+
     /// The maximum amount of tokens this engine definition can have.
     const MAX_TOKENS: usize = 1024;
 
+    /// The maximum amount of tokens this engine definition can generate in a single completion,
+    /// which defaults to its context length. The API documents no separate output cap for the
+    /// known engines, so none of them override it.
+    const MAX_OUTPUT_TOKENS: usize = Self::MAX_TOKENS;
+
+    /// The id of the End-Of-Text token of this engine definition, if known. See
+    /// [`EngineDefinition::eot_token`].
+    const EOT_TOKEN: Option<TokenId> = None;
+
+    /// The approximate number of characters per token of this engine definition. See
+    /// [`EngineDefinition::approx_chars_per_token`].
+    const APPROX_CHARS_PER_TOKEN: f64 = 4.0;
+
+    /// The endpoints this engine definition supports.
+    const CAPABILITIES: Capabilities = Capabilities::COMPLETION_ONLY;
+
     /// Conversion into a [`CustomEngineDefinition`].
     const AS_CUSTOM_ENGINE_DEFINITION: CustomEngineDefinition =
-        CustomEngineDefinition::r#static(Self::ID, Self::MAX_TOKENS);
+        CustomEngineDefinition::r#static(Self::ID, Self::MAX_TOKENS)
+            .with_max_output_tokens(Self::MAX_OUTPUT_TOKENS);
+}
+
+/// The endpoints an engine supports, so that unsupported features can be disabled up front instead
+/// of failing when calling the API. Requests to an endpoint the engine doesn't support fail
+/// validation without being sent, see [`ValidationError::Unsupported`].
+///
+/// [`ValidationError::Unsupported`]: crate::error::ValidationError::Unsupported
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+#[cfg_attr(
+    feature = "serde_derives",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct Capabilities {
+    /// Whether the engine supports text completion and log probabilities.
+    pub completion: bool,
+
+    /// Whether the engine supports chat.
+    pub chat: bool,
+
+    /// Whether the engine supports translation.
+    pub translate: bool,
+
+    /// Whether the engine supports embeddings.
+    pub embeddings: bool,
+
+    /// Whether the engine supports image generation.
+    pub image: bool,
+}
+
+impl Capabilities {
+    /// An engine which only supports text completion.
+    pub const COMPLETION_ONLY: Self = Self {
+        completion: true,
+        chat: false,
+        translate: false,
+        embeddings: false,
+        image: false,
+    };
+
+    /// An engine which only supports translation.
+    pub const TRANSLATE_ONLY: Self = Self {
+        completion: false,
+        chat: false,
+        translate: true,
+        embeddings: false,
+        image: false,
+    };
 }
 
 /// [GPT-J] is a language model with 6 billion parameters trained on [the Pile] (825 GB of text data)
@@ -33,7 +104,9 @@ pub struct GptJ6B {
 
 impl KnownEngineDefinition for GptJ6B {
     const ID: &'static str = "gptj_6B";
+    const ENGINE_DEFINITION: EngineDefinition = EngineDefinition::GptJ6B;
     const MAX_TOKENS: usize = 2048;
+    const EOT_TOKEN: Option<TokenId> = Some(50256);
 }
 
 impl private::Sealed for GptJ6B {}
@@ -48,6 +121,8 @@ pub struct Boris6B {
 
 impl KnownEngineDefinition for Boris6B {
     const ID: &'static str = "boris_6B";
+    const ENGINE_DEFINITION: EngineDefinition = EngineDefinition::Boris6B;
+    const EOT_TOKEN: Option<TokenId> = Some(50256);
 }
 
 impl private::Sealed for Boris6B {}
@@ -66,11 +141,18 @@ pub struct FairseqGpt13B {
 
 impl KnownEngineDefinition for FairseqGpt13B {
     const ID: &'static str = "fairseq_gpt_13B";
+    const ENGINE_DEFINITION: EngineDefinition = EngineDefinition::FairseqGpt13B;
 }
 
 impl private::Sealed for FairseqGpt13B {}
 
 /// A custom engine definition which may or may not exist.
+///
+/// The maximum amount of tokens must be at least 1, otherwise no [`MaxTokens`] can be created for
+/// it and every completion fails validation. The constructors debug assert this; use
+/// [`Self::try_new`] when the maximum amount of tokens comes from an untrusted source.
+///
+/// [`MaxTokens`]: crate::engine::text_completion::MaxTokens
 #[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 #[cfg_attr(
     feature = "serde_derives",
@@ -82,34 +164,208 @@ pub struct CustomEngineDefinition {
 
     /// The maximum amount of tokens this engine definition can have.
     pub max_tokens: usize,
+
+    /// The maximum amount of tokens this engine definition can generate in a single completion, if
+    /// it is lower than [`Self::max_tokens`].
+    #[cfg_attr(feature = "serde_derives", serde(default))]
+    max_output_tokens: Option<usize>,
+
+    /// The endpoints this engine definition supports, if known. See
+    /// [`EngineDefinition::capabilities`].
+    #[cfg_attr(feature = "serde_derives", serde(default))]
+    capabilities: Option<Capabilities>,
 }
 
 impl CustomEngineDefinition {
     /// Creates a new custom engine definition with the given statically known id and maximum amount
     /// of tokens.
     pub const fn r#static(id: &'static str, max_tokens: usize) -> Self {
+        debug_assert!(max_tokens > 0, "max_tokens must be at least 1");
+
         Self {
             id: Cow::Borrowed(id),
             max_tokens,
+            max_output_tokens: None,
+            capabilities: None,
         }
     }
 
     /// Creates a new custom engine definition with the given runtime known id and maximum amount of
     /// tokens.
     pub const fn dynamic(id: String, max_tokens: usize) -> Self {
+        debug_assert!(max_tokens > 0, "max_tokens must be at least 1");
+
         Self {
             id: Cow::Owned(id),
             max_tokens,
+            max_output_tokens: None,
+            capabilities: None,
         }
     }
 
     /// Creates a new custom engine definition with the given id and maximum amount of tokens.
     pub fn new(id: impl Into<Cow<'static, str>>, max_tokens: usize) -> Self {
+        debug_assert!(max_tokens > 0, "max_tokens must be at least 1");
+
         Self {
             id: id.into(),
             max_tokens,
+            max_output_tokens: None,
+            capabilities: None,
         }
     }
+
+    /// Same as [`Self::new`], but returns [`None`] if `max_tokens` is zero instead of debug
+    /// asserting.
+    pub fn try_new(id: impl Into<Cow<'static, str>>, max_tokens: usize) -> Option<Self> {
+        (max_tokens > 0).then(|| Self::new(id, max_tokens))
+    }
+
+    /// Limit the amount of tokens this engine definition can generate in a single completion to
+    /// the given amount.
+    pub const fn with_max_output_tokens(mut self, max_output_tokens: usize) -> Self {
+        self.max_output_tokens = Some(max_output_tokens);
+        self
+    }
+
+    /// Declare the endpoints this engine definition supports, such as
+    /// [`Capabilities::TRANSLATE_ONLY`] for a translation engine.
+    pub const fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = Some(capabilities);
+        self
+    }
+
+    /// The maximum amount of tokens this engine definition can generate in a single completion, if
+    /// it was limited with [`Self::with_max_output_tokens`].
+    pub const fn max_output_tokens(&self) -> Option<usize> {
+        self.max_output_tokens
+    }
+
+    /// The endpoints this engine definition supports, if they were declared with
+    /// [`Self::with_capabilities`].
+    pub const fn capabilities(&self) -> Option<Capabilities> {
+        self.capabilities
+    }
+
+    /// Check whether this engine definition exists on the server `text_synth` makes requests to.
+    ///
+    /// Returns the maximum amount of tokens the server reports for the engine, so that this
+    /// definition can be corrected if it differs, or [`None`] if the server doesn't know about it.
+    /// If the server lists the engine with a maximum amount of tokens of zero, so that it can't be
+    /// used, a `502 Bad Gateway` error is returned. See [`TextSynth::engines`] for the endpoint
+    /// used.
+    pub async fn verify(
+        &self,
+        text_synth: &TextSynth,
+    ) -> reqwest::Result<crate::Result<Option<usize>>> {
+        text_synth.engine_listing().await.map(|result| {
+            result.and_then(|listing| match listing.into_info(&self.id) {
+                Some(info) if info.max_tokens == 0 => Err(crate::Error::invalid_engine(&self.id)),
+                info => Ok(info.map(|info| info.max_tokens)),
+            })
+        })
+    }
+
+    /// Creates a custom engine definition which never limits the amount of tokens, for tests which
+    /// don't care about token limits.
+    #[cfg(test)]
+    pub(crate) const fn unlimited() -> Self {
+        Self::r#static("unlimited", usize::MAX)
+    }
+}
+
+/// The metadata of an engine, as listed by the server. See [`Engine::info`].
+///
+/// Only [`Self::id`] and [`Self::max_tokens`] are part of every engine listing. The other fields
+/// are only reported by some deployments, such as self-hosted servers, so they're [`None`] if the
+/// server doesn't report them. Fields may be named in either snake case or camel case.
+///
+/// [`Engine::info`]: crate::engine::Engine::info
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize)]
+pub struct EngineInfo {
+    id: String,
+
+    #[serde(alias = "maxTokens")]
+    max_tokens: usize,
+
+    #[serde(default, alias = "maxOutputTokens")]
+    max_output_tokens: Option<usize>,
+
+    #[serde(default, alias = "parameterCount")]
+    parameters: Option<u64>,
+
+    #[serde(default)]
+    capabilities: Option<Vec<String>>,
+}
+
+impl EngineInfo {
+    /// The id of the engine.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The context length of the engine, which is the maximum amount of tokens of the prompt and
+    /// the generated text together.
+    pub const fn max_tokens(&self) -> usize {
+        self.max_tokens
+    }
+
+    /// The maximum amount of tokens the engine can generate in a single completion, if it is
+    /// lower than [`Self::max_tokens`] and the server reports it.
+    pub const fn max_output_tokens(&self) -> Option<usize> {
+        self.max_output_tokens
+    }
+
+    /// The number of parameters of the model, if the server reports it.
+    pub const fn parameters(&self) -> Option<u64> {
+        self.parameters
+    }
+
+    /// The endpoints the engine supports, if the server reports them as a list of names among
+    /// `completion`, `chat`, `translate`, `embeddings` and `image`. Unknown names are ignored.
+    pub fn capabilities(&self) -> Option<Capabilities> {
+        let names = self.capabilities.as_ref()?;
+        let supports = |name: &str| names.iter().any(|other| other == name);
+
+        Some(Capabilities {
+            completion: supports("completion"),
+            chat: supports("chat"),
+            translate: supports("translate"),
+            embeddings: supports("embeddings"),
+            image: supports("image"),
+        })
+    }
+
+    /// Convert this engine metadata into a [`CustomEngineDefinition`], including the maximum output
+    /// and capabilities if reported, returning [`None`] if the engine can't be used because its
+    /// [`Self::max_tokens`] is zero.
+    pub fn to_custom_engine_definition(&self) -> Option<CustomEngineDefinition> {
+        let mut definition = CustomEngineDefinition::try_new(self.id.clone(), self.max_tokens)?;
+        definition.max_output_tokens = self.max_output_tokens;
+        definition.capabilities = self.capabilities();
+
+        Some(definition)
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EngineListing {
+    engines: Vec<EngineInfo>,
+}
+
+impl EngineListing {
+    /// Engines reporting a maximum amount of tokens of zero can't be used, so they're skipped.
+    pub(crate) fn into_definitions(self) -> Vec<CustomEngineDefinition> {
+        self.engines
+            .into_iter()
+            .filter_map(|engine| CustomEngineDefinition::try_new(engine.id, engine.max_tokens))
+            .collect()
+    }
+
+    /// Take the metadata of the engine with the given id, if it is listed.
+    pub(crate) fn into_info(self, id: &str) -> Option<EngineInfo> {
+        self.engines.into_iter().find(|engine| engine.id == id)
+    }
 }
 
 /// Engine definitions supported by this crate.
@@ -133,8 +389,26 @@ pub enum EngineDefinition {
 }
 
 impl EngineDefinition {
+    /// Get the engine definition of the given known engine, so that code generic over a
+    /// [`KnownEngineDefinition`] can produce the matching variant.
+    pub const fn of<T: KnownEngineDefinition>() -> Self {
+        T::ENGINE_DEFINITION
+    }
+
+    /// Get every known engine definition, that is every variant except [`Self::Custom`], for
+    /// example to let users pick an engine.
+    pub const fn all_known() -> &'static [Self] {
+        const ALL_KNOWN: &[EngineDefinition] = &[
+            EngineDefinition::GptJ6B,
+            EngineDefinition::Boris6B,
+            EngineDefinition::FairseqGpt13B,
+        ];
+
+        ALL_KNOWN
+    }
+
     /// Convert this engine definition into a [`CustomEngineDefinition`].
-    pub const fn to_custom_engine_definition(&self) -> Cow<CustomEngineDefinition> {
+    pub const fn to_custom_engine_definition(&self) -> Cow<'_, CustomEngineDefinition> {
         match self {
             Self::GptJ6B => Cow::Owned(GptJ6B::AS_CUSTOM_ENGINE_DEFINITION),
             Self::Boris6B => Cow::Owned(Boris6B::AS_CUSTOM_ENGINE_DEFINITION),
@@ -157,11 +431,155 @@ impl EngineDefinition {
     pub fn max_tokens(&self) -> usize {
         self.to_custom_engine_definition().max_tokens
     }
+
+    /// Get the maximum amount of tokens this engine definition can generate in a single
+    /// completion, which is its context length unless it is capped lower.
+    pub fn max_output_tokens(&self) -> usize {
+        let definition = self.to_custom_engine_definition();
+        definition
+            .max_output_tokens
+            .map_or(definition.max_tokens, |max_output_tokens| {
+                max_output_tokens.min(definition.max_tokens)
+            })
+    }
+
+    /// Get the id of the End-Of-Text token of this engine definition, which the engine generates
+    /// when it ends the text.
+    ///
+    /// Returns [`None`] if it isn't known, which is the case for custom engine definitions.
+    pub const fn eot_token(&self) -> Option<TokenId> {
+        match self {
+            Self::GptJ6B => GptJ6B::EOT_TOKEN,
+            Self::Boris6B => Boris6B::EOT_TOKEN,
+            Self::FairseqGpt13B => FairseqGpt13B::EOT_TOKEN,
+            Self::Custom(_) => None,
+        }
+    }
+
+    /// Get the approximate number of characters per token of this engine definition, which is a
+    /// heuristic for showing rough token estimates, such as a live token count while typing. Use
+    /// [`Engine::tokenize`](crate::engine::Engine::tokenize) for exact counts.
+    ///
+    /// This is 4 for the known engines, which are oriented towards Latin scripts. Custom engine
+    /// definitions may be for any language, so they use a lower value, which overestimates the
+    /// number of tokens of Latin text rather than underestimating it for other scripts.
+    pub const fn approx_chars_per_token(&self) -> f64 {
+        match self {
+            Self::GptJ6B => GptJ6B::APPROX_CHARS_PER_TOKEN,
+            Self::Boris6B => Boris6B::APPROX_CHARS_PER_TOKEN,
+            Self::FairseqGpt13B => FairseqGpt13B::APPROX_CHARS_PER_TOKEN,
+            Self::Custom(_) => 3.0,
+        }
+    }
+
+    /// Get the approximate number of tokens of a text of the given number of characters, rounded
+    /// up. This is a heuristic, see [`Self::approx_chars_per_token`].
+    pub fn approx_tokens_for_chars(&self, chars: usize) -> usize {
+        (chars as f64 / self.approx_chars_per_token()).ceil() as usize
+    }
+
+    /// Get a pessimistic estimate of the number of tokens of a text of the given number of
+    /// characters, rounded up, for budgeting the context window without exceeding it. This assumes
+    /// tokens are half as long as [`Self::approx_chars_per_token`], since digits, punctuation,
+    /// whitespace and non-Latin scripts produce much shorter tokens than prose.
+    pub fn pessimistic_tokens_for_chars(&self, chars: usize) -> usize {
+        self.approx_tokens_for_chars(chars.saturating_mul(2))
+    }
+
+    /// Get the endpoints this engine definition supports. Custom engine definitions are assumed to
+    /// only support text completion, unless they declare otherwise with
+    /// [`CustomEngineDefinition::with_capabilities`].
+    pub const fn capabilities(&self) -> Capabilities {
+        match self {
+            Self::GptJ6B => GptJ6B::CAPABILITIES,
+            Self::Boris6B => Boris6B::CAPABILITIES,
+            Self::FairseqGpt13B => FairseqGpt13B::CAPABILITIES,
+            Self::Custom(CustomEngineDefinition {
+                capabilities: Some(capabilities),
+                ..
+            }) => *capabilities,
+            Self::Custom(_) => Capabilities::COMPLETION_ONLY,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils;
+    use crate::test_utils::server::{self, Response};
+
+    #[test]
+    fn test_engine_definition_of() {
+        fn check<T: KnownEngineDefinition>() {
+            let definition = EngineDefinition::of::<T>();
+            assert_eq!(definition.id(), T::ID);
+            assert_eq!(definition.max_tokens(), T::MAX_TOKENS);
+            assert_eq!(definition.max_output_tokens(), T::MAX_OUTPUT_TOKENS);
+        }
+
+        check::<GptJ6B>();
+        check::<Boris6B>();
+        check::<FairseqGpt13B>();
+        assert_eq!(EngineDefinition::of::<GptJ6B>(), EngineDefinition::GptJ6B);
+    }
+
+    #[test]
+    fn test_engine_definition_all_known() {
+        let all_known = EngineDefinition::all_known();
+
+        for (index, definition) in all_known.iter().enumerate() {
+            assert!(!definition.id().is_empty());
+            assert!(!matches!(definition, EngineDefinition::Custom(_)));
+            assert!(all_known[..index]
+                .iter()
+                .all(|other| other.id() != definition.id()));
+        }
+
+        // keep this exhaustive so that new known engines can't be left out
+        for definition in [
+            EngineDefinition::GptJ6B,
+            EngineDefinition::Boris6B,
+            EngineDefinition::FairseqGpt13B,
+            EngineDefinition::Custom(CustomEngineDefinition::new("custom", 1024)),
+        ] {
+            match definition {
+                EngineDefinition::GptJ6B
+                | EngineDefinition::Boris6B
+                | EngineDefinition::FairseqGpt13B => assert!(all_known.contains(&definition)),
+                EngineDefinition::Custom(_) => assert!(!all_known.contains(&definition)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_engine_definition_approx_tokens_for_chars() {
+        assert_eq!(EngineDefinition::GptJ6B.approx_chars_per_token(), 4.0);
+        assert_eq!(EngineDefinition::GptJ6B.approx_tokens_for_chars(0), 0);
+        assert_eq!(EngineDefinition::GptJ6B.approx_tokens_for_chars(9), 3);
+
+        let custom = EngineDefinition::Custom(CustomEngineDefinition::new("custom", 1024));
+        assert!(custom.approx_chars_per_token() < 4.0);
+        assert_eq!(custom.approx_tokens_for_chars(9), 3);
+    }
+
+    #[test]
+    fn test_engine_definition_pessimistic_tokens_for_chars() {
+        assert_eq!(EngineDefinition::GptJ6B.pessimistic_tokens_for_chars(0), 0);
+        assert_eq!(EngineDefinition::GptJ6B.pessimistic_tokens_for_chars(9), 5);
+
+        let custom = EngineDefinition::Custom(CustomEngineDefinition::new("custom", 1024));
+        assert_eq!(custom.pessimistic_tokens_for_chars(9), 6);
+    }
+
+    #[test]
+    fn test_engine_definition_eot_token() {
+        assert_eq!(EngineDefinition::GptJ6B.eot_token(), Some(50256));
+        assert_eq!(EngineDefinition::FairseqGpt13B.eot_token(), None);
+
+        let custom = EngineDefinition::Custom(CustomEngineDefinition::new("custom", 1024));
+        assert_eq!(custom.eot_token(), None);
+    }
 
     #[test]
     fn test_custom_engine_definition_static() {
@@ -179,6 +597,105 @@ mod tests {
         let _ = CustomEngineDefinition::new(String::from("new"), 42);
     }
 
+    #[test]
+    fn test_custom_engine_definition_try_new() {
+        assert_eq!(
+            CustomEngineDefinition::try_new("new", 42),
+            Some(CustomEngineDefinition::new("new", 42))
+        );
+        assert_eq!(CustomEngineDefinition::try_new("new", 0), None);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "max_tokens must be at least 1")]
+    fn test_custom_engine_definition_new_zero_max_tokens() {
+        let _ = CustomEngineDefinition::new("new", 0);
+    }
+
+    #[test]
+    fn test_engine_info() {
+        let info: EngineInfo = serde_json::from_str(
+            r#"{"id": "custom", "maxTokens": 4096, "maxOutputTokens": 1024, "capabilities": ["completion", "chat", "unknown"]}"#,
+        )
+        .unwrap();
+        assert_eq!(info.parameters(), None);
+        assert_eq!(
+            info.capabilities(),
+            Some(Capabilities {
+                chat: true,
+                ..Capabilities::COMPLETION_ONLY
+            })
+        );
+        assert_eq!(
+            info.to_custom_engine_definition(),
+            Some(
+                CustomEngineDefinition::new("custom", 4096)
+                    .with_max_output_tokens(1024)
+                    .with_capabilities(Capabilities {
+                        chat: true,
+                        ..Capabilities::COMPLETION_ONLY
+                    })
+            )
+        );
+
+        let info: EngineInfo = serde_json::from_str(r#"{"id": "zero", "max_tokens": 0}"#).unwrap();
+        assert_eq!(info.max_output_tokens(), None);
+        assert_eq!(info.capabilities(), None);
+        assert_eq!(info.to_custom_engine_definition(), None);
+    }
+
+    #[test]
+    fn test_engine_listing_skips_zero_max_tokens() {
+        let listing: EngineListing = serde_json::from_str(
+            r#"{"engines": [{"id": "zero", "max_tokens": 0}, {"id": "custom", "max_tokens": 4096}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            listing.into_definitions(),
+            [CustomEngineDefinition::new("custom", 4096)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_custom_engine_definition_verify() {
+        let base_url = server::serve(|_| {
+            Response::json(
+                200,
+                r#"{"engines": [{"id": "custom", "max_tokens": 4096}, {"id": "zero", "max_tokens": 0}]}"#,
+            )
+        })
+        .await;
+        let text_synth = test_utils::text_synth::with_base_url(&base_url);
+
+        let max_tokens = CustomEngineDefinition::new("custom", 2048)
+            .verify(&text_synth)
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(max_tokens, Some(4096));
+
+        let max_tokens = CustomEngineDefinition::new("missing", 2048)
+            .verify(&text_synth)
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(max_tokens, None);
+
+        let error = CustomEngineDefinition::new("zero", 2048)
+            .verify(&text_synth)
+            .await
+            .expect("network error")
+            .expect_err("expected an error for an engine listed with zero tokens");
+        assert_eq!(error.status_code(), reqwest::StatusCode::BAD_GATEWAY);
+        assert!(error.message().contains("`zero`"));
+    }
+
+    #[test]
+    fn test_custom_engine_definition_unlimited() {
+        assert_eq!(CustomEngineDefinition::unlimited().max_tokens, usize::MAX);
+    }
+
     #[test]
     fn test_engine_definition_to_custom_engine_definition() {
         assert_eq!(
@@ -214,6 +731,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_engine_definition_capabilities() {
+        let capabilities = EngineDefinition::GptJ6B.capabilities();
+        assert!(capabilities.completion);
+        assert!(!capabilities.translate);
+
+        let custom = CustomEngineDefinition::new("custom", 42);
+        assert_eq!(custom.capabilities(), None);
+        let custom = EngineDefinition::Custom(custom);
+        assert_eq!(custom.capabilities(), Capabilities::COMPLETION_ONLY);
+
+        let custom = CustomEngineDefinition::new("m2m100_1_2B", 1024)
+            .with_capabilities(Capabilities::TRANSLATE_ONLY);
+        assert_eq!(custom.capabilities(), Some(Capabilities::TRANSLATE_ONLY));
+        let custom = EngineDefinition::Custom(custom);
+        assert_eq!(custom.capabilities(), Capabilities::TRANSLATE_ONLY);
+    }
+
     #[test]
     fn test_engine_definition_max_tokens() {
         assert_eq!(EngineDefinition::GptJ6B.max_tokens(), GptJ6B::MAX_TOKENS);
@@ -227,4 +762,22 @@ mod tests {
             42
         );
     }
+
+    #[test]
+    fn test_engine_definition_max_output_tokens() {
+        assert_eq!(
+            EngineDefinition::GptJ6B.max_output_tokens(),
+            GptJ6B::MAX_OUTPUT_TOKENS
+        );
+
+        let custom = CustomEngineDefinition::new("custom", 4096);
+        assert_eq!(
+            EngineDefinition::Custom(custom.clone()).max_output_tokens(),
+            4096
+        );
+
+        let custom = custom.with_max_output_tokens(512);
+        assert_eq!(custom.max_output_tokens(), Some(512));
+        assert_eq!(EngineDefinition::Custom(custom).max_output_tokens(), 512);
+    }
 }
@@ -0,0 +1,249 @@
+//! Framing of streamed responses.
+
+use bytes::{Bytes, BytesMut};
+use futures::{stream, Stream, StreamExt};
+use std::collections::VecDeque;
+
+/// How the records of a streamed response are delimited.
+///
+/// The public API delimits records by newlines, but self-hosted servers, or proxies in front of
+/// them, may use [Server-Sent Events] instead.
+///
+/// [Server-Sent Events]: https://html.spec.whatwg.org/multipage/server-sent-events.html
+#[derive(Debug, Copy, Clone, Default, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub enum StreamFraming {
+    /// Each record is a JSON object on its own line. Blank lines are ignored.
+    #[default]
+    JsonLines,
+
+    /// Each record is the data of an event, as in `data: {...}\n\n`. The data of an event spanning
+    /// multiple `data` lines is joined with newlines, comments and other fields are ignored, and a
+    /// `[DONE]` payload is skipped.
+    ServerSentEvents,
+}
+
+/// Splits the bytes of a streamed response into the payloads of its records, regardless of how the
+/// bytes are split into chunks.
+///
+/// Chunks are copied once into a buffer, from which lines and payloads are split off without
+/// copying them again. The buffer is only scanned once for newlines, even if a line spans many
+/// chunks.
+///
+/// Payloads are only split off at newlines, which never occur inside a multi-byte UTF-8 sequence,
+/// so a character split across chunks is buffered until it is complete instead of yielding
+/// invalid text.
+#[derive(Debug)]
+pub(crate) struct Decoder {
+    framing: StreamFraming,
+    buffer: BytesMut,
+    scanned: usize,
+    data: Vec<Bytes>,
+    payloads: VecDeque<Bytes>,
+}
+
+impl Decoder {
+    pub(crate) fn new(framing: StreamFraming) -> Self {
+        Self {
+            framing,
+            buffer: BytesMut::new(),
+            scanned: 0,
+            data: Vec::new(),
+            payloads: VecDeque::new(),
+        }
+    }
+
+    /// Feed the next chunk of the response.
+    pub(crate) fn push(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+
+        while let Some(offset) = self.buffer[self.scanned..]
+            .iter()
+            .position(|&byte| byte == b'\n')
+        {
+            let line = self.buffer.split_to(self.scanned + offset + 1).freeze();
+            self.scanned = 0;
+            self.line(trim_line_ending(line));
+        }
+
+        self.scanned = self.buffer.len();
+    }
+
+    /// Signal the end of the response, flushing a trailing record without a final newline.
+    pub(crate) fn finish(&mut self) {
+        if !self.buffer.is_empty() {
+            let line = self.buffer.split().freeze();
+            self.scanned = 0;
+            self.line(line);
+        }
+
+        self.line(Bytes::new());
+    }
+
+    /// Take the next complete payload.
+    pub(crate) fn next_payload(&mut self) -> Option<Bytes> {
+        self.payloads.pop_front()
+    }
+
+    fn line(&mut self, line: Bytes) {
+        match self.framing {
+            StreamFraming::JsonLines => {
+                if !line.iter().all(u8::is_ascii_whitespace) {
+                    self.payloads.push_back(line);
+                }
+            }
+            StreamFraming::ServerSentEvents => {
+                if line.is_empty() {
+                    // a blank line dispatches the event
+                    let data = match self.data.len() {
+                        0 => return,
+                        1 => self.data.pop().unwrap(),
+                        _ => Bytes::from(self.data.drain(..).collect::<Vec<_>>().join(&b'\n')),
+                    };
+
+                    if data != b"[DONE]"[..] {
+                        self.payloads.push_back(data);
+                    }
+                } else if line.starts_with(b"data") {
+                    let value = match &line[4..] {
+                        [b':', b' ', ..] => line.slice(6..),
+                        [b':', ..] => line.slice(5..),
+                        [] => Bytes::new(),
+                        // a field which merely starts with `data`
+                        _ => return,
+                    };
+
+                    self.data.push(value);
+                }
+
+                // comments (lines starting with `:`) and other fields are ignored
+            }
+        }
+    }
+}
+
+fn trim_line_ending(mut line: Bytes) -> Bytes {
+    if line.ends_with(b"\n") {
+        line.truncate(line.len() - 1);
+    }
+
+    if line.ends_with(b"\r") {
+        line.truncate(line.len() - 1);
+    }
+
+    line
+}
+
+/// Decode the given stream of chunks into a stream of payloads.
+pub(crate) fn decode<S, B>(
+    chunks: S,
+    framing: StreamFraming,
+) -> impl Stream<Item = reqwest::Result<Bytes>>
+where
+    S: Stream<Item = reqwest::Result<B>>,
+    B: AsRef<[u8]>,
+{
+    let state = (Some(Box::pin(chunks)), Decoder::new(framing));
+
+    stream::unfold(state, |(mut chunks, mut decoder)| async move {
+        loop {
+            if let Some(payload) = decoder.next_payload() {
+                return Some((Ok(payload), (chunks, decoder)));
+            }
+
+            match chunks.as_mut()?.next().await {
+                Some(Ok(chunk)) => decoder.push(chunk.as_ref()),
+                Some(Err(error)) => return Some((Err(error), (chunks, decoder))),
+                None => {
+                    chunks = None;
+                    decoder.finish();
+                }
+            }
+        }
+    })
+}
+
+/// Decode the given chunks of a whole streamed response into the payloads of its records, for
+/// testing and benchmarking the decoding outside of a request.
+#[cfg(any(test, feature = "testing"))]
+pub fn decode_chunks(
+    framing: StreamFraming,
+    chunks: impl IntoIterator<Item = impl AsRef<[u8]>>,
+) -> Vec<Bytes> {
+    let mut decoder = Decoder::new(framing);
+    let mut payloads = Vec::new();
+
+    for chunk in chunks {
+        decoder.push(chunk.as_ref());
+        payloads.extend(std::iter::from_fn(|| decoder.next_payload()));
+    }
+
+    decoder.finish();
+    payloads.extend(std::iter::from_fn(|| decoder.next_payload()));
+    payloads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(framing: StreamFraming, chunks: &[&str]) -> Vec<String> {
+        decode_chunks(framing, chunks)
+            .into_iter()
+            .map(|payload| String::from_utf8(payload.to_vec()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_json_lines() {
+        let payloads = decode(
+            StreamFraming::JsonLines,
+            &["{\"a\": 1}\n\n{\"b\"", ": 2}\n", "\n", "{\"c\": 3}"],
+        );
+        assert_eq!(payloads, [r#"{"a": 1}"#, r#"{"b": 2}"#, r#"{"c": 3}"#]);
+    }
+
+    #[test]
+    fn test_decode_line_spanning_chunks() {
+        let line = format!("{{\"text\": \"{}\"}}", "a".repeat(1000));
+        let chunks = line
+            .as_bytes()
+            .chunks(7)
+            .map(|chunk| std::str::from_utf8(chunk).unwrap());
+        let chunks = chunks.chain(["\n"]).collect::<Vec<_>>();
+        assert_eq!(decode(StreamFraming::JsonLines, &chunks), [line]);
+    }
+
+    #[test]
+    fn test_decode_multi_byte_character_spanning_chunks() {
+        let line = "{\"text\": \"caf\u{e9} \u{1f950}\"}\n";
+        let mut decoder = Decoder::new(StreamFraming::JsonLines);
+
+        for byte in line.as_bytes() {
+            assert!(decoder.next_payload().is_none());
+            decoder.push(&[*byte]);
+        }
+
+        let payload = decoder.next_payload().unwrap();
+        assert_eq!(std::str::from_utf8(&payload).unwrap(), line.trim_end());
+    }
+
+    #[test]
+    fn test_decode_server_sent_events() {
+        let payloads = decode(
+            StreamFraming::ServerSentEvents,
+            &[
+                ": keep-alive\n\n",
+                "event: completion\ndata: {\"a\":",
+                " 1}\r\n\r\n",
+                "data:{\"b\": 2}\n\n",
+                "data: [\ndata: 3]\n\n",
+                "data: [DONE]\n\n",
+                "data: {\"c\": 4}",
+            ],
+        );
+        assert_eq!(
+            payloads,
+            [r#"{"a": 1}"#, r#"{"b": 2}"#, "[\n3]", r#"{"c": 4}"#]
+        );
+    }
+}
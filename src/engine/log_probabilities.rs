@@ -1,6 +1,12 @@
 //! Operations involving log probabilities.
 
+use crate::engine::Engine;
+use crate::error::ValidationError;
+use crate::utils;
+use crate::wire::LogProbabilitiesRequest;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tap::Pipe;
 
 /// A [`String`] which is guaranteed to not be empty.
 #[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Serialize)]
@@ -27,20 +33,31 @@ impl NonEmptyString {
     }
 }
 
-#[derive(Serialize)]
-pub(crate) struct LogProbabilitiesRequest {
-    pub(crate) context: String,
-    pub(crate) continuation: NonEmptyString,
-}
-
 /// This is logarithm of the probability that a continuation is generated after a context. It can be
 /// used to answer questions when only a few answers (such as yes/no) are possible. It can also be
 /// used to benchmark the models.
-#[derive(Debug, Copy, Clone, PartialOrd, PartialEq, Deserialize)]
+///
+/// Fields may be named in either snake case or camel case.
+///
+/// This type used to be [`Copy`], but isn't anymore since it may carry the
+/// [greedy tokens](Self::greedy_tokens), which are heap allocated. Code which relied on implicit
+/// copies must [`Clone`] it, or copy out the values it needs, such as
+/// [`Self::log_probability`].
+#[derive(Debug, Clone, PartialOrd, PartialEq, Deserialize)]
 pub struct LogProbabilities {
     logprob: f64,
+
+    #[serde(alias = "isGreedy")]
     is_greedy: bool,
+
+    #[serde(alias = "totalTokens")]
     total_tokens: usize,
+
+    #[serde(default, alias = "greedyTokens")]
+    greedy_tokens: Option<Vec<String>>,
+
+    #[serde(default, alias = "tokenIsGreedy")]
+    token_is_greedy: Option<Vec<bool>>,
 }
 
 impl LogProbabilities {
@@ -60,12 +77,147 @@ impl LogProbabilities {
     pub const fn total_tokens(&self) -> usize {
         self.total_tokens
     }
+
+    /// The perplexity, computed as `exp(-log_probability / total_tokens)` where the log probability
+    /// is a natural logarithm, as returned by the API. Lower is better.
+    ///
+    /// Note that [`Self::total_tokens`] counts the tokens of both the context and the continuation,
+    /// while the log probability is only of the continuation.
+    ///
+    /// Returns [`None`] if there are no tokens.
+    pub fn perplexity(&self) -> Option<f64> {
+        if self.total_tokens == 0 {
+            return None;
+        }
+
+        Some((-self.logprob / self.total_tokens as f64).exp())
+    }
+
+    /// The token greedy sampling would have generated at each position of `continuation`, useful to
+    /// inspect where the continuation diverges from what the model expects.
+    ///
+    /// Returns [`None`] if the API didn't return them.
+    pub fn greedy_tokens(&self) -> Option<&[String]> {
+        self.greedy_tokens.as_deref()
+    }
+
+    /// The fraction of the positions of `continuation` at which its token is the one greedy
+    /// sampling would have generated, between 0 and 1. Unlike [`Self::is_greedy`], this tells a
+    /// continuation which is greedy but for a single token apart from one which isn't at all.
+    ///
+    /// Returns [`None`] if the API didn't return per-token data.
+    pub fn greedy_ratio(&self) -> Option<f64> {
+        let token_is_greedy = self.token_is_greedy.as_deref()?;
+
+        if token_is_greedy.is_empty() {
+            return None;
+        }
+
+        let greedy = token_is_greedy.iter().filter(|&&greedy| greedy).count();
+        Some(greedy as f64 / token_is_greedy.len() as f64)
+    }
+}
+
+/// A log probabilities builder.
+#[derive(Clone)]
+#[must_use = "builders do nothing unless the request is sent"]
+pub struct LogProbabilitiesBuilder<'ts, 'e> {
+    /// The engine used to create this log probabilities request.
+    pub engine: &'e Engine<'ts>,
+
+    /// The context. If empty, the context is set to the End-Of-Text token.
+    pub context: String,
+
+    /// See [`Self::continuation`].
+    pub continuation: Option<NonEmptyString>,
+
+    /// See [`Self::timeout`].
+    pub timeout: Option<Duration>,
+}
+
+impl<'ts, 'e> LogProbabilitiesBuilder<'ts, 'e> {
+    /// Create a new log probabilities builder.
+    pub const fn new(engine: &'e Engine<'ts>, context: String) -> Self {
+        Self {
+            engine,
+            context,
+            continuation: None,
+            timeout: None,
+        }
+    }
+
+    /// Set the continuation whose log probability is computed. This must be set before calling
+    /// [`Self::send`], otherwise it fails with [`ValidationError::MissingContinuation`].
+    pub fn continuation(mut self, continuation: NonEmptyString) -> Self {
+        self.continuation = Some(continuation);
+        self
+    }
+
+    /// Cancel the request if it doesn't complete within the given timeout. In that case, a
+    /// [`reqwest::Error`] is returned for which [`reqwest::Error::is_timeout`] is `true`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Run the client side validations done before sending the request, returning every invalid
+    /// parameter at once. See [`TextCompletionBuilder::validate`].
+    ///
+    /// [`TextCompletionBuilder::validate`]: crate::engine::text_completion::TextCompletionBuilder::validate
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Vec::new();
+
+        if self.continuation.is_none() {
+            errors.push(ValidationError::MissingContinuation);
+        }
+
+        if !self.engine.definition.capabilities().completion {
+            errors.push(ValidationError::Unsupported("logprob"));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Compute the log probabilities. See [`LogProbabilities`] for information about this return
+    /// value.
+    ///
+    /// Invalid requests, such as ones without a continuation or to an engine which doesn't support
+    /// log probabilities, aren't sent. A `400 Bad Request`
+    /// [`crate::Error`] describing the problems is returned instead, see [`Self::validate`].
+    pub async fn send(self) -> reqwest::Result<crate::Result<LogProbabilities>> {
+        if let Err(errors) = self.validate() {
+            return Ok(Err(errors.into()));
+        }
+
+        let text_synth = self.engine.text_synth;
+        let engine_id = self.engine.definition.id();
+        let url = text_synth.url(&format!("/v1/engines/{engine_id}/logprob"));
+        let continuation = self.continuation.expect("the continuation was validated");
+        let mut request = text_synth.post(url).json(&LogProbabilitiesRequest {
+            context: self.context,
+            continuation,
+        });
+
+        if let Some(timeout) = self.timeout {
+            request = request.timeout(timeout);
+        }
+
+        text_synth
+            .abortable(async { text_synth.send(request).await?.pipe(utils::json).await })
+            .await
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::engine::definition::{Capabilities, CustomEngineDefinition, EngineDefinition};
     use crate::test_utils;
+    use crate::test_utils::server::{self, Response};
 
     #[test]
     fn test_non_empty_string_new() {
@@ -106,4 +258,121 @@ mod tests {
     fn test_log_probabilities_total_tokens() {
         let _ = test_utils::cache::log_probabilities().total_tokens();
     }
+
+    #[test]
+    fn test_log_probabilities_greedy_tokens() {
+        let log_probabilities: LogProbabilities = serde_json::from_str(
+            r#"{"logprob": -0.5, "is_greedy": false, "total_tokens": 3, "greedy_tokens": [" cat"]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            log_probabilities.greedy_tokens(),
+            Some(&[String::from(" cat")][..])
+        );
+
+        let log_probabilities: LogProbabilities =
+            serde_json::from_str(r#"{"logprob": -0.5, "is_greedy": true, "total_tokens": 3}"#)
+                .unwrap();
+        assert_eq!(log_probabilities.greedy_tokens(), None);
+    }
+
+    #[test]
+    fn test_log_probabilities_perplexity() {
+        let log_probabilities: LogProbabilities =
+            serde_json::from_str(r#"{"logprob": -6.0, "is_greedy": false, "total_tokens": 3}"#)
+                .unwrap();
+        assert_eq!(log_probabilities.perplexity(), Some(2.0f64.exp()));
+
+        let log_probabilities: LogProbabilities =
+            serde_json::from_str(r#"{"logprob": 0.0, "is_greedy": true, "total_tokens": 0}"#)
+                .unwrap();
+        assert_eq!(log_probabilities.perplexity(), None);
+    }
+
+    #[test]
+    fn test_log_probabilities_greedy_ratio() {
+        let log_probabilities: LogProbabilities = serde_json::from_str(
+            r#"{"logprob": -0.5, "is_greedy": false, "total_tokens": 6, "token_is_greedy": [true, true, false, true]}"#,
+        )
+        .unwrap();
+        assert_eq!(log_probabilities.greedy_ratio(), Some(0.75));
+
+        let log_probabilities: LogProbabilities =
+            serde_json::from_str(r#"{"logprob": -0.5, "is_greedy": true, "total_tokens": 3}"#)
+                .unwrap();
+        assert_eq!(log_probabilities.greedy_ratio(), None);
+    }
+
+    #[test]
+    fn test_log_probabilities_camel_case() {
+        let log_probabilities: LogProbabilities = serde_json::from_str(
+            r#"{"logprob": -0.5, "isGreedy": true, "totalTokens": 3, "greedyTokens": [" cat"], "tokenIsGreedy": [true]}"#,
+        )
+        .unwrap();
+        assert!(log_probabilities.is_greedy());
+        assert_eq!(log_probabilities.total_tokens(), 3);
+        assert_eq!(log_probabilities.greedy_ratio(), Some(1.0));
+    }
+
+    #[tokio::test]
+    async fn test_log_probabilities_builder_send() {
+        let base_url = server::serve(|request| {
+            assert_eq!(request.path, "/v1/engines/gptj_6B/logprob");
+            assert_eq!(
+                request.json(),
+                serde_json::json!({ "context": "The lazy ", "continuation": "dog" })
+            );
+            Response::json(
+                200,
+                r#"{"logprob": -1.5, "is_greedy": true, "total_tokens": 3}"#,
+            )
+        })
+        .await;
+        let text_synth = test_utils::text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(test_utils::text_synth::ENGINE_DEFINITION);
+        let log_probabilities = LogProbabilitiesBuilder::new(&engine, "The lazy ".into())
+            .continuation(NonEmptyString::new("dog".into()).unwrap())
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(log_probabilities.log_probability(), -1.5);
+        assert!(log_probabilities.is_greedy());
+    }
+
+    #[test]
+    fn test_log_probabilities_builder_validate_unsupported() {
+        let text_synth = test_utils::text_synth::get();
+        let engine = text_synth.engine(EngineDefinition::Custom(
+            CustomEngineDefinition::new("m2m100_1_2B", 1024)
+                .with_capabilities(Capabilities::TRANSLATE_ONLY),
+        ));
+        let builder = LogProbabilitiesBuilder::new(&engine, "The lazy ".into())
+            .continuation(NonEmptyString::new("dog".into()).unwrap());
+        assert_eq!(
+            builder.validate(),
+            Err(vec![ValidationError::Unsupported("logprob")])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_log_probabilities_builder_send_without_continuation() {
+        let base_url = server::serve(|_| panic!("invalid requests shouldn't be sent")).await;
+        let text_synth = test_utils::text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(test_utils::text_synth::ENGINE_DEFINITION);
+        let builder = LogProbabilitiesBuilder::new(&engine, "".into());
+        assert_eq!(
+            builder.validate(),
+            Err(vec![ValidationError::MissingContinuation])
+        );
+
+        let error = builder
+            .send()
+            .await
+            .expect("network error")
+            .expect_err("expected a validation error");
+        assert_eq!(error.status_code(), reqwest::StatusCode::BAD_REQUEST);
+        assert_eq!(error.message(), "`continuation` must be set");
+    }
 }
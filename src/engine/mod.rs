@@ -1,13 +1,27 @@
 //! Common engine types and operations.
 
+pub mod compare;
+pub mod cost;
 pub mod definition;
+pub mod framing;
 pub mod log_probabilities;
+pub mod stream;
 pub mod text_completion;
+pub mod tokenize;
+pub mod translate;
+pub mod typed;
 
 use crate::core::TextSynth;
-use crate::engine::log_probabilities::{LogProbabilities, LogProbabilitiesRequest, NonEmptyString};
-use crate::engine::text_completion::TextCompletionBuilder;
-use definition::EngineDefinition;
+use crate::engine::log_probabilities::{LogProbabilities, LogProbabilitiesBuilder, NonEmptyString};
+use crate::engine::text_completion::{TextCompletion, TextCompletionBuilder};
+use crate::engine::tokenize::{BudgetReport, TokenId};
+use crate::engine::translate::TranslateBuilder;
+use crate::engine::typed::TypedTextCompletionBuilder;
+use crate::error::ApiError;
+use definition::{EngineDefinition, EngineInfo};
+use futures::stream::FuturesUnordered;
+use futures::{Stream, StreamExt};
+use std::time::Duration;
 
 /// An engine which will be used for synthesizing text.
 #[derive(Debug, Clone)]
@@ -19,7 +33,20 @@ pub struct Engine<'ts> {
     pub definition: EngineDefinition,
 }
 
+/// Engines are equal if they have equal definitions, regardless of the [`TextSynth`] instance they
+/// make requests through.
+impl PartialEq for Engine<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.definition == other.definition
+    }
+}
+
+impl Eq for Engine<'_> {}
+
 impl<'ts> Engine<'ts> {
+    /// The maximum number of requests in flight at once in [`Self::complete_many`].
+    pub const COMPLETE_MANY_CONCURRENCY: usize = 4;
+
     /// Creates a new engine.
     pub const fn new(text_synth: &'ts TextSynth, definition: EngineDefinition) -> Self {
         Self {
@@ -28,36 +55,203 @@ impl<'ts> Engine<'ts> {
         }
     }
 
+    /// A key identifying this engine, for keying caches or per-engine rate limiters.
+    ///
+    /// Since [`Engine`] holds a reference to a [`TextSynth`] it can't be used as a key itself; this
+    /// is the id of its definition, so engines sharing a definition share a key.
+    pub fn cache_key(&self) -> &str {
+        self.definition.id()
+    }
+
+    /// Get the metadata the server lists for this engine, such as its context length, to discover
+    /// what it supports rather than relying on [`KnownEngineDefinition`] constants. See
+    /// [`EngineInfo`] for which fields every server reports, and
+    /// [`EngineInfo::to_custom_engine_definition`] to use the reported context length.
+    ///
+    /// This uses the engine listing of [`TextSynth::engines`]. If the server doesn't list this
+    /// engine, a `404 Not Found` error is returned.
+    ///
+    /// [`KnownEngineDefinition`]: definition::KnownEngineDefinition
+    pub async fn info(&self) -> reqwest::Result<crate::Result<EngineInfo>> {
+        let id = self.definition.id();
+
+        self.text_synth.engine_listing().await.map(|result| {
+            result.and_then(|listing| {
+                listing
+                    .into_info(id)
+                    .ok_or_else(|| crate::Error::engine_not_found(id))
+            })
+        })
+    }
+
     /// See [`LogProbabilities`] for information about this return value.
     ///
+    /// The API computes log probabilities in one go and doesn't stream its progress, so long
+    /// continuations can take a while. Use [`Self::log_probabilities_with_timeout`] to bound how long
+    /// to wait for them.
+    ///
     /// # Arguments
     ///   - `context`: If empty, the context is set to the End-Of-Text token.
     ///   - `continuation`: Must be a non empty string.
     pub async fn log_probabilities(
         &self,
-        context: String,
+        context: impl Into<String>,
         continuation: NonEmptyString,
     ) -> reqwest::Result<crate::Result<LogProbabilities>> {
-        let url = format!(
-            "https://api.textsynth.com/v1/engines/{}/logprob",
-            self.definition.id()
-        );
-        self.text_synth
-            .post(url)
-            .json(&LogProbabilitiesRequest {
-                context,
-                continuation,
-            })
+        self.log_probabilities_builder(context)
+            .continuation(continuation)
             .send()
-            .await?
-            .json::<crate::UntaggedResult<_>>()
             .await
-            .map(Into::into)
+    }
+
+    /// Same as [`Self::log_probabilities`] with an empty context, which the API replaces with the
+    /// End-Of-Text token. This scores the continuation unconditionally, as if it started a new
+    /// document, which is useful as a baseline to compare conditional log probabilities against.
+    pub async fn log_probabilities_from_eot(
+        &self,
+        continuation: NonEmptyString,
+    ) -> reqwest::Result<crate::Result<LogProbabilities>> {
+        self.log_probabilities(String::new(), continuation).await
+    }
+
+    /// Same as [`Self::log_probabilities`], but cancels the request if it doesn't complete within
+    /// the given timeout. In that case, a [`reqwest::Error`] is returned for which
+    /// [`reqwest::Error::is_timeout`] is `true`.
+    pub async fn log_probabilities_with_timeout(
+        &self,
+        context: impl Into<String>,
+        continuation: NonEmptyString,
+        timeout: Duration,
+    ) -> reqwest::Result<crate::Result<LogProbabilities>> {
+        self.log_probabilities_builder(context)
+            .continuation(continuation)
+            .timeout(timeout)
+            .send()
+            .await
+    }
+
+    /// Create a builder for log probabilities. See [`Self::log_probabilities`] for the meaning of
+    /// `context`.
+    pub fn log_probabilities_builder(
+        &self,
+        context: impl Into<String>,
+    ) -> LogProbabilitiesBuilder<'ts, '_> {
+        LogProbabilitiesBuilder::new(self, context.into())
     }
 
     /// Create a builder for text completion.
-    pub fn text_completion(&self, prompt: String) -> TextCompletionBuilder {
-        TextCompletionBuilder::new(self, prompt)
+    pub fn text_completion(&self, prompt: impl Into<String>) -> TextCompletionBuilder<'ts, '_> {
+        TextCompletionBuilder::new(self, prompt.into())
+    }
+
+    /// Create a builder for text completion whose prompt is given as token ids rather than text.
+    /// See [`TextCompletionBuilder::prompt_tokens`], including which servers support it.
+    pub fn text_completion_tokens(&self, tokens: Vec<TokenId>) -> TextCompletionBuilder<'ts, '_> {
+        TextCompletionBuilder::new(self, String::new()).prompt_tokens(tokens)
+    }
+
+    /// Create a builder for text completion which rejects invalid combinations of parameters at
+    /// compile time. See [`typed`] for the tradeoffs compared to [`Self::text_completion`].
+    pub fn typed_text_completion(
+        &self,
+        prompt: impl Into<String>,
+    ) -> TypedTextCompletionBuilder<'ts, '_> {
+        TypedTextCompletionBuilder::new(self, prompt.into())
+    }
+
+    /// Split the given text into the tokens of this engine, returning their ids.
+    pub async fn tokenize(&self, text: &str) -> reqwest::Result<crate::Result<Vec<TokenId>>> {
+        tokenize::tokenize(self, text).await
+    }
+
+    /// Tokenize the given prompt and report whether `max_tokens` tokens can be generated after it,
+    /// given the [maximum context length](EngineDefinition::max_tokens) of this engine.
+    pub async fn count_tokens_for_completion(
+        &self,
+        prompt: &str,
+        max_tokens: usize,
+    ) -> reqwest::Result<crate::Result<BudgetReport>> {
+        let engine_max_tokens = self.definition.max_tokens();
+
+        self.tokenize(prompt).await.map(|result| {
+            result.map(|tokens| BudgetReport::new(tokens.len(), engine_max_tokens, max_tokens))
+        })
+    }
+
+    /// Generate a text completion for each of the given prompts, yielding them as they finish
+    /// along with the index of their prompt, so that callers can reassemble them in order.
+    ///
+    /// At most [`Self::COMPLETE_MANY_CONCURRENCY`] requests are in flight at once. See
+    /// [`Self::complete_many_with_concurrency`] to choose the limit.
+    pub fn complete_many(
+        &self,
+        prompts: Vec<String>,
+    ) -> impl Stream<Item = (usize, Result<TextCompletion, ApiError>)> + '_ {
+        self.complete_many_with_concurrency(prompts, Self::COMPLETE_MANY_CONCURRENCY)
+    }
+
+    /// Same as [`Self::complete_many`], but with at most `concurrency` requests in flight at once.
+    ///
+    /// # Panics
+    /// Panics if `concurrency` is zero.
+    pub fn complete_many_with_concurrency(
+        &self,
+        prompts: Vec<String>,
+        concurrency: usize,
+    ) -> impl Stream<Item = (usize, Result<TextCompletion, ApiError>)> + '_ {
+        assert_ne!(concurrency, 0, "`concurrency` must be positive");
+
+        futures::stream::iter(prompts.into_iter().enumerate())
+            .map(move |(index, prompt)| async move {
+                let result = self.text_completion(prompt).now().await;
+                (index, ApiError::flatten(result))
+            })
+            .buffer_unordered(concurrency)
+    }
+
+    /// Generate all the given text completions concurrently and return the first one satisfying
+    /// the given predicate, along with the index of its builder, for example to generate candidates
+    /// until one passes a validator. The builders may differ in prompt, parameters or both.
+    ///
+    /// Once a text completion satisfies the predicate, the requests still in flight are aborted, so
+    /// their connections are released right away. Failed requests don't stop the search; if no
+    /// text completion satisfies the predicate and some requests failed, the first error is
+    /// returned, otherwise [`None`] is.
+    pub async fn complete_first_matching<'e>(
+        &'e self,
+        builders: impl IntoIterator<Item = TextCompletionBuilder<'ts, 'e>>,
+        predicate: impl Fn(&TextCompletion) -> bool,
+    ) -> Result<Option<(usize, TextCompletion)>, ApiError> {
+        let mut completions = builders
+            .into_iter()
+            .enumerate()
+            .map(|(index, builder)| async move { (index, ApiError::flatten(builder.now().await)) })
+            .collect::<FuturesUnordered<_>>();
+        let mut first_error = None;
+
+        while let Some((index, result)) = completions.next().await {
+            match result {
+                Ok(text_completion) if predicate(&text_completion) => {
+                    // dropping the remaining futures aborts their requests
+                    return Ok(Some((index, text_completion)));
+                }
+                Ok(_) => {}
+                Err(error) => {
+                    first_error.get_or_insert(error);
+                }
+            }
+        }
+
+        first_error.map_or(Ok(None), Err)
+    }
+
+    /// Create a builder for translating the given texts into the language with the given code.
+    pub fn translate(
+        &self,
+        text: Vec<String>,
+        target_lang: impl Into<String>,
+    ) -> TranslateBuilder<'ts, '_> {
+        TranslateBuilder::new(self, text, target_lang.into())
     }
 }
 
@@ -65,6 +259,7 @@ impl<'ts> Engine<'ts> {
 mod tests {
     use super::*;
     use crate::test_utils;
+    use crate::test_utils::server::{self, Response};
     use once_cell::sync::Lazy;
 
     #[test]
@@ -78,9 +273,281 @@ mod tests {
         let _ = Lazy::force(&test_utils::cache::LOG_PROBABILITIES);
     }
 
+    #[tokio::test]
+    async fn test_engine_log_probabilities_with_timeout() {
+        let base_url = server::serve(|_| Response::hang()).await;
+        let text_synth = test_utils::text_synth::with_base_url(&base_url);
+        let error = text_synth
+            .engine(test_utils::text_synth::ENGINE_DEFINITION)
+            .log_probabilities_with_timeout(
+                "The quick brown fox jumps over the lazy ",
+                NonEmptyString::new("dog".into()).unwrap(),
+                Duration::from_millis(100),
+            )
+            .await
+            .expect_err("expected request to time out");
+        assert!(error.is_timeout());
+    }
+
+    #[tokio::test]
+    async fn test_engine_log_probabilities_from_eot() {
+        let base_url = server::serve(|request| {
+            assert_eq!(request.path, "/v1/engines/gptj_6B/logprob");
+            assert_eq!(
+                request.json(),
+                serde_json::json!({ "context": "", "continuation": "Hello" })
+            );
+            Response::json(
+                200,
+                r#"{"logprob": -4.5, "is_greedy": false, "total_tokens": 2}"#,
+            )
+        })
+        .await;
+        let text_synth = test_utils::text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(test_utils::text_synth::ENGINE_DEFINITION);
+        let log_probabilities = engine
+            .log_probabilities_from_eot(NonEmptyString::new("Hello".into()).unwrap())
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(log_probabilities.log_probability(), -4.5);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_engine_dropped_requests_release_connections() {
+        fn open_file_descriptors() -> usize {
+            std::fs::read_dir("/proc/self/fd").unwrap().count()
+        }
+
+        let base_url = server::serve(|_| Response::hang()).await;
+        let text_synth = test_utils::text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(EngineDefinition::GptJ6B);
+        let timeout = Duration::from_millis(20);
+        let before = open_file_descriptors();
+
+        for _ in 0..50 {
+            let continuation = NonEmptyString::new("dog".into()).unwrap();
+            let log_probabilities = engine.log_probabilities("The lazy ", continuation);
+            assert!(tokio::time::timeout(timeout, log_probabilities)
+                .await
+                .is_err());
+
+            let text_completion = engine.text_completion("fn main() {").now();
+            assert!(tokio::time::timeout(timeout, text_completion)
+                .await
+                .is_err());
+        }
+
+        // let the mock server notice the closed connections
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // other tests running concurrently may hold some descriptors, but leaking a connection per
+        // request would leak at least a hundred of them
+        let after = open_file_descriptors();
+        assert!(after < before + 50, "leaked {} descriptors", after - before);
+    }
+
+    #[test]
+    fn test_engine_cache_key() {
+        let textsynth = test_utils::text_synth::get();
+        let engine = Engine::new(textsynth, EngineDefinition::GptJ6B);
+        assert_eq!(engine.cache_key(), EngineDefinition::GptJ6B.id());
+        assert_ne!(
+            engine.cache_key(),
+            textsynth.engine(EngineDefinition::Boris6B).cache_key()
+        );
+    }
+
+    #[test]
+    fn test_engine_eq() {
+        let textsynth = test_utils::text_synth::get();
+        let engine = textsynth.engine(EngineDefinition::GptJ6B);
+        assert_eq!(engine, textsynth.engine(EngineDefinition::GptJ6B));
+        assert_ne!(engine, textsynth.engine(EngineDefinition::Boris6B));
+
+        let other = test_utils::text_synth::with_base_url(textsynth.base_url());
+        assert_eq!(engine, other.engine(EngineDefinition::GptJ6B));
+    }
+
+    #[tokio::test]
+    async fn test_engine_info() {
+        let base_url = server::serve(|request| {
+            assert_eq!(request.path, "/v1/engines");
+            Response::json(
+                200,
+                r#"{"engines": [{"id": "gptj_6B", "max_tokens": 2048, "parameters": 6000000000}]}"#,
+            )
+        })
+        .await;
+        let text_synth = test_utils::text_synth::with_base_url(&base_url);
+
+        let info = text_synth
+            .engine(EngineDefinition::GptJ6B)
+            .info()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(info.id(), "gptj_6B");
+        assert_eq!(info.max_tokens(), 2048);
+        assert_eq!(info.parameters(), Some(6_000_000_000));
+
+        let error = text_synth
+            .engine(EngineDefinition::Boris6B)
+            .info()
+            .await
+            .expect("network error")
+            .expect_err("expected the engine not to be listed");
+        assert_eq!(error.status_code(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_engine_log_probabilities_builder() {
+        let _ = test_utils::text_synth::engine().log_probabilities_builder("The lazy ");
+    }
+
+    #[tokio::test]
+    async fn test_engine_tokenize() {
+        let base_url = server::serve(|request| {
+            assert_eq!(request.path, "/v1/engines/gptj_6B/tokenize");
+            Response::json(200, r#"{"tokens": [31373]}"#)
+        })
+        .await;
+        let text_synth = test_utils::text_synth::with_base_url(&base_url);
+        let tokens = text_synth
+            .engine(EngineDefinition::GptJ6B)
+            .tokenize("hello")
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(tokens, [31373]);
+    }
+
+    #[tokio::test]
+    async fn test_engine_count_tokens_for_completion() {
+        let base_url = server::serve(|_| Response::json(200, r#"{"tokens": [1, 2, 3]}"#)).await;
+        let text_synth = test_utils::text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(EngineDefinition::GptJ6B);
+        let report = engine
+            .count_tokens_for_completion("a b c", 2045)
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(report.prompt_tokens(), 3);
+        assert_eq!(report.remaining(), 2045);
+        assert!(report.fits());
+    }
+
+    #[tokio::test]
+    async fn test_engine_complete_many() {
+        let base_url = server::serve(|request| {
+            let prompt = request.json()["prompt"].as_str().unwrap().to_owned();
+            if prompt == "error" {
+                return Response::json(400, r#"{"error": "bad prompt"}"#);
+            }
+
+            let body = serde_json::json!({"text": prompt.to_uppercase(), "reached_end": true});
+            Response::json(200, body.to_string())
+        })
+        .await;
+        let text_synth = test_utils::text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(EngineDefinition::GptJ6B);
+        let prompts = ["a", "error", "c"].map(String::from).to_vec();
+        let mut results = engine
+            .complete_many_with_concurrency(prompts, 2)
+            .collect::<Vec<_>>()
+            .await;
+        results.sort_by_key(|(index, _)| *index);
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].1.as_ref().unwrap().text(), "A");
+        assert!(matches!(results[1].1, Err(ApiError::Api(_))));
+        assert_eq!(results[2].1.as_ref().unwrap().text(), "C");
+    }
+
+    #[tokio::test]
+    async fn test_engine_complete_first_matching() {
+        let base_url = server::serve(|request| {
+            let prompt = request.json()["prompt"].as_str().unwrap().to_owned();
+            match prompt.as_str() {
+                "slow" => Response::hang(),
+                "error" => Response::json(400, r#"{"error": "bad prompt"}"#),
+                _ => {
+                    let body = serde_json::json!({"text": prompt, "reached_end": true});
+                    Response::json(200, body.to_string())
+                }
+            }
+        })
+        .await;
+        let text_synth = test_utils::text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(EngineDefinition::GptJ6B);
+
+        // the hanging request is aborted rather than waited for
+        let builders =
+            ["slow", "error", "invalid", "valid"].map(|prompt| engine.text_completion(prompt));
+        let (index, text_completion) = engine
+            .complete_first_matching(builders, |text_completion| {
+                text_completion.text() == "valid"
+            })
+            .await
+            .expect("api error")
+            .expect("expected a matching text completion");
+        assert_eq!(index, 3);
+        assert_eq!(text_completion.text(), "valid");
+
+        let builders = ["invalid"].map(|prompt| engine.text_completion(prompt));
+        let result = engine.complete_first_matching(builders, |_| false).await;
+        assert!(matches!(result, Ok(None)));
+
+        let builders = ["invalid", "error"].map(|prompt| engine.text_completion(prompt));
+        let result = engine.complete_first_matching(builders, |_| false).await;
+        assert!(matches!(result, Err(ApiError::Api(_))));
+    }
+
+    #[test]
+    fn test_engine_translate() {
+        let textsynth = test_utils::text_synth::engine();
+        let _ = textsynth.translate(vec!["Hello".into()], "fr");
+        let _ = textsynth.translate(vec!["Hello".into()], String::from("fr"));
+    }
+
     #[test]
     fn test_engine_text_completion() {
         let textsynth = test_utils::text_synth::engine();
-        let _ = textsynth.text_completion("The quick brown fox jumps over the lazy ".into());
+        let _ = textsynth.text_completion("The quick brown fox jumps over the lazy ");
+        let _ = textsynth.text_completion(String::from("The quick brown fox jumps over the lazy "));
+    }
+
+    #[test]
+    fn test_engine_text_completion_tokens() {
+        let textsynth = test_utils::text_synth::engine();
+        let request = textsynth
+            .text_completion_tokens(vec![464, 2068, 7586])
+            .dry_run()
+            .unwrap();
+        assert_eq!(request["prompt"], serde_json::json!([464, 2068, 7586]));
+    }
+
+    #[tokio::test]
+    async fn test_engine_typed_text_completion() {
+        let base_url = server::serve(|request| {
+            assert_eq!(request.path, "/v1/engines/gptj_6B/completions");
+            assert_eq!(
+                request.json()["prompt"],
+                "The quick brown fox jumps over the lazy "
+            );
+            Response::json(200, r#"{"text": "dog", "reached_end": true}"#)
+        })
+        .await;
+        let text_synth = test_utils::text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(test_utils::text_synth::ENGINE_DEFINITION);
+        let text_completion = engine
+            .typed_text_completion("The quick brown fox jumps over the lazy ")
+            .now()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(text_completion.text(), "dog");
+        assert!(text_completion.reached_end());
     }
 }
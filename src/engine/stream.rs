@@ -0,0 +1,949 @@
+//! Adapters over text completion streams.
+
+use crate::engine::text_completion::{
+    TextCompletion, TextCompletionStream, TextCompletionStreamResult,
+};
+use crate::error::ApiError;
+use futures::{stream, Stream, StreamExt};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// Throughput statistics of a text completion stream.
+#[derive(Debug, Copy, Clone, PartialOrd, PartialEq)]
+pub struct StreamStats {
+    tokens: Option<usize>,
+    duration: Duration,
+    time_to_headers: Duration,
+}
+
+impl StreamStats {
+    /// The threshold of [`Self::time_to_headers`] under which the request is assumed to have
+    /// reused a pooled connection. See [`Self::likely_reused_connection`].
+    pub const COLD_CONNECTION_THRESHOLD: Duration = Duration::from_millis(150);
+
+    /// The total number of tokens, as reported by the final text completion.
+    ///
+    /// Returns [`None`] if the stream ended before the final text completion was received.
+    pub const fn tokens(&self) -> Option<usize> {
+        self.tokens
+    }
+
+    /// The wall-clock time elapsed from sending the request to the end of the stream.
+    pub const fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// The wall-clock time elapsed from sending the request to receiving the headers of the
+    /// response, which includes establishing a connection if a pooled one couldn't be reused.
+    pub const fn time_to_headers(&self) -> Duration {
+        self.time_to_headers
+    }
+
+    /// Whether the request likely reused a pooled (kept-alive) connection rather than opening a new
+    /// one, which is useful to tell cold start latency apart from warm latency.
+    ///
+    /// The HTTP client doesn't expose whether a connection was reused, so this is a heuristic:
+    /// opening a connection takes a few round trips (TCP and TLS handshakes), so the response
+    /// headers of a request on a new connection are assumed to take at least
+    /// [`Self::COLD_CONNECTION_THRESHOLD`] to arrive. This is wrong for servers which are very
+    /// close, or which are slow to respond.
+    pub fn likely_reused_connection(&self) -> bool {
+        self.time_to_headers < Self::COLD_CONNECTION_THRESHOLD
+    }
+
+    /// The number of tokens generated per second.
+    ///
+    /// Returns [`None`] if the stream ended before the final text completion was received, or if
+    /// no measurable time elapsed, such as for a stream whose chunks were already buffered.
+    pub fn tokens_per_second(&self) -> Option<f64> {
+        if self.duration.is_zero() {
+            return None;
+        }
+
+        self.tokens
+            .map(|tokens| tokens as f64 / self.duration.as_secs_f64())
+    }
+}
+
+/// An item of a text completion stream which also yields its [`StreamStats`].
+#[derive(Debug)]
+pub enum StatsStreamItem {
+    /// A text completion from the stream.
+    TextCompletion(TextCompletionStreamResult),
+
+    /// The statistics of the stream, yielded once after the last text completion.
+    Stats(StreamStats),
+}
+
+/// Extension methods for handling the errors of [`TextCompletionStream`]s.
+pub trait StreamResultExt: TextCompletionStream + Sized {
+    /// Flatten the nested result of each text completion into a single [`ApiError`], so that the
+    /// stream can be consumed without unwrapping each layer separately.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use futures::StreamExt;
+    /// use textsynth::prelude::*;
+    ///
+    /// # async fn run(engine: Engine<'_>) -> Result<(), textsynth::error::ApiError> {
+    /// let mut stream = engine
+    ///     .text_completion("fn main() {")
+    ///     .stream()
+    ///     .await?
+    ///     .flatten_ok()
+    ///     .boxed();
+    ///
+    /// while let Some(text_completion) = stream.next().await {
+    ///     print!("{}", text_completion?.text());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn flatten_ok(self) -> impl Stream<Item = Result<TextCompletion, ApiError>> {
+        self.map(ApiError::flatten_stream)
+    }
+}
+
+impl<S: TextCompletionStream> StreamResultExt for S {}
+
+/// A text completion recorded in a [`StreamTranscript`], along with when it was received.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde_derives",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct TranscriptChunk {
+    text_completion: TextCompletion,
+    elapsed: Duration,
+}
+
+impl TranscriptChunk {
+    /// The recorded text completion.
+    pub const fn text_completion(&self) -> &TextCompletion {
+        &self.text_completion
+    }
+
+    /// The time elapsed from the start of the transcript to receiving this text completion.
+    pub const fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+}
+
+/// A record of every text completion of a stream and when each was received, to replay or debug
+/// streaming sessions. See [`TextCompletionBuilder::stream_to_transcript`].
+///
+/// With the `serde_derives` feature, transcripts can be serialized and deserialized. The start of
+/// a deserialized transcript is when it was deserialized.
+///
+/// [`TextCompletionBuilder::stream_to_transcript`]:
+/// crate::engine::text_completion::TextCompletionBuilder::stream_to_transcript
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde_derives",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct StreamTranscript {
+    #[cfg_attr(feature = "serde_derives", serde(skip, default = "Instant::now"))]
+    start: Instant,
+    chunks: Vec<TranscriptChunk>,
+}
+
+impl StreamTranscript {
+    /// Create an empty transcript starting now.
+    pub fn new() -> Self {
+        Self::starting_at(Instant::now())
+    }
+
+    pub(crate) const fn starting_at(start: Instant) -> Self {
+        Self {
+            start,
+            chunks: Vec::new(),
+        }
+    }
+
+    /// Collect the given stream into a transcript starting now, returning the first error of the
+    /// stream. To keep the text completions received before an error, [`Self::record`] them
+    /// instead.
+    pub async fn collect<S: TextCompletionStream>(stream: S) -> Result<Self, ApiError> {
+        Self::new().collect_from(stream).await
+    }
+
+    pub(crate) async fn collect_from<S: TextCompletionStream>(
+        mut self,
+        stream: S,
+    ) -> Result<Self, ApiError> {
+        let mut stream = Box::pin(stream);
+
+        while let Some(item) = stream.next().await {
+            self.record(ApiError::flatten_stream(item)?);
+        }
+
+        Ok(self)
+    }
+
+    /// Record the given text completion as received now.
+    pub fn record(&mut self, text_completion: TextCompletion) {
+        self.chunks.push(TranscriptChunk {
+            text_completion,
+            elapsed: self.start.elapsed(),
+        });
+    }
+
+    /// The recorded text completions, in the order they were received.
+    pub fn chunks(&self) -> &[TranscriptChunk] {
+        &self.chunks
+    }
+
+    /// Take the recorded text completions, in the order they were received.
+    pub fn into_chunks(self) -> Vec<TranscriptChunk> {
+        self.chunks
+    }
+
+    /// The generated text, which is the text of every recorded text completion concatenated.
+    pub fn text(&self) -> String {
+        self.chunks
+            .iter()
+            .map(|chunk| chunk.text_completion.text())
+            .collect()
+    }
+}
+
+impl Default for StreamTranscript {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Stop the given stream once the text accumulated so far satisfies the predicate. The text
+/// completion which satisfied the predicate is still yielded, after which the inner stream is
+/// dropped, closing the connection.
+pub(crate) fn until<S: TextCompletionStream>(
+    text_completion_stream: S,
+    predicate: impl Fn(&str) -> bool,
+) -> impl TextCompletionStream {
+    let state = (
+        Some(Box::pin(text_completion_stream)),
+        String::new(),
+        predicate,
+    );
+
+    stream::unfold(state, |(stream, mut text, predicate)| async move {
+        let mut stream = stream?;
+        let item: TextCompletionStreamResult = stream.next().await?;
+
+        if let Ok(Ok(Ok(text_completion))) = &item {
+            text.push_str(text_completion.text());
+        }
+
+        let stream = if predicate(&text) { None } else { Some(stream) };
+        Some((item, (stream, text, predicate)))
+    })
+}
+
+/// Yield the items of the given stream, followed by its [`StreamStats`] measured from `start`.
+pub(crate) fn with_stats<S: TextCompletionStream>(
+    text_completion_stream: S,
+    start: Instant,
+    time_to_headers: Duration,
+) -> impl Stream<Item = StatsStreamItem> {
+    let state = (Some(Box::pin(text_completion_stream)), None);
+
+    stream::unfold(state, move |(stream, tokens)| async move {
+        let mut stream = stream?;
+
+        match stream.next().await {
+            Some(item) => {
+                let tokens = match &item {
+                    Ok(Ok(Ok(text_completion))) => text_completion.total_tokens().or(tokens),
+                    _ => tokens,
+                };
+
+                Some((
+                    StatsStreamItem::TextCompletion(item),
+                    (Some(stream), tokens),
+                ))
+            }
+            None => {
+                let stats = StreamStats {
+                    tokens,
+                    duration: start.elapsed(),
+                    time_to_headers,
+                };
+
+                Some((StatsStreamItem::Stats(stats), (None, tokens)))
+            }
+        }
+    })
+}
+
+/// The result of a text completion along with the approximate progress of the generation, with the
+/// same nesting as [`TextCompletionStreamResult`]. See
+/// [`TextCompletionBuilder::stream_with_progress`].
+///
+/// [`TextCompletionBuilder::stream_with_progress`]:
+/// crate::engine::text_completion::TextCompletionBuilder::stream_with_progress
+pub type ProgressStreamResult =
+    reqwest::Result<serde_json::Result<crate::Result<(TextCompletion, f64)>>>;
+
+/// Pair each text completion of the given stream with the fraction of `max_tokens` generated so
+/// far, counting a token per text completion, capped at `1.0`. The final text completion is always
+/// at `1.0`.
+pub(crate) fn with_progress<S: TextCompletionStream>(
+    text_completion_stream: S,
+    max_tokens: usize,
+) -> impl Stream<Item = ProgressStreamResult> {
+    let mut tokens = 0;
+
+    text_completion_stream.map(move |item| {
+        item.map(|result| {
+            result.map(|result| {
+                result.map(|text_completion| {
+                    tokens += 1;
+                    let progress = if text_completion.reached_end() {
+                        1.0
+                    } else {
+                        (tokens as f64 / max_tokens.max(1) as f64).min(1.0)
+                    };
+
+                    (text_completion, progress)
+                })
+            })
+        })
+    })
+}
+
+/// The result of a sentence from [`sentences`], with the same nesting as
+/// [`TextCompletionStreamResult`].
+pub type SentenceStreamResult = reqwest::Result<serde_json::Result<crate::Result<String>>>;
+
+/// Take the complete sentences at the start of the buffer out of it, without their surrounding
+/// whitespace.
+fn take_sentences(buffer: &mut String) -> Vec<String> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = buffer.char_indices().peekable();
+
+    while let Some((index, char)) = chars.next() {
+        let ends_sentence = matches!(char, '.' | '?' | '!')
+            && chars.peek().is_some_and(|(_, next)| next.is_whitespace());
+
+        if ends_sentence {
+            let end = index + char.len_utf8();
+            let sentence = buffer[start..end].trim();
+
+            if !sentence.is_empty() {
+                sentences.push(sentence.to_owned());
+            }
+
+            start = end;
+        }
+    }
+
+    buffer.drain(..start);
+    sentences
+}
+
+/// Buffer the text of the given stream and yield it as complete sentences rather than as the chunks
+/// it was generated in, for example to feed coherent units to a text to speech engine. Errors are
+/// yielded as they occur, without discarding the buffered text.
+///
+/// A sentence ends at a `.`, `?` or `!` followed by whitespace, so a sentence ending the text
+/// chunk isn't yielded until the next chunk starts with whitespace. Whatever remains once the final
+/// text completion is received, or once the stream ends, is yielded as the last sentence, even if
+/// it is incomplete.
+///
+/// This heuristic is meant for Latin scripts: abbreviations such as "Dr. Smith" are split, closing
+/// quotes after the punctuation stay at the start of the next sentence, and scripts with their own
+/// punctuation (such as `。`) or which don't separate sentences with whitespace aren't split at all.
+pub fn sentences<S: TextCompletionStream>(
+    text_completion_stream: S,
+) -> impl Stream<Item = SentenceStreamResult> {
+    let state = (
+        Some(Box::pin(text_completion_stream)),
+        String::new(),
+        VecDeque::new(),
+    );
+
+    stream::unfold(state, |(mut stream, mut buffer, mut pending)| async move {
+        loop {
+            if let Some(sentence) = pending.pop_front() {
+                return Some((sentence, (stream, buffer, pending)));
+            }
+
+            let item = match stream.as_mut() {
+                Some(stream) => stream.next().await,
+                None => return None,
+            };
+
+            match item {
+                Some(Ok(Ok(Ok(text_completion)))) => {
+                    buffer.push_str(text_completion.text());
+                    pending.extend(
+                        take_sentences(&mut buffer)
+                            .into_iter()
+                            .map(|s| Ok(Ok(Ok(s)))),
+                    );
+
+                    if text_completion.reached_end() {
+                        stream = None;
+                    }
+                }
+                Some(Ok(Ok(Err(error)))) => pending.push_back(Ok(Ok(Err(error)))),
+                Some(Ok(Err(error))) => pending.push_back(Ok(Err(error))),
+                Some(Err(error)) => pending.push_back(Err(error)),
+                None => stream = None,
+            }
+
+            if stream.is_none() {
+                let rest = buffer.trim();
+
+                if !rest.is_empty() {
+                    pending.push_back(Ok(Ok(Ok(rest.to_owned()))));
+                }
+
+                buffer.clear();
+            }
+        }
+    })
+}
+
+struct Demultiplexer<S> {
+    stream: Option<Pin<Box<S>>>,
+
+    /// [`None`] once the stream of the candidate is dropped, so that nothing is buffered for it
+    /// anymore.
+    buffers: Vec<Option<VecDeque<TextCompletionStreamResult>>>,
+    wakers: Vec<Option<Waker>>,
+}
+
+impl<S: TextCompletionStream> Demultiplexer<S> {
+    fn poll_next(
+        &mut self,
+        index: usize,
+        cx: &mut Context,
+    ) -> Poll<Option<TextCompletionStreamResult>> {
+        loop {
+            if let Some(item) = self.buffers[index].as_mut().and_then(VecDeque::pop_front) {
+                return Poll::Ready(Some(item));
+            }
+
+            let stream = match &mut self.stream {
+                Some(stream) => stream,
+                None => return Poll::Ready(None),
+            };
+
+            match stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    // errors can't be attributed to a candidate, so they go to whoever polled
+                    let target = match &item {
+                        Ok(Ok(Ok(text_completion))) => text_completion.index().unwrap_or(0),
+                        _ => index,
+                    };
+
+                    // records for candidates which weren't asked for, or whose stream was
+                    // dropped, are dropped
+                    if let Some(buffer) = self.buffers.get_mut(target).and_then(Option::as_mut) {
+                        buffer.push_back(item);
+
+                        if let Some(waker) = self.wakers[target].take() {
+                            waker.wake();
+                        }
+                    }
+                }
+                Poll::Ready(None) => {
+                    self.stream = None;
+                    self.wakers
+                        .iter_mut()
+                        .flatten()
+                        .for_each(|waker| waker.wake_by_ref());
+                }
+                Poll::Pending => {
+                    self.wakers[index] = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+/// One of the streams returned by [`demultiplex`].
+struct DemultiplexedStream<S> {
+    demultiplexer: Arc<Mutex<Demultiplexer<S>>>,
+    index: usize,
+}
+
+impl<S: TextCompletionStream> Stream for DemultiplexedStream<S> {
+    type Item = TextCompletionStreamResult;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.demultiplexer
+            .lock()
+            .expect("demultiplexer lock poisoned")
+            .poll_next(self.index, cx)
+    }
+}
+
+impl<S> Drop for DemultiplexedStream<S> {
+    fn drop(&mut self) {
+        if let Ok(mut demultiplexer) = self.demultiplexer.lock() {
+            demultiplexer.buffers[self.index] = None;
+            demultiplexer.wakers[self.index] = None;
+
+            // the underlying stream may only have registered the waker of this stream, so the
+            // others are woken to register theirs
+            demultiplexer
+                .wakers
+                .iter_mut()
+                .flatten()
+                .for_each(|waker| waker.wake_by_ref());
+        }
+    }
+}
+
+/// Split a stream which carries `n` candidates, distinguished by [`TextCompletion::index`], into
+/// a stream per candidate, where the stream at index `i` yields the text completions of the
+/// candidate with index `i`.
+///
+/// Text completions without an index are routed to the first stream. Errors can't be attributed
+/// to a candidate, so they're yielded by whichever stream was being polled when they occurred.
+/// The streams share the underlying response, and text completions are buffered until their
+/// stream is polled, so the streams can be consumed concurrently or one after the other. Once a
+/// stream is dropped, the text completions of its candidate aren't buffered anymore.
+///
+/// [`TextCompletion::index`]: crate::engine::text_completion::TextCompletion::index
+pub fn demultiplex<S: TextCompletionStream>(
+    text_completion_stream: S,
+    n: usize,
+) -> Vec<impl TextCompletionStream> {
+    let demultiplexer = Arc::new(Mutex::new(Demultiplexer {
+        stream: Some(Box::pin(text_completion_stream)),
+        buffers: (0..n).map(|_| Some(VecDeque::new())).collect(),
+        wakers: vec![None; n],
+    }));
+
+    (0..n)
+        .map(|index| DemultiplexedStream {
+            demultiplexer: Arc::clone(&demultiplexer),
+            index,
+        })
+        .collect()
+}
+
+struct Tee<S> {
+    stream: Option<Pin<Box<S>>>,
+
+    /// [`None`] once the consumer is dropped, so that nothing is buffered for it anymore.
+    buffers: Vec<Option<VecDeque<TextCompletionStreamResult>>>,
+    wakers: Vec<Option<Waker>>,
+}
+
+impl<S: TextCompletionStream> Tee<S> {
+    fn poll_next(
+        &mut self,
+        index: usize,
+        cx: &mut Context,
+    ) -> Poll<Option<TextCompletionStreamResult>> {
+        if let Some(item) = self.buffers[index].as_mut().and_then(VecDeque::pop_front) {
+            return Poll::Ready(Some(item));
+        }
+
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => return Poll::Ready(None),
+        };
+
+        match stream.as_mut().poll_next(cx) {
+            Poll::Ready(Some(item)) => {
+                for (other, buffer) in self.buffers.iter_mut().enumerate() {
+                    if other == index {
+                        continue;
+                    }
+
+                    // network and json errors can't be cloned, so only whoever polled gets them
+                    if let (Some(buffer), Some(item)) = (buffer, try_clone(&item)) {
+                        buffer.push_back(item);
+
+                        if let Some(waker) = self.wakers[other].take() {
+                            waker.wake();
+                        }
+                    }
+                }
+
+                Poll::Ready(Some(item))
+            }
+            Poll::Ready(None) => {
+                self.stream = None;
+                self.wakers
+                    .iter_mut()
+                    .flatten()
+                    .for_each(|waker| waker.wake_by_ref());
+                Poll::Ready(None)
+            }
+            Poll::Pending => {
+                self.wakers[index] = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+fn try_clone(item: &TextCompletionStreamResult) -> Option<TextCompletionStreamResult> {
+    match item {
+        Ok(Ok(result)) => Some(Ok(Ok(result.clone()))),
+        _ => None,
+    }
+}
+
+/// One of the streams returned by [`tee`].
+struct TeeStream<S> {
+    tee: Arc<Mutex<Tee<S>>>,
+    index: usize,
+}
+
+impl<S: TextCompletionStream> Stream for TeeStream<S> {
+    type Item = TextCompletionStreamResult;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.tee
+            .lock()
+            .expect("tee lock poisoned")
+            .poll_next(self.index, cx)
+    }
+}
+
+impl<S> Drop for TeeStream<S> {
+    fn drop(&mut self) {
+        if let Ok(mut tee) = self.tee.lock() {
+            tee.buffers[self.index] = None;
+            tee.wakers[self.index] = None;
+
+            // the underlying stream may only have registered the waker of this stream, so the
+            // others are woken to register theirs
+            tee.wakers
+                .iter_mut()
+                .flatten()
+                .for_each(|waker| waker.wake_by_ref());
+        }
+    }
+}
+
+/// Split a stream into `n` streams which each yield all of its text completions, for example to
+/// display a generation while logging it, without making the request twice.
+///
+/// The streams share the underlying response: whichever stream is polled reads the next text
+/// completion, which is buffered for the other streams until they're polled. A stream which falls
+/// behind therefore buffers everything the others have read ahead of it, so all the streams must be
+/// polled, concurrently or one after the other, or dropped, in which case nothing is buffered for
+/// them anymore. Network and JSON errors can't be cloned, so they're only yielded by the stream
+/// which was being polled when they occurred; API errors are yielded by all streams.
+pub fn tee<S: TextCompletionStream>(
+    text_completion_stream: S,
+    n: usize,
+) -> Vec<impl TextCompletionStream> {
+    let tee = Arc::new(Mutex::new(Tee {
+        stream: Some(Box::pin(text_completion_stream)),
+        buffers: (0..n).map(|_| Some(VecDeque::new())).collect(),
+        wakers: vec![None; n],
+    }));
+
+    (0..n)
+        .map(|index| TeeStream {
+            tee: Arc::clone(&tee),
+            index,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils;
+
+    #[tokio::test]
+    async fn test_until() {
+        let text_completions = until(test_utils::stream::from_texts(&["a", "b", "c"]), |text| {
+            text.ends_with('b')
+        })
+        .map(|item| item.unwrap().unwrap().unwrap().text().to_owned())
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(text_completions, ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_with_stats() {
+        let items = with_stats(
+            test_utils::stream::from_texts(&["a", "b", "c"]),
+            Instant::now(),
+            Duration::from_millis(10),
+        )
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(items.len(), 4);
+
+        match items.last() {
+            Some(StatsStreamItem::Stats(stats)) => {
+                assert_eq!(stats.tokens(), Some(1));
+                assert!(stats.tokens_per_second().is_some());
+                assert_eq!(stats.time_to_headers(), Duration::from_millis(10));
+                assert!(stats.likely_reused_connection());
+            }
+            _ => panic!("expected the last item to be stream stats"),
+        }
+    }
+
+    #[test]
+    fn test_stream_stats_tokens_per_second() {
+        let stats = StreamStats {
+            tokens: Some(10),
+            duration: Duration::from_secs(2),
+            time_to_headers: Duration::from_secs(1),
+        };
+        assert_eq!(stats.tokens_per_second(), Some(5.0));
+        assert!(!stats.likely_reused_connection());
+
+        let stats = StreamStats {
+            tokens: None,
+            duration: Duration::from_secs(2),
+            time_to_headers: Duration::from_secs(1),
+        };
+        assert_eq!(stats.tokens_per_second(), None);
+
+        let stats = StreamStats {
+            tokens: Some(10),
+            duration: Duration::ZERO,
+            time_to_headers: Duration::ZERO,
+        };
+        assert_eq!(stats.tokens_per_second(), None);
+    }
+
+    #[tokio::test]
+    async fn test_demultiplex() {
+        let text_completions =
+            test_utils::stream::from_indexed_texts(&[(0, "a0"), (1, "b0"), (0, "a1"), (1, "b1")]);
+        let streams = demultiplex(text_completions, 2);
+        let mut texts = Vec::new();
+
+        // consume the second candidate first to exercise buffering
+        for stream in streams.into_iter().rev() {
+            let candidate = stream
+                .map(|item| item.unwrap().unwrap().unwrap().text().to_owned())
+                .collect::<Vec<_>>()
+                .await;
+            texts.push(candidate);
+        }
+
+        assert_eq!(texts, [["b0", "b1"], ["a0", "a1"]]);
+    }
+
+    #[tokio::test]
+    async fn test_demultiplex_without_index() {
+        let mut streams = demultiplex(test_utils::stream::from_texts(&["a", "b"]), 2);
+        assert_eq!(streams.remove(0).count().await, 2);
+        assert_eq!(streams.remove(0).count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_demultiplex_dropped_while_other_pending() {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let mut streams = demultiplex(receiver, 2);
+        let mut dropped = streams.pop().unwrap();
+        let mut pending = streams.pop().unwrap();
+        let next = tokio::spawn(async move {
+            pending
+                .next()
+                .await
+                .map(|item| item.unwrap().unwrap().unwrap().text().to_owned())
+        });
+        tokio::task::yield_now().await;
+
+        // the underlying stream now only holds the waker of the stream which is dropped
+        assert!(futures::poll!(dropped.next()).is_pending());
+        drop(dropped);
+
+        for (index, text) in [(1, "b0"), (0, "a0")] {
+            let text_completion = serde_json::from_value(serde_json::json!({
+                "text": text,
+                "reached_end": false,
+                "index": index,
+            }))
+            .unwrap();
+            sender.unbounded_send(Ok(Ok(Ok(text_completion)))).unwrap();
+        }
+
+        let text = tokio::time::timeout(Duration::from_secs(1), next)
+            .await
+            .expect("the pending stream was never woken")
+            .unwrap();
+        assert_eq!(text.as_deref(), Some("a0"));
+    }
+
+    #[tokio::test]
+    async fn test_tee() {
+        let mut streams = tee(test_utils::stream::from_texts(&["a", "b", "c"]), 2);
+        let logger = streams.pop().unwrap();
+        let display = streams.pop().unwrap();
+        let texts = |stream| async move {
+            StreamExt::map(stream, |item: TextCompletionStreamResult| {
+                item.unwrap().unwrap().unwrap().text().to_owned()
+            })
+            .collect::<Vec<_>>()
+            .await
+        };
+
+        let (display, logger) = futures::join!(texts(display), texts(logger));
+        assert_eq!(display, ["a", "b", "c"]);
+        assert_eq!(logger, ["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_tee_sequential_and_dropped() {
+        let mut streams = tee(test_utils::stream::from_texts(&["a", "b"]), 3);
+        drop(streams.remove(2));
+
+        let second = streams.pop().unwrap();
+        assert_eq!(streams.pop().unwrap().count().await, 2);
+        assert_eq!(second.count().await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_tee_dropped_while_other_pending() {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let mut streams = tee(receiver, 2);
+        let mut dropped = streams.pop().unwrap();
+        let mut pending = streams.pop().unwrap();
+        let next = tokio::spawn(async move {
+            pending
+                .next()
+                .await
+                .map(|item| item.unwrap().unwrap().unwrap().text().to_owned())
+        });
+        tokio::task::yield_now().await;
+
+        // the underlying stream now only holds the waker of the stream which is dropped
+        assert!(futures::poll!(dropped.next()).is_pending());
+        drop(dropped);
+
+        let text_completion = test_utils::stream::text_completion("a", true);
+        sender.unbounded_send(Ok(Ok(Ok(text_completion)))).unwrap();
+        let text = tokio::time::timeout(Duration::from_secs(1), next)
+            .await
+            .expect("the pending stream was never woken")
+            .unwrap();
+        assert_eq!(text.as_deref(), Some("a"));
+    }
+
+    #[tokio::test]
+    async fn test_flatten_ok() {
+        let error = crate::Error::from(crate::error::ValidationError::EmptyStopSequence);
+        let text_completion = test_utils::stream::text_completion("a", false);
+        let results = stream::iter([Ok(Ok(Ok(text_completion))), Ok(Ok(Err(error)))])
+            .flatten_ok()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(results[0].as_ref().unwrap().text(), "a");
+        assert!(matches!(results[1], Err(ApiError::Api(_))));
+    }
+
+    #[tokio::test]
+    async fn test_stream_transcript() {
+        let transcript = StreamTranscript::collect(test_utils::stream::from_texts(&["a", "b"]))
+            .await
+            .unwrap();
+        assert_eq!(transcript.text(), "ab");
+        assert_eq!(transcript.chunks().len(), 2);
+        assert!(transcript.chunks()[1].text_completion().reached_end());
+        assert!(transcript.chunks()[0].elapsed() <= transcript.chunks()[1].elapsed());
+
+        let error = StreamTranscript::collect(stream::iter([Ok(Ok(Err(crate::Error::from(
+            crate::error::ValidationError::EmptyStopSequence,
+        ))))]))
+        .await
+        .expect_err("expected an api error");
+        assert!(matches!(error, ApiError::Api(_)));
+    }
+
+    #[cfg(feature = "serde_derives")]
+    #[tokio::test]
+    async fn test_stream_transcript_serde() {
+        let transcript = StreamTranscript::collect(test_utils::stream::from_texts(&["a", "b"]))
+            .await
+            .unwrap();
+        let json = serde_json::to_string(&transcript).unwrap();
+        let deserialized = serde_json::from_str::<StreamTranscript>(&json).unwrap();
+        assert_eq!(deserialized.chunks(), transcript.chunks());
+    }
+
+    #[tokio::test]
+    async fn test_with_progress() {
+        let progress = with_progress(test_utils::stream::from_texts(&["a", "b", "c", "d"]), 2)
+            .map(|item| item.unwrap().unwrap().unwrap().1)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(progress, [0.5, 1.0, 1.0, 1.0]);
+
+        let progress = with_progress(test_utils::stream::from_texts(&["a", "b"]), 10)
+            .map(|item| item.unwrap().unwrap().unwrap().1)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(progress, [0.1, 1.0]);
+    }
+
+    #[tokio::test]
+    async fn test_sentences() {
+        let chunks = ["Hello", " there. How", " are you?", "\nFine! Th", "anks"];
+        let sentences = sentences(test_utils::stream::from_texts(&chunks))
+            .map(|item| item.unwrap().unwrap().unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            sentences,
+            ["Hello there.", "How are you?", "Fine!", "Thanks"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sentences_waits_for_whitespace() {
+        // neither "3." nor "Wait..." ends a sentence until whitespace follows
+        let chunks = ["Pi is 3.", "14. Wait.", "..", " ok"];
+        let sentences = sentences(test_utils::stream::from_texts(&chunks))
+            .map(|item| item.unwrap().unwrap().unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(sentences, ["Pi is 3.14.", "Wait...", "ok"]);
+    }
+
+    #[tokio::test]
+    async fn test_sentences_errors() {
+        let error = serde_json::from_str::<crate::Error>(r#"{"error": "oops"}"#).unwrap();
+        let items = vec![
+            Ok(Ok(Ok(test_utils::stream::text_completion(
+                "One. Tw", false,
+            )))),
+            Ok(Ok(Err(error))),
+        ];
+        let items = sentences(stream::iter(items)).collect::<Vec<_>>().await;
+        assert_eq!(items.len(), 3);
+        assert_eq!(
+            items[0].as_ref().unwrap().as_ref().unwrap().as_deref(),
+            Ok("One.")
+        );
+        assert!(matches!(&items[1], Ok(Ok(Err(_)))));
+        // the partial sentence is flushed when the stream ends
+        assert_eq!(
+            items[2].as_ref().unwrap().as_ref().unwrap().as_deref(),
+            Ok("Tw")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_until_never_satisfied() {
+        let count = until(test_utils::stream::from_texts(&["a", "b", "c"]), |_| false)
+            .count()
+            .await;
+        assert_eq!(count, 3);
+    }
+}
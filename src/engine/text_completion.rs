@@ -1,26 +1,50 @@
 //! Operations involving text completion.
 
-use crate::engine::definition::EngineDefinition;
-use crate::engine::Engine;
+use crate::abort::{self, Cancelled};
+use crate::core::{SentAt, TextSynth};
+use crate::engine::cost::CostModel;
+use crate::engine::definition::{CustomEngineDefinition, EngineDefinition};
+use crate::engine::framing::{self, StreamFraming};
+use crate::engine::stream::{
+    ProgressStreamResult, SentenceStreamResult, StatsStreamItem, StreamTranscript,
+};
+use crate::engine::tokenize::{self, TokenId};
+use crate::engine::{stream, Engine};
+use crate::error::{ApiError, OutOfRangeError, ValidationError};
+use crate::utils;
+use crate::wire::{Prompt, TextCompletionRequest};
 use arrayvec::ArrayVec;
 
+use futures::future::{self, Either};
 use futures::{Stream, StreamExt};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tap::Pipe;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
 /// Maximum number of tokens to generate. A token represents typically 4 or 5 characters for latin
-/// scripts. The total number of tokens (prompt + generated text) cannot exceed the model's maximum
-/// context length.
+/// scripts, see [`EngineDefinition::approx_tokens_for_chars`] for rough estimates. The total number
+/// of tokens (prompt + generated text) cannot exceed the model's maximum context length.
 ///
-/// This depends on a [`EngineDefinition`].
+/// This depends on a [`EngineDefinition`], which may also cap the number of generated tokens lower
+/// than its context length. See [`EngineDefinition::max_output_tokens`].
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Serialize)]
 pub struct MaxTokens(usize);
 
 impl MaxTokens {
     /// Creates a new maximum number of tokens. Ensured to be valid for the given engine definition.
     pub fn new(max_tokens: usize, engine_definition: &EngineDefinition) -> Option<Self> {
-        if max_tokens <= engine_definition.max_tokens() {
+        if max_tokens <= engine_definition.max_output_tokens() {
             Some(Self(max_tokens))
         } else {
             None
@@ -48,46 +72,402 @@ impl TopP {
             None
         }
     }
+
+    /// Returns the `top_p` value.
+    pub const fn inner(&self) -> f64 {
+        self.0
+    }
+}
+
+impl TryFrom<f64> for TopP {
+    type Error = OutOfRangeError;
+
+    fn try_from(top_p: f64) -> Result<Self, Self::Error> {
+        Self::new(top_p)
+            .ok_or_else(|| OutOfRangeError::new("top_p", "between 0.0 and 1.0 inclusive", top_p))
+    }
 }
 
 /// Select the next output token among the `top_k` most likely ones. A higher `top_k` gives more
 /// diversity but a potentially less relevant output.
-pub type TopK = bounded_integer::BoundedU16<1, 1000>;
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TopK(bounded_integer::BoundedU16<1, 1000>);
+
+impl TopK {
+    /// The smallest [`TopK`] value.
+    pub const MIN: Self = Self(bounded_integer::BoundedU16::MIN);
+
+    /// The largest [`TopK`] value.
+    pub const MAX: Self = Self(bounded_integer::BoundedU16::MAX);
+
+    /// Create a new [`TopK`] value. It must be between 1 and 1000 inclusive.
+    pub const fn new(top_k: u16) -> Option<Self> {
+        match bounded_integer::BoundedU16::new(top_k) {
+            Some(top_k) => Some(Self(top_k)),
+            None => None,
+        }
+    }
+
+    /// Create a new [`TopK`] value, clamping it between 1 and 1000 inclusive.
+    pub const fn new_saturating(top_k: u16) -> Self {
+        Self(bounded_integer::BoundedU16::new_saturating(top_k))
+    }
+
+    /// Returns the `top_k` value.
+    pub const fn inner(&self) -> u16 {
+        self.0.get()
+    }
+
+    /// Returns the `top_k` value.
+    #[deprecated = "use `TopK::inner` instead"]
+    pub const fn get(self) -> u16 {
+        self.0.get()
+    }
+}
+
+impl From<bounded_integer::BoundedU16<1, 1000>> for TopK {
+    fn from(top_k: bounded_integer::BoundedU16<1, 1000>) -> Self {
+        Self(top_k)
+    }
+}
+
+impl From<TopK> for bounded_integer::BoundedU16<1, 1000> {
+    fn from(top_k: TopK) -> Self {
+        top_k.0
+    }
+}
+
+impl TryFrom<u16> for TopK {
+    type Error = OutOfRangeError;
+
+    fn try_from(top_k: u16) -> Result<Self, Self::Error> {
+        Self::new(top_k)
+            .ok_or_else(|| OutOfRangeError::new("top_k", "between 1 and 1000 inclusive", top_k))
+    }
+}
 
 /// Stop the generation when the string(s) are encountered. The generated text does not contain the
 /// string.
-pub type Stop = ArrayVec<String, 5>;
+///
+/// Matching is done by the API and is exact. See [`ClientStop`] for looser matching.
+///
+/// The API accepts at most [`Self::MAX_SEQUENCES`] stop sequences, which is enforced when adding
+/// them.
+#[derive(Debug, Clone, Default, Ord, PartialOrd, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Stop(ArrayVec<String, MAX_STOP_SEQUENCES>);
 
-#[derive(Serialize, Default)]
-struct TextCompletionRequest {
-    pub prompt: String,
+const MAX_STOP_SEQUENCES: usize = 5;
 
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub max_tokens: Option<MaxTokens>,
+impl Stop {
+    /// The maximum number of stop sequences.
+    pub const MAX_SEQUENCES: usize = MAX_STOP_SEQUENCES;
 
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub temperature: Option<f64>,
+    /// Create an empty list of stop sequences.
+    pub const fn new() -> Self {
+        Self(ArrayVec::new_const())
+    }
 
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub top_k: Option<TopK>,
+    /// Create a list of the given stop sequences, returning an error if there are more than
+    /// [`Self::MAX_SEQUENCES`] of them.
+    pub fn try_new(
+        sequences: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Result<Self, OutOfRangeError> {
+        let sequences = sequences.into_iter().map(Into::into).collect::<Vec<_>>();
 
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub top_p: Option<TopP>,
+        if sequences.len() > Self::MAX_SEQUENCES {
+            return Err(Self::too_many(sequences.len()));
+        }
 
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub stream: Option<bool>,
+        Ok(Self(sequences.into_iter().collect()))
+    }
 
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub stop: Option<Stop>,
+    /// Add a stop sequence, returning an error if there are already [`Self::MAX_SEQUENCES`] of
+    /// them.
+    pub fn push(&mut self, sequence: impl Into<String>) -> Result<(), OutOfRangeError> {
+        self.0
+            .try_push(sequence.into())
+            .map_err(|_| Self::too_many(Self::MAX_SEQUENCES + 1))
+    }
+
+    fn too_many(count: usize) -> OutOfRangeError {
+        OutOfRangeError::new(
+            "stop",
+            format!("at most {} sequences", Self::MAX_SEQUENCES),
+            format_args!("{count} sequences"),
+        )
+    }
+
+    /// Returns the stop sequences.
+    pub fn sequences(&self) -> &[String] {
+        &self.0
+    }
+
+    /// Returns the number of stop sequences.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns whether there are no stop sequences.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<ArrayVec<String, MAX_STOP_SEQUENCES>> for Stop {
+    fn from(sequences: ArrayVec<String, MAX_STOP_SEQUENCES>) -> Self {
+        Self(sequences)
+    }
+}
+
+impl From<Stop> for ArrayVec<String, MAX_STOP_SEQUENCES> {
+    fn from(stop: Stop) -> Self {
+        stop.0
+    }
+}
+
+impl TryFrom<&[String]> for Stop {
+    type Error = OutOfRangeError;
+
+    fn try_from(sequences: &[String]) -> Result<Self, Self::Error> {
+        Self::try_new(sequences.iter().cloned())
+    }
+}
+
+impl TryFrom<Vec<String>> for Stop {
+    type Error = OutOfRangeError;
+
+    fn try_from(sequences: Vec<String>) -> Result<Self, Self::Error> {
+        Self::try_new(sequences)
+    }
+}
+
+/// Stop sequences matched client side, which unlike [`Stop`] can match case-insensitively or
+/// regardless of whitespace. See [`TextCompletionBuilder::stream_until_stop`].
+///
+/// Since matching is done client side on the streamed text, the generation is only stopped once the
+/// text completion containing the match was received, and the generated text contains the match.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct ClientStop {
+    sequences: Vec<String>,
+    case_insensitive: bool,
+    normalize_whitespace: bool,
+}
+
+impl ClientStop {
+    /// Create stop sequences which match exactly.
+    pub fn new(sequences: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            sequences: sequences.into_iter().map(Into::into).collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Match the stop sequences regardless of case.
+    pub fn case_insensitive(mut self) -> Self {
+        self.case_insensitive = true;
+        self
+    }
+
+    /// Match the stop sequences regardless of whitespace, such that any run of whitespace matches
+    /// any other run of whitespace. Stop sequences made only of whitespace still match exactly.
+    pub fn normalize_whitespace(mut self) -> Self {
+        self.normalize_whitespace = true;
+        self
+    }
+
+    fn fold_case<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.case_insensitive {
+            Cow::Owned(text.to_lowercase())
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+
+    /// Collapse every run of whitespace into a single space, including at the edges.
+    fn collapse_whitespace(text: &str) -> String {
+        let mut collapsed = String::with_capacity(text.len());
+        let mut previous_whitespace = false;
+
+        for c in text.chars() {
+            let whitespace = c.is_whitespace();
+
+            if !whitespace {
+                collapsed.push(c);
+            } else if !previous_whitespace {
+                collapsed.push(' ');
+            }
+
+            previous_whitespace = whitespace;
+        }
+
+        collapsed
+    }
+
+    /// The byte index from which `text` has to be searched for a match ending after `from`: `from`,
+    /// less as many characters (counting a run of whitespace as one when normalizing it) as the
+    /// longest stop sequence has, minus one.
+    fn tail_start(&self, text: &str, from: usize) -> usize {
+        let longest = self
+            .sequences
+            .iter()
+            .map(|sequence| sequence.chars().count())
+            .max()
+            .unwrap_or(0);
+        let mut start = from;
+        let mut count = 0;
+        let mut previous_whitespace = false;
+
+        for (index, c) in text[..from].char_indices().rev() {
+            let whitespace = self.normalize_whitespace && c.is_whitespace();
+
+            if !(whitespace && previous_whitespace) {
+                count += 1;
+            }
+
+            if count >= longest {
+                break;
+            }
+
+            start = index;
+            previous_whitespace = whitespace;
+        }
+
+        start
+    }
+
+    /// Whether the given text contains any of the stop sequences.
+    pub fn matches(&self, text: &str) -> bool {
+        self.matches_after(text, 0)
+    }
+
+    /// Whether the given text contains any of the stop sequences ending after the byte index
+    /// `from`, such that only the part of the text which wasn't checked yet has to be normalized.
+    fn matches_after(&self, text: &str, from: usize) -> bool {
+        let tail = self.fold_case(&text[self.tail_start(text, from)..]);
+        let collapsed_tail = self
+            .normalize_whitespace
+            .then(|| Self::collapse_whitespace(&tail));
+
+        self.sequences
+            .iter()
+            .filter(|sequence| !sequence.is_empty())
+            .any(|sequence| {
+                let sequence = self.fold_case(sequence);
+
+                // whitespace only stop sequences are matched as they are, since collapsing them
+                // would match any whitespace
+                match &collapsed_tail {
+                    Some(collapsed_tail) if !sequence.trim().is_empty() => {
+                        collapsed_tail.contains(&Self::collapse_whitespace(&sequence))
+                    }
+                    _ => tail.contains(&*sequence),
+                }
+            })
+    }
+}
+
+/// A log probability. Unlike [`f64`], it is totally ordered, as by [`f64::total_cmp`], and
+/// hashable, so that [`TextCompletion`] can be too.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct LogProb(f64);
+
+impl LogProb {
+    /// Returns the log probability.
+    pub const fn get(self) -> f64 {
+        self.0
+    }
+}
+
+impl From<LogProb> for f64 {
+    fn from(log_prob: LogProb) -> Self {
+        log_prob.get()
+    }
+}
+
+impl PartialEq for LogProb {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for LogProb {}
+
+impl PartialOrd for LogProb {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for LogProb {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl Hash for LogProb {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state);
+    }
 }
 
 /// A text completion response from the API.
-#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Deserialize)]
+///
+/// Fields may be named in either snake case, as the API does, or camel case, as some self-hosted
+/// servers do. They're always serialized in snake case.
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct TextCompletion {
     text: String,
+
+    #[serde(alias = "reachedEnd")]
     reached_end: bool,
+
+    #[serde(alias = "truncatedPrompt", skip_serializing_if = "Option::is_none")]
     truncated_prompt: Option<bool>,
+
+    #[serde(
+        default,
+        alias = "totalTokens",
+        deserialize_with = "utils::lenient_option",
+        skip_serializing_if = "Option::is_none"
+    )]
     total_tokens: Option<usize>,
+
+    #[serde(
+        default,
+        alias = "inputTokens",
+        deserialize_with = "utils::lenient_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    input_tokens: Option<usize>,
+
+    #[serde(
+        default,
+        alias = "outputTokens",
+        deserialize_with = "utils::lenient_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    output_tokens: Option<usize>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index: Option<usize>,
+
+    #[serde(
+        default,
+        alias = "tokenLogprobs",
+        skip_serializing_if = "Option::is_none"
+    )]
+    token_logprobs: Option<Vec<Option<LogProb>>>,
+
+    #[serde(
+        default,
+        alias = "topLogprobs",
+        deserialize_with = "utils::top_logprobs",
+        skip_serializing_if = "Option::is_none"
+    )]
+    top_logprobs: Option<Vec<Vec<(String, LogProb)>>>,
 }
 
 impl TextCompletion {
@@ -115,6 +495,243 @@ impl TextCompletion {
     /// yet.
     pub fn total_tokens(&self) -> Option<usize> {
         self.total_tokens
+            .or_else(|| Some(self.input_tokens? + self.output_tokens?))
+    }
+
+    /// The number of tokens of the prompt, which is part of [`Self::total_tokens`].
+    ///
+    /// Returns [`None`] if the API didn't report it separately, unless it can be derived from the
+    /// total and the number of generated tokens.
+    pub fn prompt_tokens(&self) -> Option<usize> {
+        self.input_tokens
+            .or_else(|| self.total_tokens?.checked_sub(self.output_tokens?))
+    }
+
+    /// The number of generated tokens, which is part of [`Self::total_tokens`].
+    ///
+    /// Returns [`None`] if the API didn't report it separately, unless it can be derived from the
+    /// total and the number of prompt tokens. See [`Self::count_completion_tokens`] to count them
+    /// client side in that case.
+    pub fn completion_tokens(&self) -> Option<usize> {
+        self.output_tokens
+            .or_else(|| self.total_tokens?.checked_sub(self.input_tokens?))
+    }
+
+    /// The estimated cost of this text completion with the given cost model. Prompt and generated
+    /// tokens are priced separately if their numbers are known, otherwise all of
+    /// [`Self::total_tokens`] are priced as generated tokens, which usually overestimates.
+    ///
+    /// Returns `0.0` if the number of tokens isn't known, which is the case for streamed text
+    /// completions other than the final one, so that the cost of a stream is the sum of the cost of
+    /// its text completions.
+    pub fn estimated_cost(&self, cost_model: &CostModel) -> f64 {
+        let per_token = |price: f64| price / 1000.0;
+
+        match (self.prompt_tokens(), self.completion_tokens()) {
+            (Some(prompt_tokens), Some(completion_tokens)) => {
+                prompt_tokens as f64 * per_token(cost_model.prompt_price())
+                    + completion_tokens as f64 * per_token(cost_model.completion_price())
+            }
+            _ => self.total_tokens().unwrap_or(0) as f64 * per_token(cost_model.completion_price()),
+        }
+    }
+
+    /// Same as [`Self::completion_tokens`], but if the API didn't report it, the generated text is
+    /// tokenized with the given engine instead, which should be the one which generated it.
+    /// Tokenizations are cached, so counting the same text again doesn't make another request.
+    pub async fn count_completion_tokens(
+        &self,
+        engine: &Engine<'_>,
+    ) -> reqwest::Result<crate::Result<usize>> {
+        if let Some(completion_tokens) = self.completion_tokens() {
+            return Ok(Ok(completion_tokens));
+        }
+
+        tokenize::tokenize_cached(engine, &self.text)
+            .await
+            .map(|result| result.map(|tokens| tokens.len()))
+    }
+
+    /// The index of the candidate this text completion belongs to, when several completions are
+    /// streamed simultaneously over the same response. Use [`stream::demultiplex`] to split such a
+    /// stream into a stream per candidate.
+    ///
+    /// Returns [`None`] if the API didn't include an index, in which case there is a single
+    /// candidate.
+    pub fn index(&self) -> Option<usize> {
+        self.index
+    }
+
+    /// The log probability of each token of the text, if requested with
+    /// [`TextCompletionBuilder::logprobs`]. Combined with [`TextCompletionBuilder::echo`], this
+    /// includes the tokens of the prompt.
+    ///
+    /// Returns [`None`] if the server didn't return log probabilities. The log probability of a
+    /// token is [`None`] if it has none, as is the case for the first token of an echoed prompt.
+    pub fn token_logprobs(&self) -> Option<&[Option<LogProb>]> {
+        self.token_logprobs.as_deref()
+    }
+
+    /// The most likely alternative tokens at each position of the text along with their log
+    /// probabilities, from most to least likely, if requested with
+    /// [`TextCompletionBuilder::logprobs_top_n`]. Useful to visualize the uncertainty of the model
+    /// or suggest alternative words.
+    ///
+    /// Returns [`None`] if the server didn't return alternatives.
+    pub fn top_logprobs(&self) -> Option<&[Vec<(String, LogProb)>]> {
+        self.top_logprobs.as_deref()
+    }
+
+    /// The perplexity of the text, computed from [`Self::token_logprobs`] as the exponential of the
+    /// negated mean log probability. Lower is better. Combined with
+    /// [`TextCompletionBuilder::echo`], this scores the prompt itself.
+    ///
+    /// Tokens without a log probability are skipped. Returns [`None`] if there are no log
+    /// probabilities.
+    pub fn perplexity(&self) -> Option<f64> {
+        let token_logprobs = self.token_logprobs()?.iter().flatten();
+        let (sum, count) = token_logprobs.fold((0.0, 0), |(sum, count), token_logprob| {
+            (sum + token_logprob.get(), count + 1)
+        });
+
+        if count == 0 {
+            return None;
+        }
+
+        Some((-sum / count as f64).exp())
+    }
+
+    /// Build a text completion continuing this one, whose prompt is the prompt of the given builder
+    /// followed by the generated text, to extend the output past [`MaxTokens`]. The given builder
+    /// should be the one which generated this text completion, so that its parameters are
+    /// preserved.
+    ///
+    /// If the builder [echoes](TextCompletionBuilder::echo) the prompt, the generated text already
+    /// starts with it, so the generated text alone is used as the prompt.
+    ///
+    /// Only the text prompt is continued: a prompt given as
+    /// [token ids](TextCompletionBuilder::prompt_tokens) has to be extended with the tokens of the
+    /// generated text separately, as [`TextCompletionBuilder::now_until_end`] does.
+    pub fn continue_with<'ts, 'e>(
+        &self,
+        mut builder: TextCompletionBuilder<'ts, 'e>,
+    ) -> TextCompletionBuilder<'ts, 'e> {
+        if builder.echo == Some(true) {
+            builder.prompt.clone_from(&self.text);
+        } else {
+            builder.prompt.push_str(&self.text);
+        }
+
+        builder
+    }
+
+    fn map_text(mut self, map_text: Option<&TextMapper>) -> Self {
+        if let Some(map_text) = map_text {
+            self.text = map_text(self.text);
+        }
+
+        self
+    }
+
+    /// Append the text completion continuing this one, as returned by a request built with
+    /// [`Self::continue_with`].
+    fn extend(&mut self, continuation: Self) {
+        fn concat<T>(this: &mut Option<Vec<T>>, continuation: Option<Vec<T>>) {
+            *this = this
+                .take()
+                .zip(continuation)
+                .map(|(mut this, continuation)| {
+                    this.extend(continuation);
+                    this
+                });
+        }
+
+        self.text.push_str(&continuation.text);
+        self.reached_end = continuation.reached_end;
+        self.truncated_prompt = Some(self.truncated_prompt() || continuation.truncated_prompt());
+        // derived from the current output tokens, so it has to be computed before they're updated
+        self.input_tokens = self.prompt_tokens();
+        self.output_tokens = self
+            .completion_tokens()
+            .zip(continuation.completion_tokens())
+            .map(|(this, continuation)| this + continuation);
+        self.total_tokens = self
+            .input_tokens
+            .zip(self.output_tokens)
+            .map(|(input, output)| input + output);
+        concat(&mut self.token_logprobs, continuation.token_logprobs);
+        concat(&mut self.top_logprobs, continuation.top_logprobs);
+    }
+
+    fn check_truncation(self, error_on_truncation: bool) -> crate::Result<Self> {
+        if error_on_truncation && self.truncated_prompt() {
+            Err(crate::Error::truncated_prompt())
+        } else {
+            Ok(self)
+        }
+    }
+}
+
+/// Fluent assertions for testing prompts, which return the text completion for chaining, or panic
+/// with a message describing the text completion.
+#[cfg(any(test, feature = "testing"))]
+impl TextCompletion {
+    /// Assert that the model reached the end of the text, see [`Self::reached_end`].
+    #[track_caller]
+    pub fn assert_reached_end(self) -> Self {
+        assert!(
+            self.reached_end,
+            "expected the text completion to reach the end, got {:?}",
+            self.text,
+        );
+        self
+    }
+
+    /// Assert that the prompt was truncated, see [`Self::truncated_prompt`].
+    #[track_caller]
+    pub fn assert_truncated_prompt(self) -> Self {
+        assert!(
+            self.truncated_prompt(),
+            "expected the prompt to be truncated, got {:?}",
+            self.text,
+        );
+        self
+    }
+
+    /// Assert that the prompt wasn't truncated, see [`Self::truncated_prompt`].
+    #[track_caller]
+    pub fn assert_not_truncated_prompt(self) -> Self {
+        assert!(
+            !self.truncated_prompt(),
+            "expected the prompt not to be truncated, got {:?}",
+            self.text,
+        );
+        self
+    }
+
+    /// Assert that the generated text contains the given text.
+    #[track_caller]
+    pub fn assert_contains(self, text: &str) -> Self {
+        assert!(
+            self.text.contains(text),
+            "expected the text completion to contain {text:?}, got {:?}",
+            self.text,
+        );
+        self
+    }
+
+    /// Assert that the total number of tokens is known and at most `max_tokens`, see
+    /// [`Self::total_tokens`].
+    #[track_caller]
+    pub fn assert_total_tokens_at_most(self, max_tokens: usize) -> Self {
+        match self.total_tokens {
+            Some(total_tokens) => assert!(
+                total_tokens <= max_tokens,
+                "expected at most {max_tokens} total tokens, got {total_tokens}",
+            ),
+            None => panic!("expected the total number of tokens to be known"),
+        }
+        self
     }
 }
 
@@ -133,15 +750,28 @@ pub trait TextCompletionStream: Stream<Item = TextCompletionStreamResult> {}
 
 impl<T: Stream<Item = TextCompletionStreamResult>> TextCompletionStream for T {}
 
+/// How many text completions the channel of [`TextCompletionBuilder::stream_to_channel`] holds.
+pub const STREAM_CHANNEL_CAPACITY: usize = 64;
+
+/// A function transforming generated text. See [`TextCompletionBuilder::map_text`].
+pub type TextMapper = Arc<dyn Fn(String) -> String + Send + Sync>;
+
 /// A text completion builder.
 #[derive(Clone)]
+#[must_use = "builders do nothing unless the request is sent"]
 pub struct TextCompletionBuilder<'ts, 'e> {
     /// The engine used to create this text completion request.
     pub engine: &'e Engine<'ts>,
 
+    /// See [`Self::on_engine`].
+    pub engine_definition: Option<EngineDefinition>,
+
     /// See [`Self::prompt`].
     pub prompt: String,
 
+    /// See [`Self::prompt_tokens`].
+    pub prompt_tokens: Option<Vec<TokenId>>,
+
     /// See [`Self::max_tokens`].
     pub max_tokens: Option<MaxTokens>,
 
@@ -153,6 +783,39 @@ pub struct TextCompletionBuilder<'ts, 'e> {
 
     /// See [`Self::top_p`].
     pub top_p: Option<TopP>,
+
+    /// See [`Self::stop`].
+    pub stop: Option<Stop>,
+
+    /// See [`Self::schema`].
+    pub schema: Option<serde_json::Value>,
+
+    /// See [`Self::grammar`].
+    pub grammar: Option<String>,
+
+    /// See [`Self::map_text`].
+    pub map_text: Option<TextMapper>,
+
+    /// See [`Self::logit_bias`].
+    pub logit_bias: Option<HashMap<TokenId, f64>>,
+
+    /// See [`Self::end_token_bias`].
+    pub end_token_bias: Option<f64>,
+
+    /// See [`Self::framing`].
+    pub framing: StreamFraming,
+
+    /// See [`Self::echo`].
+    pub echo: Option<bool>,
+
+    /// See [`Self::logprobs`].
+    pub logprobs: Option<bool>,
+
+    /// See [`Self::logprobs_top_n`].
+    pub logprobs_top_n: Option<usize>,
+
+    /// See [`Self::error_on_truncation`].
+    pub error_on_truncation: bool,
 }
 
 impl<'ts, 'e> TextCompletionBuilder<'ts, 'e> {
@@ -160,20 +823,110 @@ impl<'ts, 'e> TextCompletionBuilder<'ts, 'e> {
     pub const fn new(engine: &'e Engine<'ts>, prompt: String) -> Self {
         Self {
             engine,
+            engine_definition: None,
             prompt,
+            prompt_tokens: None,
             max_tokens: None,
             temperature: None,
             top_k: None,
             top_p: None,
+            stop: None,
+            schema: None,
+            grammar: None,
+            map_text: None,
+            logit_bias: None,
+            end_token_bias: None,
+            framing: StreamFraming::JsonLines,
+            echo: None,
+            logprobs: None,
+            logprobs_top_n: None,
+            error_on_truncation: false,
         }
     }
 
+    /// Send the request to the engine with the given definition instead of the one of
+    /// [`Self::engine`], through the same [`TextSynth`] instance. Useful to compare engines on an
+    /// otherwise identical request without creating an [`Engine`] for each.
+    ///
+    /// Limits such as [`Self::max_tokens`] are validated against the new engine when the request is
+    /// sent.
+    pub fn on_engine(mut self, definition: EngineDefinition) -> Self {
+        self.engine_definition = Some(definition);
+        self
+    }
+
+    /// Send the prompt as the given token ids instead of [`Self::prompt`], to control exactly how
+    /// it's tokenized rather than having the API tokenize the text again, for example when the
+    /// prompt was built from the output of [`Engine::tokenize`]. The token ids must not be empty.
+    ///
+    /// Only servers which accept a list of token ids as the prompt support this; others respond
+    /// with an error, which is returned as usual. Methods which extend the text prompt, such as
+    /// [`Self::stream_resilient`] and [`TextCompletion::continue_with`], don't support it.
+    pub fn prompt_tokens(mut self, prompt_tokens: Vec<TokenId>) -> Self {
+        self.prompt_tokens = Some(prompt_tokens);
+        self
+    }
+
+    fn definition(&self) -> &EngineDefinition {
+        self.engine_definition
+            .as_ref()
+            .unwrap_or(&self.engine.definition)
+    }
+
     /// Set the maximum number of tokens to generate. See [`MaxTokens`] for more information.
     pub fn max_tokens(mut self, max_tokens: MaxTokens) -> Self {
         self.max_tokens = Some(max_tokens);
         self
     }
 
+    /// Set the maximum number of tokens to generate to the largest value allowed by the engine,
+    /// which is its [maximum context length](EngineDefinition::max_tokens) minus the size of the
+    /// prompt, capped at its [maximum output](EngineDefinition::max_output_tokens), maximizing the
+    /// length of the generated text.
+    ///
+    /// Unless the prompt is given as [token ids](Self::prompt_tokens), its size is estimated client
+    /// side with [`EngineDefinition::pessimistic_tokens_for_chars`], which rather overestimates it,
+    /// so that the total stays within the context. If the prompt is estimated to fill the whole
+    /// context, a single token is generated. If the engine can't generate any token, the maximum
+    /// number of tokens is left unchanged.
+    pub fn max_tokens_to_engine_limit(self) -> Self {
+        let definition = self.definition();
+        let prompt_tokens = match &self.prompt_tokens {
+            Some(prompt_tokens) => prompt_tokens.len(),
+            None => definition.pessimistic_tokens_for_chars(self.prompt.chars().count()),
+        };
+        let max_tokens = definition
+            .max_tokens()
+            .saturating_sub(prompt_tokens)
+            .min(definition.max_output_tokens())
+            .max(1);
+
+        match MaxTokens::new(max_tokens, definition) {
+            Some(max_tokens) => self.max_tokens(max_tokens),
+            None => self,
+        }
+    }
+
+    /// Set the maximum number of tokens to generate to the given fraction of the
+    /// [maximum context length](EngineDefinition::max_tokens) of the engine, rounded down, such as
+    /// `0.25` to use up to a quarter of the context for the output.
+    ///
+    /// The result is clamped between a single token and the
+    /// [maximum output](EngineDefinition::max_output_tokens) of the engine. Returns [`None`] if the
+    /// fraction isn't between `0.0` and `1.0`, inclusive.
+    pub fn max_tokens_fraction(self, fraction: f64) -> Option<Self> {
+        if !(0.0..=1.0).contains(&fraction) {
+            return None;
+        }
+
+        let definition = self.definition();
+        let max_tokens = (fraction * definition.max_tokens() as f64) as usize;
+        let max_tokens = max_tokens.clamp(1, definition.max_output_tokens().max(1));
+        let max_tokens = MaxTokens::new(max_tokens, definition)?;
+
+        Some(self.max_tokens(max_tokens))
+    }
+
     /// Sampling temperature. A higher temperature means the model will select less common tokens
     /// leading to a larger diversity but potentially less relevant output. It is usually better to
     /// tune `top_p` or `top_k`.
@@ -194,84 +947,793 @@ impl<'ts, 'e> TextCompletionBuilder<'ts, 'e> {
         self
     }
 
-    fn url(&self) -> String {
-        let engine_id = self.engine.definition.id();
-        format!("https://api.textsynth.com/v1/engines/{engine_id}/completions")
+    /// Set the sequences which stop the generation. See [`Stop`] for more information.
+    ///
+    /// This applies to [`Self::now`], [`Self::stream`] and every other way of generating the text
+    /// completion.
+    pub fn stop(mut self, stop: Stop) -> Self {
+        self.stop = Some(stop);
+        self
     }
 
-    async fn now_impl(self, stop: Option<Stop>) -> reqwest::Result<crate::Result<TextCompletion>> {
-        let url = self.url();
-        let request = TextCompletionRequest {
-            prompt: self.prompt,
-            max_tokens: self.max_tokens,
-            temperature: self.temperature,
-            top_k: self.top_k,
-            top_p: self.top_p,
-            stream: None,
-            stop,
-        };
+    /// Constrain the generated text to JSON conforming to the given [JSON schema]. See
+    /// [`Self::json`] for parsing the generated text, or [`Self::json_object`] to derive the schema
+    /// from the parsed type.
+    ///
+    /// At most one structured output constraint may be active: setting both this and
+    /// [`Self::grammar`] is a validation error.
+    ///
+    /// [JSON schema]: https://json-schema.org
+    pub fn schema(mut self, schema: serde_json::Value) -> Self {
+        self.schema = Some(schema);
+        self
+    }
+
+    /// Constrain the generated text to the given grammar, in the BNF-like syntax described in the
+    /// [API documentation].
+    ///
+    /// At most one structured output constraint may be active: setting both this and
+    /// [`Self::schema`] is a validation error.
+    ///
+    /// [API documentation]: https://textsynth.com/documentation.html
+    pub fn grammar(mut self, grammar: impl Into<String>) -> Self {
+        self.grammar = Some(grammar.into());
+        self
+    }
+
+    /// Add the given bias to the logits of the token with the given id, making it more likely to be
+    /// generated if positive and less likely if negative. See [`Self::bias_str`] to bias tokens by
+    /// text instead.
+    pub fn logit_bias(mut self, token: TokenId, bias: f64) -> Self {
+        self.logit_bias
+            .get_or_insert_with(HashMap::new)
+            .insert(token, bias);
+        self
+    }
+
+    /// Add the given bias to the logits of the End-Of-Text token of the engine, encouraging the
+    /// model to end the text sooner if positive, which keeps completions short without a hard
+    /// stop sequence. See [`Self::logit_bias`] for more information.
+    ///
+    /// This requires knowing the id of the End-Of-Text token of the engine (see
+    /// [`EngineDefinition::eot_token`]) when the request is sent, so it follows
+    /// [`Self::on_engine`]. If it isn't known, such as for custom engines, the request fails
+    /// validation with [`ValidationError::UnknownEndToken`], in which case use
+    /// [`Self::logit_bias`] with the id of the token instead.
+    pub fn end_token_bias(mut self, bias: f64) -> Self {
+        self.end_token_bias = Some(bias);
+        self
+    }
+
+    /// Add the given bias to the logits of all the tokens of the given text, which is useful to
+    /// steer the generation toward or away from words. See [`Self::logit_bias`] for more
+    /// information.
+    ///
+    /// This requires a network call to tokenize the text, unless the text was recently tokenized
+    /// with the same engine, since each [`TextSynth`] instance, shared with its clones, caches up to
+    /// [`TOKENIZATION_CACHE_CAPACITY`](tokenize::TOKENIZATION_CACHE_CAPACITY) tokenizations.
+    pub async fn bias_str(
+        self,
+        text: &str,
+        bias: f64,
+    ) -> reqwest::Result<crate::Result<TextCompletionBuilder<'ts, 'e>>> {
+        let engine = Engine::new(self.engine.text_synth, self.definition().clone());
+        let tokens = tokenize::tokenize_cached(&engine, text).await?;
+
+        Ok(tokens.map(|tokens| {
+            tokens
+                .into_iter()
+                .fold(self, |builder, token| builder.logit_bias(token, bias))
+        }))
+    }
+
+    /// Set whether the prompt is included at the start of the returned text. Combined with
+    /// [`Self::logprobs`], this gives the log probabilities of the tokens of the prompt, to score
+    /// arbitrary text (see [`TextCompletion::perplexity`]).
+    ///
+    /// Not every server supports this.
+    pub fn echo(mut self, echo: bool) -> Self {
+        self.echo = Some(echo);
+        self
+    }
+
+    /// Set whether the log probability of each token is returned, see
+    /// [`TextCompletion::token_logprobs`].
+    ///
+    /// Not every server supports this.
+    pub fn logprobs(mut self, logprobs: bool) -> Self {
+        self.logprobs = Some(logprobs);
+        self
+    }
+
+    /// Set how many of the most likely alternative tokens are returned at each position, see
+    /// [`TextCompletion::top_logprobs`]. Combine with [`Self::logprobs`].
+    ///
+    /// Not every server supports this.
+    pub fn logprobs_top_n(mut self, logprobs_top_n: usize) -> Self {
+        self.logprobs_top_n = Some(logprobs_top_n);
+        self
+    }
+
+    /// Return an error from [`Self::now`] and [`Self::now_with_raw`] if the API reports that the
+    /// prompt was [truncated](TextCompletion::truncated_prompt) to fit in the context of the engine,
+    /// instead of a text completion generated from only the end of the prompt. The error is a
+    /// `400 Bad Request` [`crate::Error`]. Streams aren't affected.
+    pub fn error_on_truncation(mut self) -> Self {
+        self.error_on_truncation = true;
+        self
+    }
+
+    /// Set how the records of streamed responses are delimited. Defaults to
+    /// [`StreamFraming::JsonLines`], which is what the public API uses.
+    pub fn framing(mut self, framing: StreamFraming) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// Transform the generated text with the given function, for example to trim it or to strip a
+    /// known prefix. When called multiple times, the functions are applied in the order they were
+    /// added.
+    ///
+    /// When streaming, the function is applied to the text of each text completion separately, so
+    /// it shouldn't assume it sees the whole generated text. It's applied after stop sequences were
+    /// handled by the API (see [`Self::now_until`]), but before client side stopping conditions
+    /// (see [`Self::stream_until`]), which therefore see the transformed text.
+    pub fn map_text(mut self, map_text: impl Fn(String) -> String + Send + Sync + 'static) -> Self {
+        self.map_text = Some(match self.map_text.take() {
+            Some(previous) => Arc::new(move |text| map_text(previous(text))),
+            None => Arc::new(map_text),
+        });
+        self
+    }
 
+    fn url(&self) -> String {
+        let engine_id = self.definition().id();
         self.engine
             .text_synth
-            .post(url)
-            .json(&request)
-            .send()
-            .await?
-            .json::<crate::UntaggedResult<_>>()
-            .await
-            .map(Into::into)
+            .url(&format!("/v1/engines/{engine_id}/completions"))
+    }
+
+    fn into_request(self, stream: Option<bool>) -> TextCompletionRequest {
+        let end_token_bias = self.definition().eot_token().zip(self.end_token_bias);
+        let mut logit_bias = self.logit_bias;
+
+        if let Some((eot_token, bias)) = end_token_bias {
+            logit_bias
+                .get_or_insert_with(HashMap::new)
+                .insert(eot_token, bias);
+        }
+
+        let prompt = match self.prompt_tokens {
+            Some(prompt_tokens) => Prompt::Tokens(prompt_tokens),
+            None => Prompt::Text(self.prompt),
+        };
+
+        TextCompletionRequest {
+            prompt,
+            max_tokens: self.max_tokens,
+            temperature: self.temperature,
+            top_k: self.top_k,
+            top_p: self.top_p,
+            stream,
+            stop: self.stop,
+            schema: self.schema,
+            grammar: self.grammar,
+            logit_bias: logit_bias.map(|logit_bias| logit_bias.into_iter().collect()),
+            echo: self.echo,
+            logprobs: self.logprobs,
+            logprobs_top_n: self.logprobs_top_n,
+        }
+    }
+
+    /// Run the client side validations done before sending a request, returning every invalid
+    /// parameter at once rather than only the first one. Useful for forms and configuration
+    /// validators which report all problems together.
+    ///
+    /// The number of stop sequences is already limited by [`Stop`] itself, so it can't be invalid
+    /// here.
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Vec::new();
+        let engine_max_tokens = self.definition().max_output_tokens();
+
+        if let Some(max_tokens) = self.max_tokens {
+            if max_tokens.inner() > engine_max_tokens {
+                errors.push(
+                    OutOfRangeError::new(
+                        "max_tokens",
+                        "at most the maximum number of output tokens of the engine",
+                        max_tokens.inner(),
+                    )
+                    .into(),
+                );
+            }
+        }
+
+        if let Some(temperature) = self.temperature {
+            if !temperature.is_finite() || temperature < 0.0 {
+                errors.push(
+                    OutOfRangeError::new(
+                        "temperature",
+                        "a finite non-negative number",
+                        temperature,
+                    )
+                    .into(),
+                );
+            }
+        }
+
+        if self
+            .stop
+            .as_ref()
+            .is_some_and(|stop| stop.sequences().iter().any(String::is_empty))
+        {
+            errors.push(ValidationError::EmptyStopSequence);
+        }
+
+        if self.grammar.is_some() && self.schema.is_some() {
+            errors.push(ValidationError::GrammarAndSchema);
+        }
+
+        if self.prompt_tokens.as_ref().is_some_and(Vec::is_empty) {
+            errors.push(ValidationError::EmptyPromptTokens);
+        }
+
+        if !self.definition().capabilities().completion {
+            errors.push(ValidationError::Unsupported("completions"));
+        }
+
+        if self.end_token_bias.is_some() && self.definition().eot_token().is_none() {
+            errors.push(ValidationError::UnknownEndToken);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Run the client side validations done before sending a request, without sending it, and
+    /// return the body which would be sent. Useful to validate prompt templates and parameter
+    /// presets, for example in CI.
+    ///
+    /// The same validations as [`Self::validate`] are done by [`Self::now`] and friends, which
+    /// return all validation errors as a single `400 Bad Request` [`crate::Error`] without sending
+    /// the request.
+    pub fn dry_run(self) -> Result<serde_json::Value, Vec<ValidationError>> {
+        self.validate()?;
+
+        let request = self.into_request(None);
+        Ok(serde_json::to_value(request).expect("failed to serialize text completion request"))
     }
 
     /// Generate a text completion now.
     pub async fn now(self) -> reqwest::Result<crate::Result<TextCompletion>> {
-        self.now_impl(None).await
+        if let Err(errors) = self.validate() {
+            return Ok(Err(errors.into()));
+        }
+
+        let engine = self.engine;
+        let url = self.url();
+        let map_text = self.map_text.clone();
+        let error_on_truncation = self.error_on_truncation;
+        let request = self.into_request(None);
+
+        let text_synth = engine.text_synth;
+
+        text_synth
+            .abortable(async {
+                text_synth
+                    .send(text_synth.post(url).json(&request))
+                    .await?
+                    .pipe(utils::json::<TextCompletion>)
+                    .await
+            })
+            .await
+            .map(|result| {
+                result
+                    .and_then(|text_completion| {
+                        text_completion.check_truncation(error_on_truncation)
+                    })
+                    .map(|text_completion| text_completion.map_text(map_text.as_ref()))
+            })
     }
 
     /// Generate a text completion now, stopping when the specified list of strings are found.
+    /// Shorthand for [`Self::stop`] followed by [`Self::now`].
     pub async fn now_until(self, stop: Stop) -> reqwest::Result<crate::Result<TextCompletion>> {
-        self.now_impl(Some(stop)).await
+        self.stop(stop).now().await
+    }
+
+    /// Same as [`Self::now`], but also returns the raw JSON of the response, to access fields this
+    /// crate doesn't expose yet. The response is only parsed once.
+    ///
+    /// [`Self::map_text`] only applies to the typed text completion, not to the raw JSON.
+    pub async fn now_with_raw(
+        self,
+    ) -> reqwest::Result<crate::Result<(TextCompletion, serde_json::Value)>> {
+        if let Err(errors) = self.validate() {
+            return Ok(Err(errors.into()));
+        }
+
+        let engine = self.engine;
+        let url = self.url();
+        let map_text = self.map_text.clone();
+        let error_on_truncation = self.error_on_truncation;
+        let request = self.into_request(None);
+
+        let text_synth = engine.text_synth;
+
+        text_synth
+            .abortable(async {
+                text_synth
+                    .send(text_synth.post(url).json(&request))
+                    .await?
+                    .pipe(utils::json_with_raw::<TextCompletion>)
+                    .await
+            })
+            .await
+            .map(|result| {
+                result
+                    .and_then(|(text_completion, raw)| {
+                        Ok((text_completion.check_truncation(error_on_truncation)?, raw))
+                    })
+                    .map(|(text_completion, raw)| {
+                        (text_completion.map_text(map_text.as_ref()), raw)
+                    })
+            })
+    }
+
+    /// Same as [`Self::now`], but if the engine is a [custom](EngineDefinition::Custom) one and the
+    /// prompt was truncated, the engine listing is queried to learn the real maximum amount of
+    /// tokens of the engine. If it differs from the local definition, the definition reported by the
+    /// server is returned along with the text completion, so that the local definition can be
+    /// fixed.
+    ///
+    /// This makes an extra request to [`TextSynth::engines`](crate::core::TextSynth::engines)
+    /// whenever the prompt of a custom engine is truncated. Looking up the engine is best effort:
+    /// if the listing fails or doesn't include the engine, no definition is returned rather than
+    /// losing the text completion.
+    pub async fn now_correcting_definition(
+        self,
+    ) -> reqwest::Result<crate::Result<(TextCompletion, Option<CustomEngineDefinition>)>> {
+        let text_synth = self.engine.text_synth;
+        let definition = self.definition().clone();
+
+        let text_completion = match self.now().await? {
+            Ok(text_completion) => text_completion,
+            Err(error) => return Ok(Err(error)),
+        };
+
+        let local = match &definition {
+            EngineDefinition::Custom(local) if text_completion.truncated_prompt() => local,
+            _ => return Ok(Ok((text_completion, None))),
+        };
+
+        let corrected = match text_synth.engines().await {
+            Ok(Ok(engines)) => engines
+                .into_iter()
+                .find(|engine| engine.id == local.id && engine.max_tokens != local.max_tokens),
+            _ => None,
+        };
+
+        Ok(Ok((text_completion, corrected)))
+    }
+
+    /// Generate a text completion now, and while it ends because [`Self::max_tokens`] was reached
+    /// rather than because the text [reached its end](TextCompletion::reached_end), continue it
+    /// with [`TextCompletion::continue_with`], up to `max_extensions` times. The text completions
+    /// are concatenated into one, so this keeps going until the text naturally stops.
+    ///
+    /// Each extension is a separate request whose prompt includes the text generated so far, so
+    /// its cost grows with the length of the text. To avoid runaway costs, no extension is made
+    /// once `max_total_tokens` tokens were generated in total, and [`Self::max_tokens`] is lowered
+    /// for the last request if needed. Generated tokens are counted from
+    /// [`TextCompletion::completion_tokens`], or assumed to be [`Self::max_tokens`] if the API
+    /// doesn't report them. At least one request is always made.
+    ///
+    /// [`Self::echo`] is turned off, so that only generated text is concatenated, and
+    /// [`Self::map_text`] applies to the concatenated text. The token counts of the result cover
+    /// the original prompt and the whole generation, and its log probabilities are concatenated if
+    /// every request returned them.
+    pub async fn now_until_end(
+        mut self,
+        max_extensions: usize,
+        max_total_tokens: usize,
+    ) -> reqwest::Result<crate::Result<TextCompletion>> {
+        let map_text = self.map_text.take();
+        self.echo = None;
+
+        let default_max_tokens = self
+            .max_tokens
+            .as_ref()
+            .map_or_else(|| self.definition().max_output_tokens(), MaxTokens::inner);
+        let mut generated_tokens = 0;
+        let mut combined: Option<TextCompletion> = None;
+
+        for extension in 0..=max_extensions {
+            let remaining = max_total_tokens.saturating_sub(generated_tokens);
+
+            if extension > 0 && remaining == 0 {
+                break;
+            }
+
+            let requested_tokens = default_max_tokens.min(remaining.max(1));
+            let mut builder = self.clone();
+
+            if requested_tokens < default_max_tokens {
+                builder.max_tokens = Some(MaxTokens(requested_tokens));
+            }
+
+            let text_completion = match builder.now().await? {
+                Ok(text_completion) => text_completion,
+                Err(error) => return Ok(Err(error)),
+            };
+
+            generated_tokens += text_completion
+                .completion_tokens()
+                .unwrap_or(requested_tokens);
+
+            if self.prompt_tokens.is_some() && !text_completion.reached_end() {
+                let engine = Engine::new(self.engine.text_synth, self.definition().clone());
+                let tokens =
+                    match tokenize::tokenize_cached(&engine, text_completion.text()).await? {
+                        Ok(tokens) => tokens,
+                        Err(error) => return Ok(Err(error)),
+                    };
+
+                self.prompt_tokens
+                    .get_or_insert_with(Vec::new)
+                    .extend(tokens);
+            }
+
+            self = text_completion.continue_with(self);
+            let reached_end = text_completion.reached_end();
+
+            match &mut combined {
+                Some(combined) => combined.extend(text_completion),
+                None => combined = Some(text_completion),
+            }
+
+            if reached_end {
+                break;
+            }
+        }
+
+        let text_completion = combined.expect("at least one request is made");
+        Ok(Ok(text_completion.map_text(map_text.as_ref())))
+    }
+
+    /// Generate a text completion now and parse the generated text as JSON into `T`.
+    ///
+    /// Pair this with [`Self::schema`] so that the model is constrained to generate JSON matching
+    /// `T`, otherwise parsing will likely fail.
+    pub async fn json<T: DeserializeOwned>(
+        self,
+    ) -> reqwest::Result<crate::Result<serde_json::Result<T>>> {
+        self.now().await.map(|result| {
+            result.map(|text_completion| serde_json::from_str(text_completion.text()))
+        })
+    }
+
+    /// Generate a text completion now, constrained to the JSON schema of `T` as derived by
+    /// [`schemars`], and parse the generated text into `T`. This replaces any schema set by
+    /// [`Self::schema`].
+    #[cfg(feature = "schemars")]
+    pub async fn json_object<T: schemars::JsonSchema + DeserializeOwned>(
+        self,
+    ) -> reqwest::Result<crate::Result<serde_json::Result<T>>> {
+        let schema = serde_json::to_value(schemars::schema_for!(T)).expect("invalid json schema");
+        self.schema(schema).json().await
     }
 
     /// Create a text completion stream.
     pub async fn stream(self) -> reqwest::Result<impl TextCompletionStream> {
+        self.stream_sent_at().await.map(|(stream, _)| stream)
+    }
+
+    /// Same as [`Self::stream`], but also returns when the request was sent, if it was.
+    async fn stream_sent_at(self) -> reqwest::Result<(impl TextCompletionStream, Option<Instant>)> {
+        if let Err(errors) = self.validate() {
+            let invalid = future::ready(Ok(Ok(Err(errors.into()))));
+            return Ok((Either::Left(futures::stream::once(invalid)), None));
+        }
+
+        let text_synth = self.engine.text_synth;
         let url = self.url();
-        let request = TextCompletionRequest {
-            prompt: self.prompt,
-            max_tokens: self.max_tokens,
-            temperature: self.temperature,
-            top_k: self.top_k,
-            top_p: self.top_p,
-            stream: Some(true),
-            stop: None,
-        };
+        let map_text = self.map_text.clone();
+        let framing = self.framing;
+        let request = self.into_request(Some(true));
 
-        self.engine
-            .text_synth
-            .post(url)
-            .json(&request)
-            .send()
-            .await?
-            .bytes_stream()
-            .map(|bytes| {
-                bytes
-                    .map(|bytes| bytes.slice(..bytes.len() - 2))
-                    .map(|bytes| serde_json::from_slice::<crate::UntaggedResult<_>>(&bytes))
-                    .map(|result| result.map(Into::into))
-            })
-            .pipe(Ok)
+        send_stream(text_synth, url, request, map_text, framing)
+            .await
+            .map(|(stream, sent_at)| (Either::Right(stream), sent_at))
+    }
+
+    /// Stream the text completion from a spawned task, sending each text completion to the
+    /// returned channel, so that it can be consumed independently, for example by an actor.
+    ///
+    /// The channel holds up to [`STREAM_CHANNEL_CAPACITY`] text completions, after which the task
+    /// waits for them to be received. The task ends once the stream ends, at the first error,
+    /// which its handle resolves to, or once the receiver is dropped, closing the connection.
+    ///
+    /// # Panics
+    /// Panics if called outside of a Tokio runtime.
+    #[must_use = "dropping the receiver stops the stream"]
+    pub fn stream_to_channel(
+        self,
+    ) -> (
+        mpsc::Receiver<TextCompletion>,
+        JoinHandle<Result<(), ApiError>>,
+    ) {
+        let (sender, receiver) = mpsc::channel(STREAM_CHANNEL_CAPACITY);
+        let text_synth = self.engine.text_synth.clone();
+        let prepared = self.validate().map(|()| {
+            let url = self.url();
+            let map_text = self.map_text.clone();
+            let framing = self.framing;
+            (url, map_text, framing, self.into_request(Some(true)))
+        });
+
+        let handle = tokio::spawn(async move {
+            let (url, map_text, framing, request) =
+                prepared.map_err(|errors| ApiError::Api(errors.into()))?;
+            let (stream, _) = send_stream(&text_synth, url, request, map_text, framing).await?;
+            let mut stream = Box::pin(stream);
+
+            while let Some(item) = stream.next().await {
+                if sender.send(ApiError::flatten_stream(item)?).await.is_err() {
+                    break;
+                }
+            }
+
+            Ok(())
+        });
+
+        (receiver, handle)
+    }
+
+    /// Same as [`Self::stream`], but if the connection fails mid-stream, up to `max_reconnects`
+    /// times, a new request is made to continue the generation instead of yielding the network
+    /// error. This keeps long generations going over unreliable connections, on a best effort
+    /// basis.
+    ///
+    /// This is opt-in because it changes the semantics of the generation: the new request uses the
+    /// prompt followed by the text generated so far as its prompt, so the model re-conditions on
+    /// its partial output instead of continuing its original sampling. [`Self::max_tokens`] applies
+    /// to each request separately, and the prompt may be truncated if it no longer fits in the
+    /// context. Errors other than network errors, and network errors once the reconnections are
+    /// used up, are yielded as usual.
+    pub async fn stream_resilient(
+        mut self,
+        max_reconnects: usize,
+    ) -> reqwest::Result<impl TextCompletionStream + 'e> {
+        // the text generated so far is accumulated before it is mapped
+        let map_text = self.map_text.take();
+        let stream = self.clone().stream().await?;
+        let state = (Some(stream), self, String::new(), max_reconnects);
+
+        futures::stream::unfold(
+            state,
+            move |(mut stream, builder, mut generated, mut reconnects)| {
+                let map_text = map_text.clone();
+
+                async move {
+                    loop {
+                        let item = stream.as_mut()?.next().await;
+
+                        match item {
+                            Some(Ok(Ok(Ok(text_completion)))) => {
+                                generated.push_str(text_completion.text());
+                                let text_completion = text_completion.map_text(map_text.as_ref());
+                                let state = (stream, builder, generated, reconnects);
+                                return Some((Ok(Ok(Ok(text_completion))), state));
+                            }
+                            Some(Err(error)) if reconnects > 0 => {
+                                reconnects -= 1;
+                                builder.engine.text_synth.notify_retry(
+                                    max_reconnects - reconnects,
+                                    Duration::ZERO,
+                                    &error,
+                                );
+
+                                let mut continuation = builder.clone();
+                                continuation.prompt.push_str(&generated);
+
+                                match continuation.stream().await {
+                                    Ok(continued) => stream = Some(continued),
+                                    Err(_) => {
+                                        // report the original error rather than the reconnection's
+                                        let state = (None, builder, generated, reconnects);
+                                        return Some((Err(error), state));
+                                    }
+                                }
+                            }
+                            Some(item) => {
+                                return Some((item, (stream, builder, generated, reconnects)))
+                            }
+                            None => return None,
+                        }
+                    }
+                }
+            },
+        )
+        // boxed so that the stream only captures `'e`, which `'ts` outlives
+        .boxed()
+        .pipe(Ok)
+    }
+
+    /// Stream the text completion, writing each text completion to `writer` as a line of JSON
+    /// ([NDJSON]), which is useful to record generation sessions for later replay or analysis.
+    ///
+    /// Returns the final text completion, or [`None`] if the stream was empty. Errors writing to
+    /// `writer` are returned as [`ApiError::Io`].
+    ///
+    /// [NDJSON]: http://ndjson.org
+    pub async fn stream_to_ndjson<W: Write>(
+        self,
+        mut writer: W,
+    ) -> Result<Option<TextCompletion>, ApiError> {
+        let mut stream = Box::pin(self.stream().await?);
+        let mut last_text_completion = None;
+
+        while let Some(item) = stream.next().await {
+            let text_completion = ApiError::flatten_stream(item)?;
+            let mut line = serde_json::to_vec(&text_completion)?;
+            line.push(b'\n');
+            writer.write_all(&line)?;
+            last_text_completion = Some(text_completion);
+        }
+
+        writer.flush()?;
+
+        Ok(last_text_completion)
+    }
+
+    /// Create a text completion stream which stops once the text generated so far satisfies the
+    /// given predicate, closing the connection.
+    ///
+    /// Unlike [`Self::now_until`], the stopping condition is checked client side, so it can be
+    /// arbitrary (balanced brackets, a regex match, ...). The text completion which satisfied the
+    /// predicate is still yielded, and since it's checked client side, the generated text may
+    /// include the matched text.
+    pub async fn stream_until(
+        self,
+        predicate: impl Fn(&str) -> bool,
+    ) -> reqwest::Result<impl TextCompletionStream> {
+        self.stream()
+            .await
+            .map(|stream| stream::until(stream, predicate))
+    }
+
+    /// Create a text completion stream which stops once the text generated so far contains one of
+    /// the given stop sequences, matched client side. See [`ClientStop`] for how this differs from
+    /// [`Self::now_until`], whose stop sequences are matched exactly by the API.
+    pub async fn stream_until_stop(
+        self,
+        stop: ClientStop,
+    ) -> reqwest::Result<impl TextCompletionStream> {
+        let checked = Cell::new(0);
+
+        self.stream_until(move |text| {
+            let matches = stop.matches_after(text, checked.get());
+            checked.set(text.len());
+            matches
+        })
+        .await
+    }
+
+    /// Create `n` text completion streams which each yield all the text completions of a single
+    /// generation. See [`stream::tee`] for how the streams share the response, and why they all
+    /// need to be polled or dropped.
+    pub async fn stream_tee(self, n: usize) -> reqwest::Result<Vec<impl TextCompletionStream>> {
+        Ok(stream::tee(self.stream().await?, n))
+    }
+
+    /// Create a text completion stream which pairs each text completion with the approximate
+    /// progress of the generation, from `0.0` to `1.0`, for progress bars.
+    ///
+    /// The progress is the number of text completions received so far, each of which is about a
+    /// token, divided by [`Self::max_tokens`], or by the maximum number of output tokens of the
+    /// engine if it isn't set. It is capped at `1.0`, and the final text completion is always at
+    /// `1.0`. This is approximate: the generation may stop early, for example at a stop sequence,
+    /// in which case the progress jumps to `1.0`.
+    pub async fn stream_with_progress(
+        self,
+    ) -> reqwest::Result<impl Stream<Item = ProgressStreamResult>> {
+        let max_tokens = self
+            .max_tokens
+            .as_ref()
+            .map_or_else(|| self.definition().max_output_tokens(), MaxTokens::inner);
+
+        Ok(stream::with_progress(self.stream().await?, max_tokens))
+    }
+
+    /// Create a stream of the complete sentences of the generated text rather than of the chunks it
+    /// was generated in. See [`stream::sentences`] for how sentences are split, and its limits.
+    pub async fn stream_sentences(
+        self,
+    ) -> reqwest::Result<impl Stream<Item = SentenceStreamResult>> {
+        Ok(stream::sentences(self.stream().await?))
+    }
+
+    /// Stream the text completion into a [`StreamTranscript`], recording when each text completion
+    /// was received relative to when the request was sent. Returns the first error of the stream.
+    pub async fn stream_to_transcript(self) -> Result<StreamTranscript, ApiError> {
+        let transcript = StreamTranscript::starting_at(Instant::now());
+        let stream = self.stream().await?;
+
+        transcript.collect_from(stream).await
     }
+
+    /// Create a text completion stream which, after the last text completion, also yields
+    /// [`StreamStats`](stream::StreamStats) measuring the throughput of the generation.
+    pub async fn stream_with_stats(self) -> reqwest::Result<impl Stream<Item = StatsStreamItem>> {
+        let (stream, sent_at) = self.stream_sent_at().await?;
+        let headers_at = Instant::now();
+        let start = sent_at.unwrap_or(headers_at);
+
+        Ok(stream::with_stats(stream, start, headers_at - start))
+    }
+}
+
+/// Send a streamed text completion request, decoding the response into a text completion stream.
+/// Also returns when the request was sent, unless it was cancelled or rejected before.
+async fn send_stream(
+    text_synth: &TextSynth,
+    url: String,
+    request: TextCompletionRequest,
+    map_text: Option<TextMapper>,
+    framing: StreamFraming,
+) -> reqwest::Result<(impl TextCompletionStream, Option<Instant>)> {
+    let mut registration = text_synth.abort_registration();
+    let response = text_synth.send(text_synth.post(url).json(&request));
+    let response = match abort::run(&mut registration, response).await {
+        Some(response) => response?,
+        None => {
+            let cancelled = future::ready(Cancelled::cancelled());
+            return Ok((Either::Left(futures::stream::once(cancelled)), None));
+        }
+    };
+    let status_code = response.status();
+    let sent_at = response
+        .extensions()
+        .get::<SentAt>()
+        .map(|sent_at| sent_at.0);
+
+    framing::decode(response.bytes_stream(), framing)
+        .map(move |payload| {
+            payload
+                .map(|payload| serde_json::from_slice::<crate::UntaggedResult<_>>(&payload))
+                .map(|result| {
+                    result.map(|result| {
+                        Result::from(result)
+                            .map(|text_completion: TextCompletion| {
+                                text_completion.map_text(map_text.as_ref())
+                            })
+                            .map_err(|error| error.or_status(status_code))
+                    })
+                })
+        })
+        .pipe(|stream| {
+            let stream = abort::abortable_stream(registration, stream);
+            Ok((Either::Right(stream), sent_at))
+        })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::abort::AbortRegistry;
+    use crate::engine::definition::Capabilities;
     use crate::prelude::CustomEngineDefinition;
     use crate::test_utils;
+    use crate::test_utils::server::{self, Response};
     use once_cell::sync::Lazy;
     use test_utils::text_synth;
 
     static YOU_SHOULD_CLONE_THIS_BUILDER: Lazy<TextCompletionBuilder> =
-        Lazy::new(|| text_synth::engine().text_completion("fn main() {".into()));
+        Lazy::new(|| text_synth::engine().text_completion("fn main() {"));
     static BUILDER: Lazy<TextCompletionBuilder> = Lazy::new(|| {
         YOU_SHOULD_CLONE_THIS_BUILDER
             .clone()
@@ -282,12 +1744,20 @@ mod tests {
     });
     static ENGINE_DEFINITION: EngineDefinition =
         EngineDefinition::Custom(CustomEngineDefinition::r#static("custom", 1024));
+    static UNLIMITED_ENGINE_DEFINITION: EngineDefinition =
+        EngineDefinition::Custom(CustomEngineDefinition::unlimited());
 
     #[test]
     fn test_max_tokens_new() {
         assert!(MaxTokens::new(1, &ENGINE_DEFINITION).is_some());
         assert!(MaxTokens::new(1024, &ENGINE_DEFINITION).is_some());
         assert!(MaxTokens::new(1025, &ENGINE_DEFINITION).is_none());
+
+        let capped = EngineDefinition::Custom(
+            CustomEngineDefinition::r#static("capped", 1024).with_max_output_tokens(256),
+        );
+        assert!(MaxTokens::new(256, &capped).is_some());
+        assert!(MaxTokens::new(257, &capped).is_none());
     }
 
     #[test]
@@ -296,6 +1766,79 @@ mod tests {
         assert_eq!(max_tokens.inner(), 1);
     }
 
+    #[test]
+    fn test_top_k_try_from() {
+        assert_eq!(TopK::try_from(1).unwrap().inner(), 1);
+        assert_eq!(TopK::try_from(1000).unwrap().inner(), 1000);
+        assert_eq!(
+            TopK::try_from(0).unwrap_err().to_string(),
+            "`top_k` must be between 1 and 1000 inclusive, got 0"
+        );
+        assert!(TopK::try_from(1001).is_err());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_top_k_compatibility() {
+        assert_eq!(TopK::new_saturating(0), TopK::MIN);
+        assert_eq!(TopK::new_saturating(2000), TopK::MAX);
+        assert_eq!(TopK::MAX.get(), 1000);
+
+        let top_k: TopK = serde_json::from_str("40").unwrap();
+        assert_eq!(top_k.inner(), 40);
+        assert!(serde_json::from_str::<TopK>("0").is_err());
+
+        let bounded = bounded_integer::BoundedU16::<1, 1000>::new(40).unwrap();
+        assert_eq!(TopK::from(bounded), top_k);
+        assert_eq!(bounded_integer::BoundedU16::from(top_k), bounded);
+    }
+
+    #[test]
+    fn test_stop_try_new() {
+        let stop = Stop::try_new(["a", "b"]).unwrap();
+        assert_eq!(stop.sequences(), ["a", "b"]);
+        assert_eq!(
+            Stop::try_new(["a", "b", "c", "d", "e", "f", "g"])
+                .unwrap_err()
+                .to_string(),
+            "`stop` must be at most 5 sequences, got 7 sequences"
+        );
+        assert!(Stop::try_from(vec![String::new(); 6]).is_err());
+    }
+
+    #[test]
+    fn test_stop_push() {
+        let mut stop = Stop::new();
+
+        for sequence in ["a", "b", "c", "d", "e"] {
+            stop.push(sequence).unwrap();
+        }
+
+        assert!(stop.push("f").is_err());
+        assert_eq!(stop.len(), Stop::MAX_SEQUENCES);
+    }
+
+    #[test]
+    fn test_stop_deserialize() {
+        let stop: Stop = serde_json::from_str(r#"["a", "b"]"#).unwrap();
+        assert_eq!(stop.sequences(), ["a", "b"]);
+        assert!(serde_json::from_str::<Stop>(r#"["a", "b", "c", "d", "e", "f"]"#).is_err());
+
+        let sequences = ArrayVec::<String, 5>::from_iter(["a".to_owned()]);
+        assert_eq!(Stop::from(sequences.clone()).sequences(), ["a"]);
+        assert_eq!(ArrayVec::from(Stop::from(sequences.clone())), sequences);
+    }
+
+    #[test]
+    fn test_top_p_try_from() {
+        assert_eq!(TopP::try_from(0.5).unwrap().inner(), 0.5);
+        assert_eq!(
+            TopP::try_from(1.5).unwrap_err().to_string(),
+            "`top_p` must be between 0.0 and 1.0 inclusive, got 1.5"
+        );
+        assert!(TopP::try_from(-0.1).is_err());
+    }
+
     #[test]
     fn test_text_completion_builder_new() {
         let _ = TextCompletionBuilder::new(text_synth::engine(), "fn main() {".into());
@@ -307,6 +1850,11 @@ mod tests {
         let _ = YOU_SHOULD_CLONE_THIS_BUILDER.clone().max_tokens(max_tokens);
     }
 
+    #[test]
+    fn test_max_tokens_new_unlimited() {
+        assert!(MaxTokens::new(usize::MAX, &UNLIMITED_ENGINE_DEFINITION).is_some());
+    }
+
     #[test]
     fn test_text_completion_temperature() {
         let _ = YOU_SHOULD_CLONE_THIS_BUILDER.clone().temperature(0.5);
@@ -324,39 +1872,1345 @@ mod tests {
         let _ = YOU_SHOULD_CLONE_THIS_BUILDER.clone().top_p(top_p);
     }
 
+    #[test]
+    fn test_text_completion_schema() {
+        let schema = serde_json::json!({ "type": "object" });
+        let builder = YOU_SHOULD_CLONE_THIS_BUILDER.clone().schema(schema.clone());
+        assert_eq!(builder.schema, Some(schema));
+    }
+
     #[tokio::test]
-    async fn test_text_completion_now_and_friends() {
-        let text_completion = BUILDER
-            .clone()
-            .now()
+    async fn test_text_completion_json() {
+        let base_url =
+            server::serve(|_| Response::json(200, r#"{"text": "[1, 2, 3]", "reached_end": true}"#))
+                .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let numbers = engine
+            .text_completion("The first three numbers are")
+            .schema(serde_json::json!({ "type": "array" }))
+            .json::<Vec<u32>>()
             .await
             .expect("network error")
-            .expect("api error");
-        assert!(
-            text_completion.total_tokens().is_some(),
-            "expected total tokens of immediate text completion to exist since it is not streamed",
-        );
-        let _ = text_completion.text();
-        let _ = text_completion.truncated_prompt();
-        let _ = text_completion.reached_end();
+            .expect("api error")
+            .expect("invalid json");
+        assert_eq!(numbers, [1, 2, 3]);
     }
 
+    #[cfg(feature = "schemars")]
     #[tokio::test]
-    async fn test_text_completion_truncated_prompt_if_prompt_too_long() {
-        let mut builder = BUILDER.clone();
-
-        // v
-        builder.prompt = format!(
-            "fn main() {{\n{}}}",
-            "println('Hello World')\n".repeat(2048)
-        );
+    async fn test_text_completion_json_object() {
+        #[derive(Debug, PartialEq, Deserialize, schemars::JsonSchema)]
+        struct Answer {
+            answer: bool,
+        }
 
-        let text_completion = builder
+        let base_url = server::serve(|request| {
+            let schema = &request.json()["schema"];
+            assert_eq!(schema["properties"]["answer"]["type"], "boolean");
+            assert_eq!(schema["required"], serde_json::json!(["answer"]));
+            Response::json(
+                200,
+                r#"{"text": "{\"answer\": true}", "reached_end": true}"#,
+            )
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let answer = engine
+            .text_completion("Is the sky blue?")
+            .json_object::<Answer>()
+            .await
+            .expect("network error")
+            .expect("api error")
+            .expect("invalid json");
+        assert_eq!(answer, Answer { answer: true });
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_stream_to_ndjson() {
+        let base_url = server::serve(|request| {
+            assert_eq!(request.json()["stream"], true);
+            Response::chunked(200, server::completion_chunks(&["a", "b"]))
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let mut ndjson = Vec::new();
+        let last_text_completion = engine
+            .text_completion("fn main() {")
+            .stream_to_ndjson(&mut ndjson)
+            .await
+            .expect("failed to stream text completions")
+            .expect("expected at least one text completion");
+        assert_eq!(last_text_completion.text(), "b");
+
+        let ndjson = String::from_utf8(ndjson).unwrap();
+        let lines = ndjson.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(ndjson.ends_with('\n'));
+
+        for (line, text) in lines.into_iter().zip(["a", "b"]) {
+            let text_completion: TextCompletion = serde_json::from_str(line).unwrap();
+            assert_eq!(text_completion.text(), text);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_stream_to_ndjson_io_error() {
+        struct FailingWriter;
+
+        impl Write for FailingWriter {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let base_url =
+            server::serve(|_| Response::chunked(200, server::completion_chunks(&["a"]))).await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let error = engine
+            .text_completion("fn main() {")
+            .stream_to_ndjson(FailingWriter)
+            .await
+            .expect_err("expected writing to fail");
+        assert!(
+            matches!(error, ApiError::Io(error) if error.kind() == std::io::ErrorKind::BrokenPipe)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_grammar_and_schema() {
+        let grammar = r#"root ::= "yes" | "no""#;
+        let request = BUILDER.clone().grammar(grammar).dry_run().unwrap();
+        assert_eq!(request["grammar"], grammar);
+
+        let base_url = server::serve(|_| panic!("invalid requests shouldn't be sent")).await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let builder = engine
+            .text_completion("Is the sky blue?")
+            .grammar(grammar)
+            .schema(serde_json::json!({ "type": "boolean" }));
+        assert_eq!(
+            builder.validate(),
+            Err(vec![ValidationError::GrammarAndSchema])
+        );
+
+        let error = builder
+            .now()
+            .await
+            .expect("network error")
+            .expect_err("expected a validation error");
+        assert_eq!(error.status_code(), reqwest::StatusCode::BAD_REQUEST);
+        assert_eq!(
+            error.message(),
+            "`grammar` and `schema` must not both be set"
+        );
+    }
+
+    #[test]
+    fn test_client_stop_matches() {
+        let stop = ClientStop::new(["The End"]);
+        assert!(stop.matches("... The End"));
+        assert!(!stop.matches("... the end"));
+
+        let stop = ClientStop::new(["The End"]).case_insensitive();
+        assert!(stop.matches("... THE END."));
+        assert!(!stop.matches("... the\n end"));
+
+        let stop = ClientStop::new(["The End"])
+            .case_insensitive()
+            .normalize_whitespace();
+        assert!(stop.matches("... the\n  end"));
+        assert!(!ClientStop::new([""]).matches("anything"));
+
+        let stop = ClientStop::new([" end"]).normalize_whitespace();
+        assert!(stop.matches("the\n\tend"));
+        assert!(!stop.matches("legend"));
+
+        let stop = ClientStop::new(["\n\n"]).normalize_whitespace();
+        assert!(stop.matches("first\n\nsecond"));
+        assert!(!stop.matches("first second"));
+    }
+
+    #[test]
+    fn test_client_stop_matches_after() {
+        let stop = ClientStop::new(["the end"]).normalize_whitespace();
+        let text = "once upon a time... the  \n end";
+        assert!(stop.matches_after(text, text.find('\n').unwrap()));
+        assert!(stop.matches_after(text, text.len() - 1));
+        assert!(!stop.matches_after(text, text.len()));
+        assert_eq!(
+            stop.tail_start(text, text.len()),
+            text.find("the").unwrap() + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_stream_until_stop() {
+        let base_url = server::serve(|_| {
+            Response::chunked(
+                200,
+                server::completion_chunks(&["once ", "upon a ", "TIME", "!"]),
+            )
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let count = engine
+            .text_completion("fn main() {")
+            .stream_until_stop(ClientStop::new(["a time"]).case_insensitive())
+            .await
+            .expect("network error")
+            .count()
+            .await;
+        assert_eq!(count, 3);
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_stream_multi_byte_characters() {
+        let base_url = server::serve(|_| {
+            // split every byte into its own chunk, which splits every multi-byte character
+            let chunks = server::completion_chunks(&["caf\u{e9} ", "cr\u{e8}me \u{1f950}"])
+                .concat()
+                .into_iter()
+                .map(|byte| vec![byte])
+                .collect();
+            Response::chunked(200, chunks)
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let texts = engine
+            .text_completion("fn main() {")
+            .stream()
+            .await
+            .expect("network error")
+            .map(|result| result.unwrap().unwrap().unwrap().text().to_owned())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(texts, ["caf\u{e9} ", "cr\u{e8}me \u{1f950}"]);
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_text_completion_stream_compressed() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+
+        let base_url = server::serve(|request| {
+            assert!(request.header("accept-encoding").unwrap().contains("gzip"));
+
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder
+                .write_all(&server::completion_chunks(&["once ", "upon ", "a time"]).concat())
+                .unwrap();
+            let chunks = encoder
+                .finish()
+                .unwrap()
+                .chunks(16)
+                .map(<[u8]>::to_vec)
+                .collect();
+            Response::chunked(200, chunks).header("content-encoding", "gzip")
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let texts = engine
+            .text_completion("fn main() {")
+            .stream()
+            .await
+            .expect("network error")
+            .map(|result| result.unwrap().unwrap().unwrap().text().to_owned())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(texts, ["once ", "upon ", "a time"]);
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_stream_stop() {
+        let base_url = server::serve(|request| {
+            assert_eq!(request.json()["stop"], serde_json::json!(["\n"]));
+            Response::chunked(200, server::completion_chunks(&["a"]))
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let count = engine
+            .text_completion("fn main() {")
+            .stop(Stop::try_new(["\n"]).unwrap())
+            .stream()
+            .await
+            .expect("network error")
+            .count()
+            .await;
+        assert_eq!(count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_stream_resilient() {
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let base_url = server::serve({
+            let requests = Arc::clone(&requests);
+            move |request| match requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 => {
+                    assert_eq!(request.json()["prompt"], "Once");
+                    let mut chunks = server::completion_chunks(&[" upon", ""]);
+                    chunks.truncate(1);
+                    chunks.push(Vec::new());
+
+                    // the connection closes before the promised body was sent
+                    Response::chunked(200, chunks).header("content-length", "10000")
+                }
+                _ => {
+                    assert_eq!(request.json()["prompt"], "Once upon");
+                    Response::chunked(200, server::completion_chunks(&[" a time"]))
+                }
+            }
+        })
+        .await;
+        let retries = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let text_synth = text_synth::with_base_url(&base_url).with_retry_hook({
+            let retries = Arc::clone(&retries);
+            move |attempt, delay, reason| {
+                assert!(reason.downcast_ref::<reqwest::Error>().is_some());
+                retries.lock().unwrap().push((attempt, delay));
+            }
+        });
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let texts = engine
+            .text_completion("Once")
+            .map_text(|text| text.to_uppercase())
+            .stream_resilient(1)
+            .await
+            .expect("network error")
+            .map(|result| result.unwrap().unwrap().unwrap().text().to_owned())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(texts, [" UPON", " A TIME"]);
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert_eq!(*retries.lock().unwrap(), [(1, Duration::ZERO)]);
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_on_engine() {
+        let base_url = server::serve(|request| {
+            assert_eq!(request.path, "/v1/engines/custom/completions");
+            Response::json(200, r#"{"text": "a", "reached_end": true}"#)
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let text_completion = engine
+            .text_completion("fn main() {")
+            .on_engine(ENGINE_DEFINITION.clone())
+            .now()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(text_completion.text(), "a");
+    }
+
+    #[test]
+    fn test_text_completion_on_engine_revalidates() {
+        let max_tokens = MaxTokens::new(2048, &text_synth::ENGINE_DEFINITION).unwrap();
+        let builder = YOU_SHOULD_CLONE_THIS_BUILDER.clone().max_tokens(max_tokens);
+        assert!(builder.clone().dry_run().is_ok());
+
+        let error = builder
+            .on_engine(ENGINE_DEFINITION.clone())
+            .dry_run()
+            .unwrap_err();
+        assert!(
+            matches!(&error[..], [ValidationError::OutOfRange(error)] if error.parameter() == "max_tokens")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_stream_to_channel() {
+        let base_url = server::serve(|request| match request.json()["prompt"].as_str() {
+            Some("error") => Response::json(400, r#"{"error": "bad prompt"}"#),
+            _ => Response::chunked(200, server::completion_chunks(&["a", "b"])),
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+
+        let (mut receiver, handle) = engine.text_completion("fn main() {").stream_to_channel();
+        let mut texts = Vec::new();
+
+        while let Some(text_completion) = receiver.recv().await {
+            texts.push(text_completion.text().to_owned());
+        }
+
+        assert_eq!(texts, ["a", "b"]);
+        handle.await.unwrap().expect("api error");
+
+        let (mut receiver, handle) = engine.text_completion("error").stream_to_channel();
+        assert!(receiver.recv().await.is_none());
+        let error = handle.await.unwrap().expect_err("expected an api error");
+        assert!(matches!(error, ApiError::Api(error) if error.message() == "bad prompt"));
+
+        let (receiver, handle) = engine
+            .text_completion("fn main() {")
+            .grammar(r#"root ::= "a""#)
+            .schema(serde_json::json!({ "type": "string" }))
+            .stream_to_channel();
+        drop(receiver);
+        let error = handle
+            .await
+            .unwrap()
+            .expect_err("expected a validation error");
+        assert!(matches!(error, ApiError::Api(error) if error.status_code() == 400));
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_now_until_end() {
+        let base_url = server::serve(|request| {
+            let request = request.json();
+            assert!(request.get("echo").is_none());
+            let max_tokens = request["max_tokens"].as_u64().unwrap();
+            let prompt = request["prompt"].as_str().unwrap();
+            let reached_end = prompt.len() == 3;
+            let text = &"abc"[prompt.len() - 1..][..1];
+            Response::json(
+                200,
+                format!(
+                    r#"{{"text": "{text}", "reached_end": {reached_end}, "input_tokens": {}, "output_tokens": {max_tokens}}}"#,
+                    prompt.len(),
+                ),
+            )
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let max_tokens = MaxTokens::new(4, &text_synth::ENGINE_DEFINITION).unwrap();
+
+        let text_completion = engine
+            .text_completion(">")
+            .max_tokens(max_tokens)
+            .echo(true)
+            .now_until_end(8, 100)
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(text_completion.text(), "abc");
+        assert!(text_completion.reached_end());
+        assert_eq!(text_completion.prompt_tokens(), Some(1));
+        assert_eq!(text_completion.completion_tokens(), Some(12));
+
+        let text_completion = engine
+            .text_completion(">")
+            .max_tokens(max_tokens)
+            .now_until_end(1, 100)
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(text_completion.text(), "ab");
+        assert!(!text_completion.reached_end());
+
+        let text_completion = engine
+            .text_completion(">")
+            .max_tokens(max_tokens)
+            .now_until_end(8, 6)
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(text_completion.text(), "ab");
+        assert_eq!(text_completion.completion_tokens(), Some(6));
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_now_until_end_prompt_tokens() {
+        let base_url = server::serve(|request| {
+            let json = request.json();
+
+            if request.path.ends_with("/tokenize") {
+                let text = json["text"].as_str().unwrap();
+                return Response::json(200, format!(r#"{{"tokens": [{}]}}"#, text.as_bytes()[0]));
+            }
+
+            let prompt = json["prompt"].as_array().unwrap();
+            let reached_end = prompt.len() == 3;
+            let text = &"abc"[prompt.len() - 1..][..1];
+            Response::json(
+                200,
+                format!(
+                    r#"{{"text": "{text}", "reached_end": {reached_end}, "total_tokens": {}, "output_tokens": 1}}"#,
+                    prompt.len() + 1,
+                ),
+            )
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+
+        let text_completion = engine
+            .text_completion_tokens(vec![1])
+            .now_until_end(8, 100)
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(text_completion.text(), "abc");
+        assert!(text_completion.reached_end());
+        // the prompt tokens are derived from the total before the output tokens are summed
+        assert_eq!(text_completion.prompt_tokens(), Some(1));
+        assert_eq!(text_completion.completion_tokens(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_error_on_truncation() {
+        let base_url = server::serve(|request| {
+            let truncated = request.json()["prompt"].as_str().unwrap().len() > 16;
+            Response::json(
+                200,
+                format!(r#"{{"text": "a", "reached_end": true, "truncated_prompt": {truncated}}}"#),
+            )
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let long_prompt = "fn main() {}\n".repeat(8);
+
+        let text_completion = engine
+            .text_completion(long_prompt.clone())
+            .now()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert!(text_completion.truncated_prompt());
+
+        let error = engine
+            .text_completion(long_prompt)
+            .error_on_truncation()
+            .now()
+            .await
+            .expect("network error")
+            .expect_err("expected a truncation error");
+        assert_eq!(error.status_code(), reqwest::StatusCode::BAD_REQUEST);
+
+        engine
+            .text_completion("fn main() {")
+            .error_on_truncation()
+            .now()
+            .await
+            .expect("network error")
+            .expect("api error");
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_stream_to_transcript() {
+        let base_url =
+            server::serve(|_| Response::chunked(200, server::completion_chunks(&["a", "b"]))).await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let transcript = text_synth
+            .engine(text_synth::ENGINE_DEFINITION)
+            .text_completion("fn main() {")
+            .stream_to_transcript()
+            .await
+            .expect("stream error");
+        assert_eq!(transcript.text(), "ab");
+        assert_eq!(transcript.chunks().len(), 2);
+        assert!(transcript.chunks()[1].elapsed() >= transcript.chunks()[0].elapsed());
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_stream_with_stats_excludes_rate_limit_wait() {
+        let base_url =
+            server::serve(|_| Response::chunked(200, server::completion_chunks(&["a"]))).await;
+        let text_synth = text_synth::with_base_url(&base_url).with_rate_limit(2.0);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let builder = engine.text_completion("fn main() {");
+
+        for _ in 0..2 {
+            let _ = builder.clone().now().await.expect("network error");
+        }
+
+        let start = Instant::now();
+        let items = builder
+            .stream_with_stats()
+            .await
+            .expect("network error")
+            .collect::<Vec<_>>()
+            .await;
+        let Some(stream::StatsStreamItem::Stats(stats)) = items.last() else {
+            panic!("missing stats");
+        };
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert!(stats.time_to_headers() < Duration::from_millis(400));
+        assert!(stats.duration() < Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_stream_with_progress() {
+        let base_url = server::serve(|request| {
+            assert_eq!(request.json()["max_tokens"], 4);
+            Response::chunked(200, server::completion_chunks(&["a", "b", "c"]))
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let max_tokens = MaxTokens::new(4, &text_synth::ENGINE_DEFINITION).unwrap();
+        let progress = engine
+            .text_completion("fn main() {")
+            .max_tokens(max_tokens)
+            .stream_with_progress()
+            .await
+            .expect("network error")
+            .map(|result| result.unwrap().unwrap().unwrap().1)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(progress, [0.25, 0.5, 1.0]);
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_stream_sentences() {
+        let base_url = server::serve(|_| {
+            Response::chunked(
+                200,
+                server::completion_chunks(&["Once upon", " a time. The", " end"]),
+            )
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let sentences = engine
+            .text_completion("Tell me a story.")
+            .stream_sentences()
+            .await
+            .expect("network error")
+            .map(|result| result.unwrap().unwrap().unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(sentences, ["Once upon a time.", "The end"]);
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_stream_tee() {
+        let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let base_url = server::serve({
+            let requests = Arc::clone(&requests);
+            move |_| {
+                requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Response::chunked(200, server::completion_chunks(&["a", "b"]))
+            }
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let streams = engine
+            .text_completion("fn main() {")
+            .stream_tee(2)
+            .await
+            .expect("network error");
+        let counts = future::join_all(streams.into_iter().map(StreamExt::count)).await;
+        assert_eq!(counts, [2, 2]);
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_text_completion_prompt_and_completion_tokens() {
+        let text_completion: TextCompletion = serde_json::from_str(
+            r#"{"text": "a", "reached_end": true, "input_tokens": 5, "output_tokens": 2}"#,
+        )
+        .unwrap();
+        assert_eq!(text_completion.prompt_tokens(), Some(5));
+        assert_eq!(text_completion.completion_tokens(), Some(2));
+        assert_eq!(text_completion.total_tokens(), Some(7));
+
+        let text_completion: TextCompletion = serde_json::from_str(
+            r#"{"text": "a", "reached_end": true, "total_tokens": 7, "input_tokens": 5}"#,
+        )
+        .unwrap();
+        assert_eq!(text_completion.completion_tokens(), Some(2));
+
+        let text_completion = test_utils::stream::text_completion("abc", true);
+        assert_eq!(text_completion.prompt_tokens(), None);
+        assert_eq!(text_completion.completion_tokens(), None);
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_count_completion_tokens() {
+        let base_url = server::serve(|request| {
+            assert_eq!(request.path, "/v1/engines/gptj_6B/tokenize");
+            assert_eq!(request.json()["text"], "the generated text");
+            Response::json(200, r#"{"tokens": [1, 2, 3]}"#)
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let text_completion = test_utils::stream::text_completion("the generated text", true);
+        let completion_tokens = text_completion
+            .count_completion_tokens(&engine)
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(completion_tokens, 3);
+    }
+
+    #[test]
+    fn test_text_completion_estimated_cost() {
+        let cost_model = CostModel::new(1.0, 3.0);
+        let text_completion: TextCompletion = serde_json::from_str(
+            r#"{"text": "a", "reached_end": true, "input_tokens": 1000, "output_tokens": 1000}"#,
+        )
+        .unwrap();
+        assert_eq!(text_completion.estimated_cost(&cost_model), 4.0);
+
+        let text_completion: TextCompletion =
+            serde_json::from_str(r#"{"text": "a", "reached_end": true, "total_tokens": 2000}"#)
+                .unwrap();
+        assert_eq!(text_completion.estimated_cost(&cost_model), 6.0);
+    }
+
+    #[test]
+    fn test_text_completion_camel_case() {
+        let snake_case: TextCompletion = serde_json::from_str(
+            r#"{"text": "a", "reached_end": true, "truncated_prompt": true, "total_tokens": 3, "token_logprobs": [-0.5]}"#,
+        )
+        .unwrap();
+        let camel_case: TextCompletion = serde_json::from_str(
+            r#"{"text": "a", "reachedEnd": true, "truncatedPrompt": true, "totalTokens": 3, "tokenLogprobs": [-0.5]}"#,
+        )
+        .unwrap();
+        assert_eq!(snake_case, camel_case);
+        assert!(camel_case.reached_end());
+        assert!(camel_case.truncated_prompt());
+        assert_eq!(camel_case.total_tokens(), Some(3));
+        assert_eq!(
+            camel_case.token_logprobs(),
+            Some(&[Some(LogProb(-0.5))][..])
+        );
+
+        let serialized = serde_json::to_value(&camel_case).unwrap();
+        assert_eq!(serialized["reached_end"], true);
+        assert_eq!(serialized["total_tokens"], 3);
+    }
+
+    #[test]
+    fn test_text_completion_dry_run() {
+        let request = BUILDER.clone().dry_run().unwrap();
+        assert_eq!(request["prompt"], "fn main() {");
+        assert_eq!(request["max_tokens"], 128);
+        assert!(request.get("stop").is_none());
+
+        let stop = Stop::try_new(["}"]).unwrap();
+        let request = BUILDER.clone().stop(stop).dry_run().unwrap();
+        assert_eq!(request["stop"], serde_json::json!(["}"]));
+
+        let error = BUILDER.clone().temperature(-1.0).dry_run().unwrap_err();
+        assert!(
+            matches!(&error[..], [ValidationError::OutOfRange(error)] if error.parameter() == "temperature")
+        );
+
+        let engine = text_synth::get().engine(UNLIMITED_ENGINE_DEFINITION.clone());
+        let max_tokens = MaxTokens::new(4096, &UNLIMITED_ENGINE_DEFINITION).unwrap();
+        let error = text_synth::engine()
+            .text_completion("")
+            .max_tokens(max_tokens)
+            .dry_run()
+            .unwrap_err();
+        assert!(
+            matches!(&error[..], [ValidationError::OutOfRange(error)] if error.parameter() == "max_tokens")
+        );
+        assert!(engine
+            .text_completion("")
+            .max_tokens(max_tokens)
+            .dry_run()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_text_completion_validate() {
+        assert_eq!(BUILDER.validate(), Ok(()));
+
+        let max_tokens = MaxTokens::new(4096, &UNLIMITED_ENGINE_DEFINITION).unwrap();
+        let stop = Stop::try_new([""]).unwrap();
+        let errors = text_synth::engine()
+            .text_completion("")
+            .max_tokens(max_tokens)
+            .temperature(-1.0)
+            .stop(stop)
+            .validate()
+            .unwrap_err();
+        let parameters = errors
+            .iter()
+            .map(|error| match error {
+                ValidationError::OutOfRange(error) => error.parameter(),
+                ValidationError::EmptyStopSequence => "stop",
+                ValidationError::GrammarAndSchema => "grammar",
+                ValidationError::EmptyPromptTokens => "prompt",
+                ValidationError::MissingContinuation => "continuation",
+                ValidationError::Unsupported(endpoint) => endpoint,
+                ValidationError::UnknownEndToken => "logit_bias",
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(parameters, ["max_tokens", "temperature", "stop"]);
+
+        let error = crate::Error::from(errors);
+        assert_eq!(error.status_code(), reqwest::StatusCode::BAD_REQUEST);
+        assert_eq!(error.message().matches("; ").count(), 2);
+    }
+
+    #[test]
+    fn test_text_completion_validate_unsupported() {
+        let builder = YOU_SHOULD_CLONE_THIS_BUILDER
+            .clone()
+            .on_engine(EngineDefinition::Custom(
+                CustomEngineDefinition::new("m2m100_1_2B", 1024)
+                    .with_capabilities(Capabilities::TRANSLATE_ONLY),
+            ));
+        assert_eq!(
+            builder.validate(),
+            Err(vec![ValidationError::Unsupported("completions")])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_invalid_not_sent() {
+        let base_url = server::serve(|_| panic!("invalid requests shouldn't be sent")).await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+
+        let stop = Stop::try_from(&["".into()][..]).unwrap();
+        let error = engine
+            .text_completion("fn main() {")
+            .now_until(stop)
+            .await
+            .expect("network error")
+            .expect_err("expected a validation error");
+        assert_eq!(error.status_code(), reqwest::StatusCode::BAD_REQUEST);
+
+        let items = engine
+            .text_completion("fn main() {")
+            .temperature(f64::NAN)
+            .stream()
+            .await
+            .expect("network error")
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(items.len(), 1);
+        assert!(matches!(&items[0], Ok(Ok(Err(_)))));
+    }
+
+    #[test]
+    fn test_text_completion_perplexity() {
+        let text_completion: TextCompletion = serde_json::from_str(
+            r#"{"text": "a b", "reached_end": true, "token_logprobs": [null, -1.0, -3.0]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            text_completion.token_logprobs(),
+            Some(&[None, Some(LogProb(-1.0)), Some(LogProb(-3.0))][..]),
+        );
+        assert_eq!(text_completion.perplexity(), Some(2.0f64.exp()));
+
+        let text_completion: TextCompletion =
+            serde_json::from_str(r#"{"text": "a", "reached_end": true, "token_logprobs": [null]}"#)
+                .unwrap();
+        assert_eq!(text_completion.perplexity(), None);
+
+        let text_completion = test_utils::stream::text_completion("a b", true);
+        assert_eq!(text_completion.perplexity(), None);
+    }
+
+    #[test]
+    fn test_text_completion_end_token_bias() {
+        let builder = BUILDER.clone().end_token_bias(5.0);
+        let request = builder.clone().dry_run().unwrap();
+        assert_eq!(request["logit_bias"], serde_json::json!({ "50256": 5.0 }));
+
+        let builder = builder.on_engine(ENGINE_DEFINITION.clone());
+        assert_eq!(
+            builder.clone().dry_run(),
+            Err(vec![ValidationError::UnknownEndToken]),
+        );
+
+        let request = builder
+            .on_engine(text_synth::ENGINE_DEFINITION)
+            .dry_run()
+            .unwrap();
+        assert_eq!(request["logit_bias"], serde_json::json!({ "50256": 5.0 }));
+    }
+
+    #[test]
+    fn test_text_completion_continue_with() {
+        let builder = BUILDER.clone().temperature(0.5);
+        let text_completion = test_utils::stream::text_completion("\n    let", true);
+
+        let request = text_completion
+            .continue_with(builder.clone())
+            .dry_run()
+            .unwrap();
+        assert_eq!(request["prompt"], "fn main() {\n    let");
+        assert_eq!(request["temperature"], 0.5);
+        assert_eq!(request["max_tokens"], 128);
+
+        let text_completion = test_utils::stream::text_completion("fn main() {}", true);
+        let request = text_completion
+            .continue_with(builder.echo(true))
+            .dry_run()
+            .unwrap();
+        assert_eq!(request["prompt"], "fn main() {}");
+    }
+
+    #[test]
+    fn test_log_prob_total_order() {
+        assert!(LogProb(-0.5) > LogProb(-2.0));
+        assert_eq!(LogProb(f64::NAN), LogProb(f64::NAN));
+        assert_ne!(LogProb(0.0), LogProb(-0.0));
+
+        let text_completion = test_utils::stream::text_completion("a", true);
+        let set = std::collections::HashSet::from([text_completion.clone(), text_completion]);
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    fn test_text_completion_top_logprobs() {
+        let text_completion: TextCompletion = serde_json::from_str(
+            r#"{"text": "a", "reached_end": true, "top_logprobs": [{"b": -2.0, "a": -0.5}]}"#,
+        )
+        .unwrap();
+        let expected = [vec![
+            ("a".to_owned(), LogProb(-0.5)),
+            ("b".to_owned(), LogProb(-2.0)),
+        ]];
+        assert_eq!(text_completion.top_logprobs(), Some(&expected[..]));
+
+        // serializing and deserializing again keeps the alternatives in order
+        let json = serde_json::to_string(&text_completion).unwrap();
+        let round_trip: TextCompletion = serde_json::from_str(&json).unwrap();
+        assert_eq!(round_trip, text_completion);
+
+        let text_completion = test_utils::stream::text_completion("a", true);
+        assert_eq!(text_completion.top_logprobs(), None);
+        let json = serde_json::to_value(&text_completion).unwrap();
+        assert!(json.get("top_logprobs").is_none());
+
+        let request = BUILDER.clone().logprobs_top_n(3).dry_run().unwrap();
+        assert_eq!(request["logprobs_top_n"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_now_correcting_definition() {
+        let base_url = server::serve(|request| match request.path.as_str() {
+            "/v1/engines" => {
+                Response::json(200, r#"{"engines": [{"id": "custom", "max_tokens": 512}]}"#)
+            }
+            _ => match request.json()["prompt"].as_str() {
+                Some("long") => Response::json(
+                    200,
+                    r#"{"text": "a", "reached_end": true, "truncated_prompt": true}"#,
+                ),
+                _ => Response::json(200, r#"{"text": "a", "reached_end": true}"#),
+            },
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(ENGINE_DEFINITION.clone());
+
+        let (_, corrected) = engine
+            .text_completion("long")
+            .now_correcting_definition()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(
+            corrected,
+            Some(CustomEngineDefinition::r#static("custom", 512))
+        );
+
+        let (_, corrected) = engine
+            .text_completion("short")
+            .now_correcting_definition()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(corrected, None);
+
+        // known engines have the right limits, so the listing isn't queried
+        let (_, corrected) = text_synth
+            .engine(text_synth::ENGINE_DEFINITION)
+            .text_completion("long")
+            .now_correcting_definition()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(corrected, None);
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_error_with_success_status() {
+        let base_url =
+            server::serve(|_| Response::json(200, r#"{"error": "upstream unavailable"}"#)).await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let error = engine
+            .text_completion("fn main() {")
+            .now()
+            .await
+            .expect("network error")
+            .expect_err("expected an error body to be an error");
+        assert_eq!(error.message(), "upstream unavailable");
+        assert_eq!(
+            error.status_code(),
+            reqwest::StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_now_with_raw() {
+        let base_url = server::serve(|request| match request.json()["prompt"].as_str() {
+            Some("invalid") => Response::json(200, r#"{"unexpected": true}"#),
+            _ => Response::json(
+                200,
+                r#"{"text": "a", "reached_end": true, "new_field": 42}"#,
+            ),
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+
+        let (text_completion, raw) = engine
+            .text_completion("fn main() {")
+            .now_with_raw()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(text_completion.text(), "a");
+        assert_eq!(raw["new_field"], 42);
+
+        let error = engine
+            .text_completion("invalid")
+            .now_with_raw()
+            .await
+            .expect("network error")
+            .expect_err("expected an error for unexpected json");
+        assert_eq!(error.status_code(), reqwest::StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_truncated_json() {
+        const BODY: &str = r#"{"text": "a", "reached_end": tr"#;
+
+        let base_url = server::serve(|_| Response::json(200, BODY)).await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let error = text_synth
+            .engine(text_synth::ENGINE_DEFINITION)
+            .text_completion("fn main() {")
+            .now()
+            .await
+            .expect("network error")
+            .expect_err("expected an error for truncated json");
+        assert_eq!(error.status_code(), reqwest::StatusCode::BAD_GATEWAY);
+        assert_eq!(error.body(), Some(BODY));
+        // the error reports where the json is invalid
+        assert!(error.message().contains("line 1 column"));
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_invalid_json_position() {
+        const BODY: &str = "{\n  \"text\": \"a\\nb\",\n  \"reached_end\": nope\n}";
+
+        let base_url = server::serve(|_| Response::json(200, BODY)).await;
+        let error = text_synth::with_base_url(&base_url)
+            .engine(text_synth::ENGINE_DEFINITION)
+            .text_completion("fn main() {")
+            .now()
+            .await
+            .expect("network error")
+            .expect_err("expected an error for invalid json");
+        // both json backends report the position as a line and column
+        assert!(error.message().contains("line 3 column"));
+    }
+
+    #[test]
+    fn test_text_completion_prompt_tokens() {
+        let request = BUILDER.clone().dry_run().unwrap();
+        assert!(request["prompt"].is_string());
+
+        let builder = BUILDER.clone().prompt_tokens(vec![1, 2, 3]);
+        let request = builder.clone().dry_run().unwrap();
+        assert_eq!(request["prompt"], serde_json::json!([1, 2, 3]));
+
+        let max_tokens = builder
+            .on_engine(EngineDefinition::Custom(CustomEngineDefinition::new(
+                "custom", 16,
+            )))
+            .max_tokens_to_engine_limit()
+            .max_tokens
+            .unwrap();
+        assert_eq!(max_tokens.inner(), 13);
+
+        assert_eq!(
+            BUILDER.clone().prompt_tokens(Vec::new()).validate(),
+            Err(vec![ValidationError::EmptyPromptTokens])
+        );
+    }
+
+    #[test]
+    fn test_text_completion_echo() {
+        let request = BUILDER.clone().echo(true).logprobs(true).dry_run().unwrap();
+        assert_eq!(request["echo"], true);
+        assert_eq!(request["logprobs"], true);
+    }
+
+    #[test]
+    fn test_text_completion_assertions() {
+        test_utils::stream::text_completion("Hello, world!", true)
+            .assert_reached_end()
+            .assert_not_truncated_prompt()
+            .assert_contains("world")
+            .assert_total_tokens_at_most(13);
+    }
+
+    #[test]
+    #[should_panic(expected = "expected the text completion to contain \"moon\"")]
+    fn test_text_completion_assert_contains_panics() {
+        test_utils::stream::text_completion("Hello, world!", true).assert_contains("moon");
+    }
+
+    #[test]
+    fn test_text_completion_lenient_total_tokens() {
+        for total_tokens in ["1024", "1024.0", "1024.5", "\"1024\"", "\"1024.0\""] {
+            let json =
+                format!(r#"{{"text": "", "reached_end": true, "total_tokens": {total_tokens}}}"#);
+            let text_completion: TextCompletion = serde_json::from_str(&json).unwrap();
+            assert_eq!(text_completion.total_tokens(), Some(1024));
+        }
+
+        for total_tokens in ["null", "-1", "\"many\""] {
+            let json =
+                format!(r#"{{"text": "", "reached_end": true, "total_tokens": {total_tokens}}}"#);
+            let text_completion = serde_json::from_str::<TextCompletion>(&json);
+            assert!(
+                text_completion.map_or(true, |text_completion| text_completion
+                    .total_tokens()
+                    .is_none())
+            );
+        }
+
+        let text_completion: TextCompletion =
+            serde_json::from_str(r#"{"text": "", "reached_end": false}"#).unwrap();
+        assert_eq!(text_completion.total_tokens(), None);
+    }
+
+    #[test]
+    fn test_text_completion_max_tokens_to_engine_limit() {
+        let engine = text_synth::get().engine(ENGINE_DEFINITION.clone());
+        let builder = engine
+            .text_completion("a".repeat(400))
+            .max_tokens_to_engine_limit();
+        assert_eq!(
+            builder.max_tokens.map(|max_tokens| max_tokens.inner()),
+            Some(1024 - ENGINE_DEFINITION.pessimistic_tokens_for_chars(400))
+        );
+
+        let builder = engine
+            .text_completion("a".repeat(10_000))
+            .max_tokens_to_engine_limit();
+        assert_eq!(
+            builder.max_tokens.map(|max_tokens| max_tokens.inner()),
+            Some(1)
+        );
+
+        let builder = engine
+            .text_completion("a")
+            .on_engine(EngineDefinition::Custom(
+                CustomEngineDefinition::r#static("no_output", 1024).with_max_output_tokens(0),
+            ))
+            .max_tokens_to_engine_limit();
+        assert!(builder.max_tokens.is_none());
+    }
+
+    #[test]
+    fn test_text_completion_max_tokens_fraction() {
+        let max_tokens = |builder: Option<TextCompletionBuilder>| {
+            builder.and_then(|builder| builder.max_tokens.map(|max_tokens| max_tokens.inner()))
+        };
+        let builder = YOU_SHOULD_CLONE_THIS_BUILDER
+            .clone()
+            .on_engine(ENGINE_DEFINITION.clone());
+        assert_eq!(
+            max_tokens(builder.clone().max_tokens_fraction(0.25)),
+            Some(256)
+        );
+        assert_eq!(
+            max_tokens(builder.clone().max_tokens_fraction(0.0)),
+            Some(1)
+        );
+        assert_eq!(
+            max_tokens(builder.clone().max_tokens_fraction(1.0)),
+            Some(1024)
+        );
+        assert!(builder.clone().max_tokens_fraction(1.5).is_none());
+        assert!(builder.clone().max_tokens_fraction(-0.1).is_none());
+        assert!(builder.max_tokens_fraction(f64::NAN).is_none());
+    }
+
+    #[test]
+    fn test_text_completion_logit_bias() {
+        let builder = BUILDER.clone().logit_bias(42, -1.0).logit_bias(7, 2.5);
+        let request = serde_json::to_value(builder.into_request(None)).unwrap();
+        assert_eq!(request["logit_bias"]["42"], -1.0);
+        assert_eq!(request["logit_bias"]["7"], 2.5);
+
+        let request = serde_json::to_value(BUILDER.clone().into_request(None)).unwrap();
+        assert!(request.get("logit_bias").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_bias_str() {
+        let base_url = server::serve(|request| match request.path.as_str() {
+            "/v1/engines/gptj_6B/tokenize" => Response::json(200, r#"{"tokens": [1, 2]}"#),
+            _ => {
+                assert_eq!(request.json()["logit_bias"]["1"], -100.0);
+                assert_eq!(request.json()["logit_bias"]["2"], -100.0);
+                Response::json(200, r#"{"text": "", "reached_end": true}"#)
+            }
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        engine
+            .text_completion("fn main() {")
+            .bias_str(" panic", -100.0)
+            .await
+            .expect("network error")
+            .expect("api error")
             .now()
             .await
             .expect("network error")
             .expect("api error");
-        assert!(text_completion.truncated_prompt())
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_stream_framing() {
+        let base_url = server::serve(|_| {
+            let chunks = [
+                ": keep-alive\n\n",
+                "data: {\"text\": \"a\", ",
+                "\"reached_end\": false}\n\n",
+                "data: {\"text\": \"b\", \"reached_end\": true}\n\ndata: [DONE]\n\n",
+            ];
+            Response::chunked(200, chunks.map(|chunk| chunk.as_bytes().to_vec()).to_vec())
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let texts = engine
+            .text_completion("fn main() {")
+            .framing(StreamFraming::ServerSentEvents)
+            .stream()
+            .await
+            .expect("network error")
+            .map(|item| item.unwrap().unwrap().unwrap().text().to_owned())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(texts, ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_stream_aborted() {
+        let base_url = server::serve(|_| {
+            Response::chunked(200, server::completion_chunks(&["a", "b", "c", "d"]))
+        })
+        .await;
+        let registry = AbortRegistry::new();
+        let text_synth = text_synth::with_base_url(&base_url).with_abort_registry(registry.clone());
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let mut stream = Box::pin(
+            engine
+                .text_completion("fn main() {")
+                .stream()
+                .await
+                .expect("network error"),
+        );
+
+        let first = stream.next().await.unwrap().unwrap().unwrap().unwrap();
+        assert_eq!(first.text(), "a");
+
+        registry.abort_all();
+
+        let error = stream.next().await.unwrap().unwrap().unwrap().unwrap_err();
+        assert!(error.is_cancelled());
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_map_text() {
+        let base_url = server::serve(|request| {
+            if request.json()["stream"] == true {
+                Response::chunked(200, server::completion_chunks(&[" a ", " b "]))
+            } else {
+                Response::json(200, r#"{"text": " a ", "reached_end": true}"#)
+            }
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let builder = engine
+            .text_completion("fn main() {")
+            .map_text(|text| text.trim().to_owned())
+            .map_text(|text| text.to_uppercase());
+
+        let text_completion = builder
+            .clone()
+            .now()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(text_completion.text(), "A");
+
+        let texts = builder
+            .stream()
+            .await
+            .expect("network error")
+            .map(|item| item.unwrap().unwrap().unwrap().text().to_owned())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(texts, ["A", "B"]);
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_now_and_friends() {
+        let text_completion = BUILDER
+            .clone()
+            .now()
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert!(
+            text_completion.total_tokens().is_some(),
+            "expected total tokens of immediate text completion to exist since it is not streamed",
+        );
+        let _ = text_completion.text();
+        let _ = text_completion.truncated_prompt();
+        let _ = text_completion.reached_end();
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_truncated_prompt_if_prompt_too_long() {
+        let mut builder = BUILDER.clone();
+
+        // v
+        builder.prompt = format!(
+            "fn main() {{\n{}}}",
+            "println('Hello World')\n".repeat(2048)
+        );
+
+        builder
+            .now()
+            .await
+            .expect("network error")
+            .expect("api error")
+            .assert_truncated_prompt();
     }
 
     #[tokio::test]
@@ -0,0 +1,233 @@
+//! Operations involving tokenization.
+
+use crate::engine::Engine;
+use crate::utils;
+use crate::wire::TokenizeRequest;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use tap::Pipe;
+
+/// The id of a token in the vocabulary of an engine.
+pub type TokenId = u32;
+
+/// How a prompt fits in the context window of an engine. See
+/// [`Engine::count_tokens_for_completion`].
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct BudgetReport {
+    prompt_tokens: usize,
+    max_tokens: usize,
+    requested_tokens: usize,
+}
+
+impl BudgetReport {
+    pub(crate) const fn new(
+        prompt_tokens: usize,
+        max_tokens: usize,
+        requested_tokens: usize,
+    ) -> Self {
+        Self {
+            prompt_tokens,
+            max_tokens,
+            requested_tokens,
+        }
+    }
+
+    /// The number of tokens of the prompt.
+    pub const fn prompt_tokens(&self) -> usize {
+        self.prompt_tokens
+    }
+
+    /// The number of tokens which can still be generated after the prompt, which is zero if the
+    /// prompt fills or exceeds the context window.
+    pub const fn remaining(&self) -> usize {
+        self.max_tokens.saturating_sub(self.prompt_tokens)
+    }
+
+    /// Whether the requested number of tokens can be generated after the prompt.
+    pub const fn fits(&self) -> bool {
+        !self.prompt_exceeds_window() && self.requested_tokens <= self.remaining()
+    }
+
+    /// Whether the prompt alone exceeds the context window, in which case the API truncates it
+    /// (see [`TextCompletion::truncated_prompt`]).
+    ///
+    /// [`TextCompletion::truncated_prompt`]: crate::engine::text_completion::TextCompletion::truncated_prompt
+    pub const fn prompt_exceeds_window(&self) -> bool {
+        self.prompt_tokens > self.max_tokens
+    }
+}
+
+#[derive(Deserialize)]
+struct Tokens {
+    tokens: Vec<TokenId>,
+}
+
+/// How many tokenizations each [`TextSynth`](crate::core::TextSynth) instance caches, see
+/// [`TextCompletionBuilder::bias_str`].
+///
+/// [`TextCompletionBuilder::bias_str`]: crate::engine::text_completion::TextCompletionBuilder::bias_str
+pub const TOKENIZATION_CACHE_CAPACITY: usize = 256;
+
+/// Tokenizations keyed by engine id and text, held by a [`TextSynth`](crate::core::TextSynth)
+/// instance so that entries are never shared with another server. Tokenizing a text is
+/// deterministic for a given engine, so entries are only evicted, least recently used first, to
+/// stay within [`TOKENIZATION_CACHE_CAPACITY`].
+#[derive(Debug, Default)]
+pub(crate) struct TokenizationCache {
+    entries: HashMap<(String, String), Vec<TokenId>>,
+    recency: VecDeque<(String, String)>,
+}
+
+impl TokenizationCache {
+    fn get(&mut self, key: &(String, String)) -> Option<Vec<TokenId>> {
+        let tokens = self.entries.get(key)?.clone();
+        self.touch(key);
+        Some(tokens)
+    }
+
+    fn insert(&mut self, key: (String, String), tokens: Vec<TokenId>) {
+        if self.entries.insert(key.clone(), tokens).is_some() {
+            self.touch(&key);
+            return;
+        }
+
+        self.recency.push_back(key);
+
+        if self.recency.len() > TOKENIZATION_CACHE_CAPACITY {
+            if let Some(evicted) = self.recency.pop_front() {
+                self.entries.remove(&evicted);
+            }
+        }
+    }
+
+    fn touch(&mut self, key: &(String, String)) {
+        if let Some(position) = self.recency.iter().position(|other| other == key) {
+            let key = self
+                .recency
+                .remove(position)
+                .expect("position is in bounds");
+            self.recency.push_back(key);
+        }
+    }
+}
+
+/// Tokenize the given text with the given engine.
+pub(crate) async fn tokenize(
+    engine: &Engine<'_>,
+    text: &str,
+) -> reqwest::Result<crate::Result<Vec<TokenId>>> {
+    let engine_id = engine.definition.id();
+    let text_synth = engine.text_synth;
+    let url = text_synth.url(&format!("/v1/engines/{engine_id}/tokenize"));
+
+    text_synth
+        .abortable(async {
+            text_synth
+                .send(text_synth.post(url).json(&TokenizeRequest { text }))
+                .await?
+                .pipe(utils::json::<Tokens>)
+                .await
+                .map(|result| result.map(|tokens| tokens.tokens))
+        })
+        .await
+}
+
+/// Same as [`tokenize`], but reuses the tokenization of previous calls with the same engine and
+/// text made through the same [`TextSynth`](crate::core::TextSynth) instance, or its clones.
+pub(crate) async fn tokenize_cached(
+    engine: &Engine<'_>,
+    text: &str,
+) -> reqwest::Result<crate::Result<Vec<TokenId>>> {
+    let cache = engine.text_synth.tokenization_cache();
+    let key = (engine.definition.id().to_owned(), text.to_owned());
+
+    if let Some(tokens) = cache.lock().expect("cache lock poisoned").get(&key) {
+        return Ok(Ok(tokens));
+    }
+
+    let tokens = tokenize(engine, text).await?;
+
+    if let Ok(tokens) = &tokens {
+        cache
+            .lock()
+            .expect("cache lock poisoned")
+            .insert(key, tokens.clone());
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::server::{self, Response};
+    use crate::test_utils::text_synth;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_budget_report() {
+        let report = BudgetReport::new(1000, 2048, 1048);
+        assert_eq!(report.remaining(), 1048);
+        assert!(report.fits());
+        assert!(!BudgetReport::new(1000, 2048, 1049).fits());
+
+        let report = BudgetReport::new(3000, 2048, 0);
+        assert_eq!(report.remaining(), 0);
+        assert!(report.prompt_exceeds_window());
+        assert!(!report.fits());
+    }
+
+    #[tokio::test]
+    async fn test_tokenize_cached() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let base_url = server::serve({
+            let requests = Arc::clone(&requests);
+            move |request| {
+                assert_eq!(request.path, "/v1/engines/gptj_6B/tokenize");
+                assert_eq!(request.json()["text"], "hello world");
+                requests.fetch_add(1, Ordering::SeqCst);
+                Response::json(200, r#"{"tokens": [31373, 995]}"#)
+            }
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+
+        for _ in 0..2 {
+            let tokens = tokenize_cached(&engine, "hello world")
+                .await
+                .expect("network error")
+                .expect("api error");
+            assert_eq!(tokens, [31373, 995]);
+        }
+
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // another instance doesn't share the cache
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        tokenize_cached(&engine, "hello world")
+            .await
+            .expect("network error")
+            .expect("api error");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_tokenization_cache_capacity() {
+        let mut cache = TokenizationCache::default();
+        let key = |index: usize| (String::from("gptj_6B"), index.to_string());
+
+        for index in 0..TOKENIZATION_CACHE_CAPACITY {
+            cache.insert(key(index), vec![index as TokenId]);
+        }
+
+        // the first entry is now the most recently used, so the second one is evicted instead
+        assert_eq!(cache.get(&key(0)), Some(vec![0]));
+        cache.insert(key(TOKENIZATION_CACHE_CAPACITY), vec![0]);
+        assert_eq!(cache.entries.len(), TOKENIZATION_CACHE_CAPACITY);
+        assert!(cache.get(&key(0)).is_some());
+        assert!(cache.get(&key(1)).is_none());
+    }
+}
@@ -0,0 +1,660 @@
+//! Operations involving translation.
+
+use crate::abort;
+use crate::core::TextSynth;
+use crate::engine::Engine;
+use crate::error::ValidationError;
+use crate::utils;
+use crate::wire::TranslateRequest;
+use futures::future::{self, Either};
+use futures::{stream, Stream};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::fmt;
+use std::str::FromStr;
+use tap::Pipe;
+
+/// The translation of a single text.
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Deserialize)]
+pub struct TranslationSegment {
+    text: String,
+
+    #[serde(alias = "detectedSourceLang")]
+    detected_source_lang: Option<String>,
+}
+
+impl TranslationSegment {
+    /// Returns the translated text.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the detected language of the source text, if the source language was `auto`.
+    pub fn detected_source_lang(&self) -> Option<&str> {
+        self.detected_source_lang.as_deref()
+    }
+}
+
+/// A translation response from the API, which holds a translation per text, in the same order
+/// as the texts were given.
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Deserialize)]
+pub struct Translation {
+    translations: Vec<TranslationSegment>,
+}
+
+impl Translation {
+    /// Returns the translations of each text, in the same order as they were given.
+    pub fn translations(&self) -> &[TranslationSegment] {
+        &self.translations
+    }
+
+    /// Take the translations of each text, in the same order as they were given.
+    pub fn into_translations(self) -> Vec<TranslationSegment> {
+        self.translations
+    }
+
+    /// Returns an iterator over the translations of each text, in the same order as they were
+    /// given.
+    pub fn iter(&self) -> std::slice::Iter<'_, TranslationSegment> {
+        self.translations.iter()
+    }
+
+    /// Returns the translated text of the first text, which is convenient when translating a
+    /// single text.
+    ///
+    /// Returns [`None`] if there are no translations.
+    pub fn first_text(&self) -> Option<&str> {
+        self.translations.first().map(TranslationSegment::text)
+    }
+
+    /// Returns the number of translations.
+    pub fn len(&self) -> usize {
+        self.translations.len()
+    }
+
+    /// Returns whether there are no translations.
+    pub fn is_empty(&self) -> bool {
+        self.translations.is_empty()
+    }
+}
+
+impl IntoIterator for Translation {
+    type Item = TranslationSegment;
+    type IntoIter = std::vec::IntoIter<TranslationSegment>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.translations.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Translation {
+    type Item = &'a TranslationSegment;
+    type IntoIter = std::slice::Iter<'a, TranslationSegment>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// A language of a translation, identified by its [ISO 639-1] code, to avoid typos in raw
+/// language codes. Languages which aren't listed can be given through [`Self::Other`].
+///
+/// Builders accept either a [`Language`] or a raw language code.
+///
+/// [ISO 639-1]: https://en.wikipedia.org/wiki/List_of_ISO_639-1_codes
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub enum Language {
+    /// Arabic (`ar`).
+    Arabic,
+
+    /// Chinese (`zh`).
+    Chinese,
+
+    /// Czech (`cs`).
+    Czech,
+
+    /// Danish (`da`).
+    Danish,
+
+    /// Dutch (`nl`).
+    Dutch,
+
+    /// English (`en`).
+    English,
+
+    /// Finnish (`fi`).
+    Finnish,
+
+    /// French (`fr`).
+    French,
+
+    /// German (`de`).
+    German,
+
+    /// Greek (`el`).
+    Greek,
+
+    /// Hebrew (`he`).
+    Hebrew,
+
+    /// Hindi (`hi`).
+    Hindi,
+
+    /// Hungarian (`hu`).
+    Hungarian,
+
+    /// Indonesian (`id`).
+    Indonesian,
+
+    /// Italian (`it`).
+    Italian,
+
+    /// Japanese (`ja`).
+    Japanese,
+
+    /// Korean (`ko`).
+    Korean,
+
+    /// Norwegian (`no`).
+    Norwegian,
+
+    /// Polish (`pl`).
+    Polish,
+
+    /// Portuguese (`pt`).
+    Portuguese,
+
+    /// Romanian (`ro`).
+    Romanian,
+
+    /// Russian (`ru`).
+    Russian,
+
+    /// Spanish (`es`).
+    Spanish,
+
+    /// Swedish (`sv`).
+    Swedish,
+
+    /// Turkish (`tr`).
+    Turkish,
+
+    /// Ukrainian (`uk`).
+    Ukrainian,
+
+    /// Vietnamese (`vi`).
+    Vietnamese,
+
+    /// Any other language, given by its code as is.
+    Other(String),
+}
+
+impl Language {
+    /// Returns the ISO 639-1 code of this language, or the code of [`Self::Other`] as is.
+    pub fn as_code(&self) -> &str {
+        match self {
+            Self::Arabic => "ar",
+            Self::Chinese => "zh",
+            Self::Czech => "cs",
+            Self::Danish => "da",
+            Self::Dutch => "nl",
+            Self::English => "en",
+            Self::Finnish => "fi",
+            Self::French => "fr",
+            Self::German => "de",
+            Self::Greek => "el",
+            Self::Hebrew => "he",
+            Self::Hindi => "hi",
+            Self::Hungarian => "hu",
+            Self::Indonesian => "id",
+            Self::Italian => "it",
+            Self::Japanese => "ja",
+            Self::Korean => "ko",
+            Self::Norwegian => "no",
+            Self::Polish => "pl",
+            Self::Portuguese => "pt",
+            Self::Romanian => "ro",
+            Self::Russian => "ru",
+            Self::Spanish => "es",
+            Self::Swedish => "sv",
+            Self::Turkish => "tr",
+            Self::Ukrainian => "uk",
+            Self::Vietnamese => "vi",
+            Self::Other(code) => code,
+        }
+    }
+}
+
+impl FromStr for Language {
+    type Err = Infallible;
+
+    /// Parse a language from its ISO 639-1 code, ignoring case. Unknown codes are kept as
+    /// [`Language::Other`].
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        Ok(match code.to_ascii_lowercase().as_str() {
+            "ar" => Self::Arabic,
+            "zh" => Self::Chinese,
+            "cs" => Self::Czech,
+            "da" => Self::Danish,
+            "nl" => Self::Dutch,
+            "en" => Self::English,
+            "fi" => Self::Finnish,
+            "fr" => Self::French,
+            "de" => Self::German,
+            "el" => Self::Greek,
+            "he" => Self::Hebrew,
+            "hi" => Self::Hindi,
+            "hu" => Self::Hungarian,
+            "id" => Self::Indonesian,
+            "it" => Self::Italian,
+            "ja" => Self::Japanese,
+            "ko" => Self::Korean,
+            "no" => Self::Norwegian,
+            "pl" => Self::Polish,
+            "pt" => Self::Portuguese,
+            "ro" => Self::Romanian,
+            "ru" => Self::Russian,
+            "es" => Self::Spanish,
+            "sv" => Self::Swedish,
+            "tr" => Self::Turkish,
+            "uk" => Self::Ukrainian,
+            "vi" => Self::Vietnamese,
+            _ => Self::Other(code.to_owned()),
+        })
+    }
+}
+
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_code())
+    }
+}
+
+impl From<Language> for String {
+    fn from(language: Language) -> Self {
+        match language {
+            Language::Other(code) => code,
+            language => language.as_code().to_owned(),
+        }
+    }
+}
+
+/// Split a text into the chunks translated separately when streaming, which are its paragraphs.
+pub(crate) fn chunk_text(text: &str) -> impl Iterator<Item = &str> {
+    text.split("\n\n")
+        .map(str::trim)
+        .filter(|chunk| !chunk.is_empty())
+}
+
+/// A translation builder.
+#[derive(Clone)]
+#[must_use = "builders do nothing unless the request is sent"]
+pub struct TranslateBuilder<'ts, 'e> {
+    /// The engine used to create this translation request.
+    pub engine: &'e Engine<'ts>,
+
+    /// The texts to translate.
+    pub text: Vec<String>,
+
+    /// See [`Self::source_lang`].
+    pub source_lang: String,
+
+    /// The language code of the language to translate to. See [`Language`] for typed codes.
+    pub target_lang: String,
+
+    /// See [`Self::num_beams`].
+    pub num_beams: Option<u8>,
+
+    /// See [`Self::split_sentences`].
+    pub split_sentences: Option<bool>,
+}
+
+impl<'ts, 'e> TranslateBuilder<'ts, 'e> {
+    /// Create a new translation builder.
+    pub fn new(engine: &'e Engine<'ts>, text: Vec<String>, target_lang: String) -> Self {
+        Self {
+            engine,
+            text,
+            source_lang: "auto".into(),
+            target_lang,
+            num_beams: None,
+            split_sentences: None,
+        }
+    }
+
+    /// Set the language code, or [`Language`], of the source texts. Defaults to `auto`, which
+    /// detects the language.
+    pub fn source_lang(mut self, source_lang: impl Into<String>) -> Self {
+        self.source_lang = source_lang.into();
+        self
+    }
+
+    /// Set the number of beams used to generate the translation. A larger number of beams gives a
+    /// potentially better translation but is slower.
+    pub fn num_beams(mut self, num_beams: u8) -> Self {
+        self.num_beams = Some(num_beams);
+        self
+    }
+
+    /// Set whether the texts are split into sentences which are translated independently.
+    pub fn split_sentences(mut self, split_sentences: bool) -> Self {
+        self.split_sentences = Some(split_sentences);
+        self
+    }
+
+    /// Run the client side validations done before sending a request, returning every invalid
+    /// parameter at once. See [`TextCompletionBuilder::validate`].
+    ///
+    /// [`TextCompletionBuilder::validate`]: crate::engine::text_completion::TextCompletionBuilder::validate
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Vec::new();
+
+        if !self.engine.definition.capabilities().translate {
+            errors.push(ValidationError::Unsupported("translate"));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn url(&self) -> String {
+        let engine_id = self.engine.definition.id();
+        self.engine
+            .text_synth
+            .url(&format!("/v1/engines/{engine_id}/translate"))
+    }
+
+    async fn send(
+        text_synth: &TextSynth,
+        url: &str,
+        request: &TranslateRequest,
+    ) -> reqwest::Result<crate::Result<Translation>> {
+        text_synth
+            .send(text_synth.post(url).json(request))
+            .await?
+            .pipe(utils::json)
+            .await
+    }
+
+    /// Translate the texts now.
+    ///
+    /// Invalid requests, such as ones to an engine which doesn't support translation, aren't sent.
+    /// A `400 Bad Request` [`crate::Error`] describing the problems is returned instead, see
+    /// [`Self::validate`].
+    pub async fn now(self) -> reqwest::Result<crate::Result<Translation>> {
+        if let Err(errors) = self.validate() {
+            return Ok(Err(errors.into()));
+        }
+
+        let text_synth = self.engine.text_synth;
+        let url = self.url();
+        let request = TranslateRequest {
+            text: self.text,
+            source_lang: self.source_lang,
+            target_lang: self.target_lang,
+            num_beams: self.num_beams,
+            split_sentences: self.split_sentences,
+        };
+
+        text_synth
+            .abortable(Self::send(text_synth, &url, &request))
+            .await
+    }
+
+    /// Create a stream of translated segments, yielded as they complete.
+    ///
+    /// The translation endpoint isn't streaming, so this is done client side: each text is split
+    /// into its paragraphs (separated by blank lines), which are translated one request at a time
+    /// and yielded in order. This gives progressive output for long documents, at the cost of one
+    /// request per paragraph and of translating each paragraph without the context of the others.
+    ///
+    /// Invalid requests aren't sent, and the stream only yields the validation error instead, see
+    /// [`Self::now`].
+    pub fn stream(
+        self,
+    ) -> impl Stream<Item = reqwest::Result<crate::Result<TranslationSegment>>> + 'ts {
+        if let Err(errors) = self.validate() {
+            let invalid = future::ready(Ok(Err(errors.into())));
+            return Either::Left(stream::once(invalid));
+        }
+
+        let text_synth = self.engine.text_synth;
+        let url = self.url();
+        let chunks = self
+            .text
+            .iter()
+            .flat_map(|text| chunk_text(text))
+            .map(String::from)
+            .collect::<VecDeque<_>>();
+        let request = TranslateRequest {
+            text: Vec::new(),
+            source_lang: self.source_lang,
+            target_lang: self.target_lang,
+            num_beams: self.num_beams,
+            split_sentences: self.split_sentences,
+        };
+
+        let registration = text_synth.abort_registration();
+        let stream = stream::unfold((chunks, request), move |(mut chunks, mut request)| {
+            let url = url.clone();
+
+            async move {
+                loop {
+                    request.text = vec![chunks.pop_front()?];
+
+                    let segment = match Self::send(text_synth, &url, &request).await {
+                        Ok(Ok(translation)) => match translation.translations.into_iter().next() {
+                            Some(segment) => Ok(Ok(segment)),
+                            None => continue,
+                        },
+                        Ok(Err(error)) => Ok(Err(error)),
+                        Err(error) => Err(error),
+                    };
+
+                    return Some((segment, (chunks, request)));
+                }
+            }
+        });
+
+        Either::Right(abort::abortable_stream(registration, stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::{Capabilities, CustomEngineDefinition, EngineDefinition};
+    use crate::test_utils;
+    use crate::test_utils::server::{self, Response};
+    use futures::StreamExt;
+
+    fn uppercase_server() -> impl Fn(&server::Request) -> Response {
+        |request| {
+            assert_eq!(request.path, "/v1/engines/m2m100_1_2B/translate");
+            let body = request.json();
+            assert_eq!(body["target_lang"], "fr");
+            let translations = body["text"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|text| {
+                    serde_json::json!({
+                        "text": text.as_str().unwrap().to_uppercase(),
+                        "detected_source_lang": "en",
+                    })
+                })
+                .collect::<Vec<_>>();
+            Response::json(
+                200,
+                serde_json::json!({ "translations": translations }).to_string(),
+            )
+        }
+    }
+
+    fn engine_definition() -> EngineDefinition {
+        EngineDefinition::Custom(
+            CustomEngineDefinition::new("m2m100_1_2B", 1024)
+                .with_capabilities(Capabilities::TRANSLATE_ONLY),
+        )
+    }
+
+    #[test]
+    fn test_chunk_text() {
+        let chunks = chunk_text("first\n\n\n\nsecond\nline\n\n  ").collect::<Vec<_>>();
+        assert_eq!(chunks, ["first", "second\nline"]);
+    }
+
+    #[test]
+    fn test_language_code_round_trip() {
+        let languages = [
+            Language::Arabic,
+            Language::Chinese,
+            Language::Czech,
+            Language::Danish,
+            Language::Dutch,
+            Language::English,
+            Language::Finnish,
+            Language::French,
+            Language::German,
+            Language::Greek,
+            Language::Hebrew,
+            Language::Hindi,
+            Language::Hungarian,
+            Language::Indonesian,
+            Language::Italian,
+            Language::Japanese,
+            Language::Korean,
+            Language::Norwegian,
+            Language::Polish,
+            Language::Portuguese,
+            Language::Romanian,
+            Language::Russian,
+            Language::Spanish,
+            Language::Swedish,
+            Language::Turkish,
+            Language::Ukrainian,
+            Language::Vietnamese,
+        ];
+
+        for language in languages {
+            assert_eq!(language.as_code().len(), 2);
+            assert_eq!(language.as_code().parse::<Language>(), Ok(language.clone()));
+            assert_eq!(String::from(language.clone()), language.to_string());
+        }
+
+        assert_eq!("FR".parse::<Language>(), Ok(Language::French));
+        assert_eq!("fra".parse::<Language>(), Ok(Language::Other("fra".into())));
+        assert_eq!(Language::Other("fra".into()).as_code(), "fra");
+    }
+
+    #[test]
+    fn test_translation() {
+        let translation: Translation = serde_json::from_str(
+            r#"{"translations": [
+                {"text": "bonjour", "detected_source_lang": "en"},
+                {"text": "monde", "detected_source_lang": null}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(translation.len(), 2);
+        assert_eq!(translation.first_text(), Some("bonjour"));
+
+        let texts = (&translation)
+            .into_iter()
+            .map(TranslationSegment::text)
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["bonjour", "monde"]);
+
+        let languages = translation
+            .into_iter()
+            .map(|segment| segment.detected_source_lang)
+            .collect::<Vec<_>>();
+        assert_eq!(languages, [Some("en".to_owned()), None]);
+
+        let translation: Translation = serde_json::from_str(r#"{"translations": []}"#).unwrap();
+        assert!(translation.is_empty());
+        assert_eq!(translation.first_text(), None);
+    }
+
+    #[test]
+    fn test_translation_segment_camel_case() {
+        let segment: TranslationSegment =
+            serde_json::from_str(r#"{"text": "bonjour", "detectedSourceLang": "en"}"#).unwrap();
+        assert_eq!(segment.detected_source_lang(), Some("en"));
+    }
+
+    #[tokio::test]
+    async fn test_translate_builder_now() {
+        let base_url = server::serve(uppercase_server()).await;
+        let text_synth = test_utils::text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(engine_definition());
+        let translation = engine
+            .translate(vec!["hello".into(), "world".into()], Language::French)
+            .source_lang("en")
+            .num_beams(4)
+            .split_sentences(true)
+            .now()
+            .await
+            .expect("network error")
+            .expect("api error");
+        let texts = translation
+            .translations()
+            .iter()
+            .map(TranslationSegment::text)
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["HELLO", "WORLD"]);
+        assert_eq!(
+            translation.translations()[0].detected_source_lang(),
+            Some("en")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_translate_builder_stream() {
+        let base_url = server::serve(uppercase_server()).await;
+        let text_synth = test_utils::text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(engine_definition());
+        let segments = engine
+            .translate(vec!["first\n\nsecond".into(), "third".into()], "fr")
+            .stream()
+            .map(|segment| {
+                segment
+                    .expect("network error")
+                    .expect("api error")
+                    .text()
+                    .to_owned()
+            })
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(segments, ["FIRST", "SECOND", "THIRD"]);
+    }
+
+    #[tokio::test]
+    async fn test_translate_builder_unsupported() {
+        let base_url = server::serve(|_| panic!("invalid requests shouldn't be sent")).await;
+        let text_synth = test_utils::text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(EngineDefinition::GptJ6B);
+        let builder = engine.translate(vec!["hello".into()], Language::French);
+        assert_eq!(
+            builder.validate(),
+            Err(vec![ValidationError::Unsupported("translate")])
+        );
+
+        let error = builder
+            .clone()
+            .now()
+            .await
+            .expect("network error")
+            .expect_err("expected a validation error");
+        assert_eq!(error.status_code(), reqwest::StatusCode::BAD_REQUEST);
+        assert_eq!(error.message(), "the engine doesn't support `translate`");
+
+        let segments = builder.stream().collect::<Vec<_>>().await;
+        assert_eq!(segments.len(), 1);
+        assert!(matches!(&segments[0], Ok(Err(error)) if error.status_code() == 400));
+    }
+}
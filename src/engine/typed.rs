@@ -0,0 +1,326 @@
+//! A text completion builder which rejects invalid combinations of parameters at compile time,
+//! using the typestate pattern.
+//!
+//! [`TypedTextCompletionBuilder`] wraps a [`TextCompletionBuilder`] and tracks two states in its
+//! type:
+//!   - the structured output constraint, which is [`Unconstrained`] until either a [`Schema`] or a
+//!     [`Grammar`] is set, after which the other can't be set anymore,
+//!   - the mode, which is [`Once`] until [`TypedTextCompletionBuilder::streaming`] is called, after
+//!     which only [`Streaming`] parameters such as the framing are available.
+//!
+//! In return, [`TypedTextCompletionBuilder::json`] is only available once a schema is set, since the
+//! generated text otherwise likely isn't valid JSON.
+//!
+//! # Tradeoffs
+//! The states are part of the type, so a builder can't be stored in a field or returned from
+//! different branches without naming them, and parameters can't be set conditionally when doing so
+//! changes the state. Only the most common parameters are available; use
+//! [`TypedTextCompletionBuilder::into_inner`] to reach the others. Parameter values, such as the
+//! temperature, are still validated at runtime. Prefer [`TextCompletionBuilder`] unless catching
+//! these mistakes at compile time is worth it.
+
+use crate::engine::definition::EngineDefinition;
+use crate::engine::framing::StreamFraming;
+use crate::engine::text_completion::{
+    MaxTokens, Stop, TextCompletion, TextCompletionBuilder, TextCompletionStream, TopK, TopP,
+};
+use crate::engine::tokenize::TokenId;
+use crate::engine::Engine;
+use crate::error::ValidationError;
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// The structured output constraint state of a [`TypedTextCompletionBuilder`].
+pub trait Constraint: private::Sealed {}
+
+/// The mode state of a [`TypedTextCompletionBuilder`].
+pub trait Mode: private::Sealed {}
+
+/// No structured output constraint is set yet.
+#[derive(Debug, Copy, Clone)]
+pub enum Unconstrained {}
+
+/// The generated text is constrained to a JSON schema.
+#[derive(Debug, Copy, Clone)]
+pub enum Schema {}
+
+/// The generated text is constrained to a grammar.
+#[derive(Debug, Copy, Clone)]
+pub enum Grammar {}
+
+/// The text completion is generated at once.
+#[derive(Debug, Copy, Clone)]
+pub enum Once {}
+
+/// The text completion is streamed.
+#[derive(Debug, Copy, Clone)]
+pub enum Streaming {}
+
+impl private::Sealed for Unconstrained {}
+impl private::Sealed for Schema {}
+impl private::Sealed for Grammar {}
+impl private::Sealed for Once {}
+impl private::Sealed for Streaming {}
+
+impl Constraint for Unconstrained {}
+impl Constraint for Schema {}
+impl Constraint for Grammar {}
+impl Mode for Once {}
+impl Mode for Streaming {}
+
+/// A text completion builder tracking its state in its type. See the [module level
+/// documentation](self).
+#[must_use = "builders do nothing unless the request is sent"]
+pub struct TypedTextCompletionBuilder<'ts, 'e, C = Unconstrained, M = Once> {
+    inner: TextCompletionBuilder<'ts, 'e>,
+    _state: PhantomData<(C, M)>,
+}
+
+impl<C, M> Clone for TypedTextCompletionBuilder<'_, '_, C, M> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            _state: PhantomData,
+        }
+    }
+}
+
+impl<'ts, 'e> TypedTextCompletionBuilder<'ts, 'e> {
+    /// Create a new typed text completion builder.
+    pub const fn new(engine: &'e Engine<'ts>, prompt: String) -> Self {
+        Self {
+            inner: TextCompletionBuilder::new(engine, prompt),
+            _state: PhantomData,
+        }
+    }
+}
+
+impl<'ts, 'e, C: Constraint, M: Mode> TypedTextCompletionBuilder<'ts, 'e, C, M> {
+    fn map<C2, M2>(
+        self,
+        f: impl FnOnce(TextCompletionBuilder<'ts, 'e>) -> TextCompletionBuilder<'ts, 'e>,
+    ) -> TypedTextCompletionBuilder<'ts, 'e, C2, M2> {
+        TypedTextCompletionBuilder {
+            inner: f(self.inner),
+            _state: PhantomData,
+        }
+    }
+
+    /// Take the underlying [`TextCompletionBuilder`], to set parameters which aren't available
+    /// here. Doing so gives up the compile time checks.
+    pub fn into_inner(self) -> TextCompletionBuilder<'ts, 'e> {
+        self.inner
+    }
+
+    /// See [`TextCompletionBuilder::on_engine`].
+    pub fn on_engine(self, definition: EngineDefinition) -> Self {
+        self.map(|inner| inner.on_engine(definition))
+    }
+
+    /// See [`TextCompletionBuilder::max_tokens`].
+    pub fn max_tokens(self, max_tokens: MaxTokens) -> Self {
+        self.map(|inner| inner.max_tokens(max_tokens))
+    }
+
+    /// See [`TextCompletionBuilder::temperature`].
+    pub fn temperature(self, temperature: f64) -> Self {
+        self.map(|inner| inner.temperature(temperature))
+    }
+
+    /// See [`TextCompletionBuilder::top_k`].
+    pub fn top_k(self, top_k: TopK) -> Self {
+        self.map(|inner| inner.top_k(top_k))
+    }
+
+    /// See [`TextCompletionBuilder::top_p`].
+    pub fn top_p(self, top_p: TopP) -> Self {
+        self.map(|inner| inner.top_p(top_p))
+    }
+
+    /// See [`TextCompletionBuilder::stop`].
+    pub fn stop(self, stop: Stop) -> Self {
+        self.map(|inner| inner.stop(stop))
+    }
+
+    /// See [`TextCompletionBuilder::logit_bias`].
+    pub fn logit_bias(self, token: TokenId, bias: f64) -> Self {
+        self.map(|inner| inner.logit_bias(token, bias))
+    }
+
+    /// See [`TextCompletionBuilder::echo`].
+    pub fn echo(self, echo: bool) -> Self {
+        self.map(|inner| inner.echo(echo))
+    }
+
+    /// See [`TextCompletionBuilder::logprobs`].
+    pub fn logprobs(self, logprobs: bool) -> Self {
+        self.map(|inner| inner.logprobs(logprobs))
+    }
+
+    /// See [`TextCompletionBuilder::logprobs_top_n`].
+    pub fn logprobs_top_n(self, logprobs_top_n: usize) -> Self {
+        self.map(|inner| inner.logprobs_top_n(logprobs_top_n))
+    }
+
+    /// See [`TextCompletionBuilder::map_text`].
+    pub fn map_text(self, map_text: impl Fn(String) -> String + Send + Sync + 'static) -> Self {
+        self.map(|inner| inner.map_text(map_text))
+    }
+
+    /// See [`TextCompletionBuilder::validate`].
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        self.inner.validate()
+    }
+
+    /// See [`TextCompletionBuilder::dry_run`].
+    pub fn dry_run(self) -> Result<serde_json::Value, Vec<ValidationError>> {
+        self.inner.dry_run()
+    }
+}
+
+impl<'ts, 'e, M: Mode> TypedTextCompletionBuilder<'ts, 'e, Unconstrained, M> {
+    /// See [`TextCompletionBuilder::schema`]. A grammar can't be set afterwards.
+    pub fn schema(
+        self,
+        schema: serde_json::Value,
+    ) -> TypedTextCompletionBuilder<'ts, 'e, Schema, M> {
+        self.map(|inner| inner.schema(schema))
+    }
+
+    /// See [`TextCompletionBuilder::grammar`]. A schema can't be set afterwards.
+    pub fn grammar(
+        self,
+        grammar: impl Into<String>,
+    ) -> TypedTextCompletionBuilder<'ts, 'e, Grammar, M> {
+        self.map(|inner| inner.grammar(grammar))
+    }
+}
+
+impl<'ts, 'e, C: Constraint> TypedTextCompletionBuilder<'ts, 'e, C, Once> {
+    /// Stream the text completion instead of generating it at once.
+    pub fn streaming(self) -> TypedTextCompletionBuilder<'ts, 'e, C, Streaming> {
+        self.map(|inner| inner)
+    }
+
+    /// See [`TextCompletionBuilder::now`].
+    pub async fn now(self) -> reqwest::Result<crate::Result<TextCompletion>> {
+        self.inner.now().await
+    }
+
+    /// See [`TextCompletionBuilder::now_with_raw`].
+    pub async fn now_with_raw(
+        self,
+    ) -> reqwest::Result<crate::Result<(TextCompletion, serde_json::Value)>> {
+        self.inner.now_with_raw().await
+    }
+}
+
+impl TypedTextCompletionBuilder<'_, '_, Unconstrained, Once> {
+    /// See [`TextCompletionBuilder::json_object`]. Only available while no structured output
+    /// constraint is set, since the schema is derived from `T`.
+    #[cfg(feature = "schemars")]
+    pub async fn json_object<T: schemars::JsonSchema + DeserializeOwned>(
+        self,
+    ) -> reqwest::Result<crate::Result<serde_json::Result<T>>> {
+        self.inner.json_object().await
+    }
+}
+
+impl TypedTextCompletionBuilder<'_, '_, Schema, Once> {
+    /// See [`TextCompletionBuilder::json`]. Only available once a schema is set.
+    pub async fn json<T: DeserializeOwned>(
+        self,
+    ) -> reqwest::Result<crate::Result<serde_json::Result<T>>> {
+        self.inner.json().await
+    }
+}
+
+impl<'ts, 'e, C: Constraint> TypedTextCompletionBuilder<'ts, 'e, C, Streaming> {
+    /// See [`TextCompletionBuilder::framing`]. Only available when streaming, since it doesn't
+    /// apply otherwise.
+    pub fn framing(self, framing: StreamFraming) -> Self {
+        self.map(|inner| inner.framing(framing))
+    }
+
+    /// See [`TextCompletionBuilder::stream`].
+    pub async fn stream(self) -> reqwest::Result<impl TextCompletionStream> {
+        self.inner.stream().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::server::{self, Response};
+    use crate::test_utils::text_synth;
+    use futures::StreamExt;
+    use serde::Deserialize;
+
+    #[test]
+    fn test_typed_text_completion_builder_dry_run() {
+        let engine = text_synth::engine();
+        let request = TypedTextCompletionBuilder::new(engine, "Is the sky blue?".into())
+            .temperature(0.5)
+            .grammar(r#"root ::= "yes" | "no""#)
+            .streaming()
+            .framing(StreamFraming::JsonLines)
+            .dry_run()
+            .unwrap();
+        assert_eq!(request["temperature"], 0.5);
+        assert_eq!(request["grammar"], r#"root ::= "yes" | "no""#);
+        assert!(request.get("schema").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_typed_text_completion_builder_json() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Answer {
+            answer: bool,
+        }
+
+        let base_url = server::serve(|request| {
+            assert!(request.json().get("schema").is_some());
+            Response::json(
+                200,
+                r#"{"text": "{\"answer\": true}", "reached_end": true}"#,
+            )
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let answer = engine
+            .typed_text_completion("Is the sky blue?")
+            .schema(serde_json::json!({ "type": "object" }))
+            .json::<Answer>()
+            .await
+            .expect("network error")
+            .expect("api error")
+            .expect("invalid json");
+        assert_eq!(answer, Answer { answer: true });
+    }
+
+    #[tokio::test]
+    async fn test_typed_text_completion_builder_stream() {
+        let base_url = server::serve(|request| {
+            assert_eq!(request.json()["stream"], true);
+            Response::chunked(200, server::completion_chunks(&["a", "b"]))
+        })
+        .await;
+        let text_synth = text_synth::with_base_url(&base_url);
+        let engine = text_synth.engine(text_synth::ENGINE_DEFINITION);
+        let texts = engine
+            .typed_text_completion("fn main() {")
+            .streaming()
+            .stream()
+            .await
+            .expect("network error")
+            .map(|result| result.unwrap().unwrap().unwrap().text().to_owned())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(texts, ["a", "b"]);
+    }
+}
@@ -2,8 +2,10 @@
 use once_cell::sync::OnceCell;
 use reqwest::StatusCode;
 use serde::Deserialize;
+use std::borrow::Cow;
 use std::error::Error as StdError;
 use std::fmt;
+use std::io;
 use std::num::NonZeroU16;
 
 /// Handy wrapper against [`Error`]s.
@@ -11,20 +13,113 @@ pub type Result<T, E = Error> = std::result::Result<T, E>;
 pub(crate) type UntaggedResult<T, E = Error> = crate::utils::UntaggedResult<T, E>;
 
 /// Bad things that could happen when calling the `textsynth` API.
+///
+/// Deserialization is lenient: the message may be found in either an `error` or a `message` field,
+/// and if the body lacks a `status`, the HTTP status code of the response is used instead.
 #[derive(Clone, Eq, PartialEq, Deserialize)]
 pub struct Error {
-    status: NonZeroU16,
+    #[serde(default, deserialize_with = "crate::utils::lenient_status")]
+    status: Option<NonZeroU16>,
+
+    #[serde(alias = "message")]
     error: String,
 
     #[serde(skip)]
     status_code: OnceCell<StatusCode>,
+
+    #[serde(skip)]
+    cancelled: bool,
+
+    #[serde(skip)]
+    body: Option<String>,
 }
 
 impl Error {
+    /// The error of a request which was aborted through an
+    /// [`AbortRegistry`](crate::abort::AbortRegistry).
+    pub(crate) fn cancelled() -> Self {
+        Self {
+            status: NonZeroU16::new(499),
+            error: "the request was cancelled".into(),
+            status_code: OnceCell::new(),
+            cancelled: true,
+            body: None,
+        }
+    }
+
+    /// The error of a response whose body wasn't valid JSON or didn't have the expected shape,
+    /// keeping the body for [`Self::body`].
+    pub(crate) fn invalid_json(error: serde_json::Error, body: impl Into<String>) -> Self {
+        Self {
+            status: NonZeroU16::new(StatusCode::BAD_GATEWAY.as_u16()),
+            error: format!("the api returned unexpected json: {error}"),
+            status_code: OnceCell::new(),
+            cancelled: false,
+            body: Some(body.into()),
+        }
+    }
+
+    /// The error of a text completion whose prompt was truncated, when
+    /// [`TextCompletionBuilder::error_on_truncation`] is set. Reported like a validation error, as a
+    /// `400 Bad Request`.
+    ///
+    /// [`TextCompletionBuilder::error_on_truncation`]:
+    /// crate::engine::text_completion::TextCompletionBuilder::error_on_truncation
+    pub(crate) fn truncated_prompt() -> Self {
+        Self {
+            status: NonZeroU16::new(400),
+            error: "the prompt was truncated to fit in the context of the engine".into(),
+            status_code: OnceCell::new(),
+            cancelled: false,
+            body: None,
+        }
+    }
+
+    /// The error of an engine which the server doesn't list, reported as a `404 Not Found`.
+    pub(crate) fn engine_not_found(id: &str) -> Self {
+        Self {
+            status: NonZeroU16::new(404),
+            error: format!("the engine `{id}` isn't listed by the server"),
+            status_code: OnceCell::new(),
+            cancelled: false,
+            body: None,
+        }
+    }
+
+    /// The error of an engine which the server lists with a maximum amount of tokens of zero, so
+    /// that it can't be used, reported as a `502 Bad Gateway`.
+    pub(crate) fn invalid_engine(id: &str) -> Self {
+        Self {
+            status: NonZeroU16::new(StatusCode::BAD_GATEWAY.as_u16()),
+            error: format!("the engine `{id}` is listed by the server with a max_tokens of zero"),
+            status_code: OnceCell::new(),
+            cancelled: false,
+            body: None,
+        }
+    }
+
+    /// Use the given status code if the body of this error didn't carry one itself.
+    ///
+    /// Success status codes are ignored, since some gateways return errors with a `200 OK`
+    /// status, which doesn't describe the error.
+    pub(crate) fn or_status(mut self, status_code: StatusCode) -> Self {
+        if self.status.is_none() && !status_code.is_success() {
+            self.status = NonZeroU16::new(status_code.as_u16());
+        }
+
+        self
+    }
+
     /// Returns the HTTP status code associated with this error.
+    ///
+    /// If neither the error body nor the response carried a status code, this is
+    /// [`StatusCode::INTERNAL_SERVER_ERROR`].
     pub fn status_code(&self) -> StatusCode {
-        *self.status_code.get_or_init(|| {
-            StatusCode::from_u16(self.status.get()).expect("invalid status code from error")
+        *self.status_code.get_or_init(|| match self.status {
+            Some(status) => {
+                StatusCode::from_u16(status.get()).expect("invalid status code from error")
+            }
+            None => StatusCode::INTERNAL_SERVER_ERROR,
         })
     }
 
@@ -32,6 +127,28 @@ impl Error {
     pub fn message(&self) -> &str {
         &self.error
     }
+
+    /// The raw body of the response, if this error was caused by a body which wasn't valid JSON
+    /// or didn't have the expected shape, in which case the status code is 502. The message then
+    /// describes where deserializing the body failed.
+    pub fn body(&self) -> Option<&str> {
+        self.body.as_deref()
+    }
+
+    /// Whether the request was aborted client side through an
+    /// [`AbortRegistry`](crate::abort::AbortRegistry), rather than failing on the server. The
+    /// status code of such errors is 499.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// Whether this error was caused by the account running out of credits, in which case the
+    /// account needs to be topped up. See [`TextSynth::credits`] to get the remaining balance.
+    ///
+    /// [`TextSynth::credits`]: crate::core::TextSynth::credits
+    pub fn is_out_of_credits(&self) -> bool {
+        self.status_code() == StatusCode::PAYMENT_REQUIRED
+    }
 }
 
 impl fmt::Display for Error {
@@ -53,6 +170,254 @@ impl fmt::Debug for Error {
 
 impl StdError for Error {}
 
+/// Any error which could happen when calling the `textsynth` API, flattening the nested results
+/// returned by this crate into a single type.
+#[derive(Debug)]
+pub enum ApiError {
+    /// Connecting to the API failed on the network level.
+    Network(reqwest::Error),
+
+    /// The API returned invalid JSON, or serializing JSON failed.
+    Json(serde_json::Error),
+
+    /// The API returned an error.
+    Api(Error),
+
+    /// Writing the results somewhere, such as with
+    /// [`TextCompletionBuilder::stream_to_ndjson`], failed.
+    ///
+    /// [`TextCompletionBuilder::stream_to_ndjson`]: crate::engine::text_completion::TextCompletionBuilder::stream_to_ndjson
+    Io(io::Error),
+}
+
+impl ApiError {
+    pub(crate) fn flatten<T>(result: reqwest::Result<Result<T>>) -> Result<T, Self> {
+        Ok(result??)
+    }
+
+    pub(crate) fn flatten_stream<T>(
+        result: reqwest::Result<serde_json::Result<Result<T>>>,
+    ) -> Result<T, Self> {
+        Ok(result???)
+    }
+}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(error: reqwest::Error) -> Self {
+        Self::Network(error)
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(error: serde_json::Error) -> Self {
+        Self::Json(error)
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(error: Error) -> Self {
+        Self::Api(error)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Network(error) => write!(f, "failed to connect to the textsynth api: {error}"),
+            Self::Json(error) => write!(f, "invalid json: {error}"),
+            Self::Api(error) => write!(f, "the textsynth api returned an error: {error}"),
+            Self::Io(error) => write!(f, "failed to write the results: {error}"),
+        }
+    }
+}
+
+impl StdError for ApiError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Self::Network(error) => Some(error),
+            Self::Json(error) => Some(error),
+            Self::Api(error) => Some(error),
+            Self::Io(error) => Some(error),
+        }
+    }
+}
+
+/// A parameter was outside of its valid range.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OutOfRangeError {
+    parameter: &'static str,
+    range: Cow<'static, str>,
+    value: String,
+}
+
+impl OutOfRangeError {
+    pub(crate) fn new(
+        parameter: &'static str,
+        range: impl Into<Cow<'static, str>>,
+        value: impl fmt::Display,
+    ) -> Self {
+        Self {
+            parameter,
+            range: range.into(),
+            value: value.to_string(),
+        }
+    }
+
+    /// Returns the name of the parameter.
+    pub const fn parameter(&self) -> &'static str {
+        self.parameter
+    }
+
+    /// Returns a description of the valid range of the parameter.
+    pub fn range(&self) -> &str {
+        &self.range
+    }
+}
+
+impl fmt::Display for OutOfRangeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Self {
+            parameter,
+            range,
+            value,
+        } = self;
+        write!(f, "`{parameter}` must be {range}, got {value}")
+    }
+}
+
+impl StdError for OutOfRangeError {}
+
+/// A request which would be rejected by the API, caught client side before sending it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ValidationError {
+    /// A parameter was outside of its valid range.
+    OutOfRange(OutOfRangeError),
+
+    /// A stop sequence was empty.
+    EmptyStopSequence,
+
+    /// Both a grammar and a JSON schema were set, while at most one structured output constraint
+    /// may be active.
+    GrammarAndSchema,
+
+    /// The prompt was given as token ids, but there were none.
+    EmptyPromptTokens,
+
+    /// A log probabilities request was sent without a continuation.
+    MissingContinuation,
+
+    /// The engine doesn't support the endpoint, such as `translate`, according to its
+    /// [capabilities](crate::engine::definition::EngineDefinition::capabilities).
+    Unsupported(&'static str),
+
+    /// A bias was set for the End-Of-Text token, but the engine doesn't know its id, see
+    /// [`EngineDefinition::eot_token`](crate::engine::definition::EngineDefinition::eot_token).
+    UnknownEndToken,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::OutOfRange(error) => write!(f, "{error}"),
+            Self::EmptyStopSequence => write!(f, "stop sequences must not be empty"),
+            Self::GrammarAndSchema => write!(f, "`grammar` and `schema` must not both be set"),
+            Self::EmptyPromptTokens => write!(f, "the prompt token ids must not be empty"),
+            Self::MissingContinuation => write!(f, "`continuation` must be set"),
+            Self::Unsupported(endpoint) => write!(f, "the engine doesn't support `{endpoint}`"),
+            Self::UnknownEndToken => write!(f, "the end-of-text token of the engine is unknown"),
+        }
+    }
+}
+
+impl StdError for ValidationError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Self::OutOfRange(error) => Some(error),
+            Self::EmptyStopSequence
+            | Self::GrammarAndSchema
+            | Self::EmptyPromptTokens
+            | Self::MissingContinuation
+            | Self::Unsupported(_)
+            | Self::UnknownEndToken => None,
+        }
+    }
+}
+
+impl From<OutOfRangeError> for ValidationError {
+    fn from(error: OutOfRangeError) -> Self {
+        Self::OutOfRange(error)
+    }
+}
+
+impl From<ValidationError> for Error {
+    /// A validation error is reported like the API would, as a `400 Bad Request`.
+    fn from(error: ValidationError) -> Self {
+        Self {
+            status: NonZeroU16::new(400),
+            error: error.to_string(),
+            status_code: OnceCell::new(),
+            cancelled: false,
+            body: None,
+        }
+    }
+}
+
+impl From<Vec<ValidationError>> for Error {
+    /// All validation errors are reported in a single `400 Bad Request`, separated by semicolons.
+    fn from(errors: Vec<ValidationError>) -> Self {
+        let error = errors
+            .iter()
+            .map(ValidationError::to_string)
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        Self {
+            status: NonZeroU16::new(400),
+            error,
+            status_code: OnceCell::new(),
+            cancelled: false,
+            body: None,
+        }
+    }
+}
+
+/// An error which occurred while creating a [`TextSynth`] instance from environment variables.
+///
+/// [`TextSynth`]: crate::core::TextSynth
+#[derive(Debug)]
+pub enum FromEnvError {
+    /// The environment variable with the given name, which holds the api key, is missing or isn't
+    /// valid unicode.
+    MissingApiKey(&'static str),
+
+    /// Creating the underlying [`reqwest::Client`] failed.
+    Client(reqwest::Error),
+}
+
+impl fmt::Display for FromEnvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MissingApiKey(name) => write!(f, "the `{name}` environment variable is not set"),
+            Self::Client(error) => write!(f, "failed to create a new `reqwest::Client`: {error}"),
+        }
+    }
+}
+
+impl StdError for FromEnvError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Self::MissingApiKey(_) => None,
+            Self::Client(error) => Some(error),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -60,9 +425,11 @@ mod tests {
     use std::ops::Deref;
 
     static ERROR: Lazy<Error> = Lazy::new(|| Error {
-        status: NonZeroU16::new(400).unwrap(),
+        status: NonZeroU16::new(400),
         error: "Bad Request".to_string(),
         status_code: OnceCell::new(),
+        cancelled: false,
+        body: None,
     });
 
     #[test]
@@ -87,4 +454,114 @@ mod tests {
     fn test_message() {
         let _ = ERROR.message();
     }
+
+    #[test]
+    fn test_is_cancelled() {
+        assert!(!ERROR.is_cancelled());
+        assert!(Error::cancelled().is_cancelled());
+        assert_eq!(Error::cancelled().status_code().as_u16(), 499);
+    }
+
+    #[test]
+    fn test_is_out_of_credits() {
+        assert!(!ERROR.is_out_of_credits());
+
+        let error: Error =
+            serde_json::from_str(r#"{"status": 402, "error": "not enough credits"}"#).unwrap();
+        assert!(error.is_out_of_credits());
+    }
+
+    #[test]
+    fn test_api_error_flatten() {
+        assert_eq!(ApiError::flatten::<u8>(Ok(Ok(42))).unwrap(), 42);
+        assert!(matches!(
+            ApiError::flatten::<u8>(Ok(Err(ERROR.clone()))),
+            Err(ApiError::Api(_))
+        ));
+        assert_eq!(ApiError::flatten_stream::<u8>(Ok(Ok(Ok(42)))).unwrap(), 42);
+        assert!(matches!(
+            ApiError::flatten_stream::<u8>(Ok(serde_json::from_str("{"))),
+            Err(ApiError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn test_api_error_display() {
+        assert_eq!(
+            ApiError::Api(ERROR.clone()).to_string(),
+            "the textsynth api returned an error: 400 Bad Request, Bad Request"
+        );
+    }
+
+    #[test]
+    fn test_out_of_range_error_display() {
+        let error = OutOfRangeError::new("top_p", "between 0.0 and 1.0 inclusive", 1.5);
+        assert_eq!(
+            error.to_string(),
+            "`top_p` must be between 0.0 and 1.0 inclusive, got 1.5"
+        );
+    }
+
+    #[test]
+    fn test_error_deserialize_lenient_status() {
+        for status in ["400", "400.0", "\"400\""] {
+            let json = format!(r#"{{"status": {status}, "error": "Bad Request"}}"#);
+            let error: Error = serde_json::from_str(&json).unwrap();
+            assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+        }
+
+        for status in ["0", "99", "1000", "65535.0", "\"42\""] {
+            let json = format!(r#"{{"status": {status}, "error": ""}}"#);
+            assert!(serde_json::from_str::<Error>(&json).is_err());
+        }
+    }
+
+    #[test]
+    fn test_error_deserialize_message_alias() {
+        let error: Error =
+            serde_json::from_str(r#"{"status": 400, "message": "Bad Request"}"#).unwrap();
+        assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.message(), "Bad Request");
+    }
+
+    #[test]
+    fn test_error_deserialize_missing_status() {
+        let error: Error = serde_json::from_str(r#"{"error": "Unauthorized"}"#).unwrap();
+        assert_eq!(error.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let error: Error = serde_json::from_str::<Error>(r#"{"error": "Unauthorized"}"#)
+            .unwrap()
+            .or_status(StatusCode::UNAUTHORIZED);
+        assert_eq!(error.status_code(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_untagged_result_prefers_error() {
+        // any body is a valid `Value`, so the error must be tried first
+        let result: Result<serde_json::Value> =
+            serde_json::from_str::<UntaggedResult<_>>(r#"{"error": "Bad Gateway"}"#)
+                .unwrap()
+                .into();
+        assert_eq!(result.unwrap_err().message(), "Bad Gateway");
+
+        let result: Result<serde_json::Value> =
+            serde_json::from_str::<UntaggedResult<_>>(r#"{"credits": 42}"#)
+                .unwrap()
+                .into();
+        assert_eq!(result.unwrap(), serde_json::json!({ "credits": 42 }));
+    }
+
+    #[test]
+    fn test_error_or_status_ignores_success() {
+        let error = serde_json::from_str::<Error>(r#"{"error": "Bad Gateway"}"#)
+            .unwrap()
+            .or_status(StatusCode::OK);
+        assert_eq!(error.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_error_or_status_keeps_body_status() {
+        let error = ERROR.clone().or_status(StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+    }
 }
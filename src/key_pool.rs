@@ -0,0 +1,56 @@
+//! A pool of api keys used in turn.
+
+use crate::utils::Redacted;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A pool of api keys which hands them out round-robin, so that consecutive requests use
+/// consecutive keys.
+pub(crate) struct KeyPool {
+    keys: Vec<String>,
+    next: AtomicUsize,
+}
+
+impl fmt::Debug for KeyPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeyPool")
+            .field("keys", &vec![Redacted; self.keys.len()])
+            .field("next", &self.next)
+            .finish()
+    }
+}
+
+impl KeyPool {
+    pub(crate) fn new(keys: Vec<String>) -> Self {
+        assert!(!keys.is_empty(), "a key pool must have at least one key");
+
+        Self {
+            keys,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Take the next key in turn.
+    pub(crate) fn next(&self) -> &str {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.keys.len();
+        &self.keys[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_pool_round_robin() {
+        let pool = KeyPool::new(vec!["a".into(), "b".into(), "c".into()]);
+        let keys = (0..4).map(|_| pool.next()).collect::<Vec<_>>();
+        assert_eq!(keys, ["a", "b", "c", "a"]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_key_pool_empty() {
+        let _ = KeyPool::new(Vec::new());
+    }
+}
@@ -1,11 +1,22 @@
 #![doc = include_str!("../README.md")]
 #![warn(missing_docs)]
 
+pub mod abort;
+#[cfg(feature = "anyhow")]
+pub mod anyhow_ext;
+pub mod clock;
 pub mod core;
+#[cfg(feature = "debug-bodies")]
+mod debug_bodies;
 pub mod engine;
 pub mod error;
+mod key_pool;
 pub mod prelude;
+mod rate_limit;
 mod utils;
+#[cfg(feature = "vcr")]
+pub mod vcr;
+mod wire;
 
 #[cfg(test)]
 mod test_utils;
@@ -1,17 +1,34 @@
 //! Most commonly used traits and types.
 
 pub use crate::{
-    core::TextSynth,
+    abort::AbortRegistry,
+    core::{
+        AuthScheme, OversizedRequestHook, RateLimitWaitHook, RequestCustomizer, RetryHook,
+        TextSynth, TextSynthBuilder, TextSynthConfig,
+    },
     engine::{
+        compare::Comparison,
+        cost::{CostModel, PricingTable},
         definition::{
-            Boris6B, CustomEngineDefinition, EngineDefinition, FairseqGpt13B, GptJ6B,
-            KnownEngineDefinition,
+            Boris6B, Capabilities, CustomEngineDefinition, EngineDefinition, EngineInfo,
+            FairseqGpt13B, GptJ6B, KnownEngineDefinition,
+        },
+        framing::StreamFraming,
+        log_probabilities::{LogProbabilities, LogProbabilitiesBuilder, NonEmptyString},
+        stream::{
+            ProgressStreamResult, SentenceStreamResult, StatsStreamItem, StreamResultExt,
+            StreamStats, StreamTranscript, TranscriptChunk,
         },
-        log_probabilities::{LogProbabilities, NonEmptyString},
         text_completion::{
-            MaxTokens, Stop, TextCompletion, TextCompletionBuilder, TextCompletionStream,
-            TextCompletionStreamResult, TopK, TopP,
+            ClientStop, LogProb, MaxTokens, Stop, TextCompletion, TextCompletionBuilder,
+            TextCompletionStream, TextCompletionStreamResult, TextMapper, TopK, TopP,
         },
+        tokenize::{BudgetReport, TokenId},
+        translate::{Language, TranslateBuilder, Translation, TranslationSegment},
+        typed::TypedTextCompletionBuilder,
         Engine,
     },
 };
+
+#[cfg(feature = "anyhow")]
+pub use crate::anyhow_ext::IntoAnyhow;
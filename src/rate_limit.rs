@@ -0,0 +1,167 @@
+//! A token bucket used to limit the rate of requests.
+
+use crate::clock::Clock;
+use std::mem;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+
+    /// [`None`] until the first request, since the clock isn't known up front.
+    last_refill: Option<Instant>,
+}
+
+/// A token bucket which refills at `requests_per_second` and holds up to `requests_per_second`
+/// tokens (but at least one), so a burst of up to that many requests goes through immediately
+/// before requests are spaced out evenly.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    requests_per_second: f64,
+    capacity: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(requests_per_second: f64) -> Self {
+        assert!(
+            requests_per_second.is_finite() && requests_per_second > 0.0,
+            "requests per second must be finite and positive"
+        );
+
+        let capacity = requests_per_second.max(1.0);
+
+        Self {
+            requests_per_second,
+            capacity,
+            bucket: Mutex::new(Bucket {
+                tokens: capacity,
+                last_refill: None,
+            }),
+        }
+    }
+
+    /// Take a token from the bucket, returning how long to wait until the token is actually
+    /// available. Tokens may be taken in advance, which queues up callers in order.
+    fn reserve(&self, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().expect("rate limiter lock poisoned");
+        let elapsed = bucket.last_refill.map_or(0.0, |last_refill| {
+            now.duration_since(last_refill).as_secs_f64()
+        });
+
+        bucket.tokens = (bucket.tokens + elapsed * self.requests_per_second).min(self.capacity);
+        bucket.last_refill = Some(now);
+        bucket.tokens -= 1.0;
+
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / self.requests_per_second)
+        }
+    }
+
+    /// Put back a token taken by [`Self::reserve`] which won't be used.
+    fn unreserve(&self) {
+        let mut bucket = self.bucket.lock().expect("rate limiter lock poisoned");
+        bucket.tokens = (bucket.tokens + 1.0).min(self.capacity);
+    }
+
+    /// Wait until a request is allowed to be made, returning how long was waited.
+    ///
+    /// If this is cancelled while waiting, the token is put back, so that it doesn't delay later
+    /// requests.
+    pub(crate) async fn acquire(&self, clock: &dyn Clock) -> Duration {
+        let delay = self.reserve(clock.now());
+
+        if !delay.is_zero() {
+            let reservation = Reservation(self);
+            clock.sleep(delay).await;
+            mem::forget(reservation);
+        }
+
+        delay
+    }
+}
+
+/// Puts back a reserved token when dropped, unless it's forgotten once the token is used.
+struct Reservation<'a>(&'a RateLimiter);
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.0.unreserve();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use futures::FutureExt;
+
+    #[test]
+    fn test_rate_limiter_burst() {
+        let rate_limiter = RateLimiter::new(2.0);
+        let now = Instant::now();
+        assert_eq!(rate_limiter.reserve(now), Duration::ZERO);
+        assert_eq!(rate_limiter.reserve(now), Duration::ZERO);
+        assert_eq!(rate_limiter.reserve(now), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_rate_limiter_fractional_rate() {
+        let rate_limiter = RateLimiter::new(0.5);
+        let now = Instant::now();
+        assert_eq!(rate_limiter.reserve(now), Duration::ZERO);
+        assert_eq!(rate_limiter.reserve(now), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_rate_limiter_refill() {
+        let rate_limiter = RateLimiter::new(1.0);
+        let now = Instant::now();
+        assert_eq!(rate_limiter.reserve(now), Duration::ZERO);
+        assert_eq!(
+            rate_limiter.reserve(now + Duration::from_secs(1)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter_acquire() {
+        let clock = MockClock::new();
+        let rate_limiter = RateLimiter::new(1.0);
+        assert_eq!(
+            rate_limiter.acquire(&clock).now_or_never(),
+            Some(Duration::ZERO)
+        );
+
+        let mut acquire = Box::pin(rate_limiter.acquire(&clock));
+        assert!((&mut acquire).now_or_never().is_none());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(acquire.now_or_never(), Some(Duration::from_secs(1)));
+        assert_eq!(clock.sleeps(), [Duration::from_secs(1)]);
+    }
+
+    #[test]
+    fn test_rate_limiter_cancelled_acquire() {
+        let clock = MockClock::new();
+        let rate_limiter = RateLimiter::new(1.0);
+        assert_eq!(
+            rate_limiter.acquire(&clock).now_or_never(),
+            Some(Duration::ZERO)
+        );
+
+        // the token of a cancelled wait is put back, so the next request waits as long
+        let mut acquire = Box::pin(rate_limiter.acquire(&clock));
+        assert!((&mut acquire).now_or_never().is_none());
+        drop(acquire);
+        assert_eq!(rate_limiter.reserve(clock.now()), Duration::from_secs(1));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_rate_limiter_zero_rate() {
+        let _ = RateLimiter::new(0.0);
+    }
+}
@@ -61,10 +61,7 @@ pub static LOG_PROBABILITIES: Lazy<LogProbabilities> = Lazy::new(|| {
         let textsynth = text_synth::engine();
         let continuation = NonEmptyString::new("dog".into()).unwrap();
         textsynth
-            .log_probabilities(
-                "The quick brown fox jumps over the lazy ".into(),
-                continuation,
-            )
+            .log_probabilities("The quick brown fox jumps over the lazy ", continuation)
             .await
             .expect("network error")
             .expect("api error")
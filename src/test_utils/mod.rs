@@ -2,6 +2,8 @@
 pub mod cache;
 
 pub mod dotenv;
+pub mod server;
+pub mod stream;
 pub mod text_synth;
 
 use once_cell::sync::Lazy;
@@ -9,6 +11,12 @@ use std::env;
 use std::time::Duration;
 
 static API_KEY: Lazy<String> = Lazy::new(|| {
+    // replaying doesn't need credentials, so CI can run the suite without them
+    #[cfg(feature = "vcr")]
+    if text_synth::replaying() {
+        return env::var("API_KEY").unwrap_or_else(|_| "replayed".into());
+    }
+
     dotenv::initialize();
     env::var("API_KEY").expect("pass an api key to run the tests")
 });
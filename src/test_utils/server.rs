@@ -0,0 +1,189 @@
+//! A minimal HTTP server serving canned responses, so that requests can be tested offline.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    #[allow(dead_code)]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[allow(dead_code)]
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).expect("request body isn't json")
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub chunks: Vec<Vec<u8>>,
+    pub hang: bool,
+}
+
+impl Response {
+    pub fn json(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            headers: vec![("content-type".into(), "application/json".into())],
+            chunks: vec![body.into().into_bytes()],
+            hang: false,
+        }
+    }
+
+    /// A response whose body is written in separate chunks, delimited by closing the connection.
+    #[allow(dead_code)]
+    pub fn chunked(status: u16, chunks: Vec<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            chunks,
+            hang: false,
+        }
+    }
+
+    /// Never respond, closing the connection once the client does.
+    #[allow(dead_code)]
+    pub fn hang() -> Self {
+        Self {
+            status: 200,
+            headers: Vec::new(),
+            chunks: Vec::new(),
+            hang: true,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+}
+
+/// The body chunks of a streamed text completion generating the given texts.
+#[allow(dead_code)]
+pub fn completion_chunks(texts: &[&str]) -> Vec<Vec<u8>> {
+    texts
+        .iter()
+        .enumerate()
+        .map(|(index, text)| {
+            let reached_end = index + 1 == texts.len();
+            let mut text_completion = serde_json::json!({
+                "text": text,
+                "reached_end": reached_end,
+            });
+
+            if reached_end {
+                text_completion["total_tokens"] = texts.len().into();
+            }
+
+            format!("{text_completion}\n\n").into_bytes()
+        })
+        .collect()
+}
+
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Option<Request> {
+    let mut line = String::new();
+    stream.read_line(&mut line).await.ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_owned();
+    let path = parts.next()?.to_owned();
+    let mut headers = Vec::new();
+
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.ok()?;
+        let line = line.trim_end();
+
+        if line.is_empty() {
+            break;
+        }
+
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+
+    let content_length = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await.ok()?;
+
+    Some(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+async fn write_response(stream: &mut BufReader<TcpStream>, response: Response) {
+    if response.hang {
+        let _ = stream.read(&mut [0]).await;
+        return;
+    }
+
+    let stream = stream.get_mut();
+    let mut head = format!("HTTP/1.1 {} Mock\r\nconnection: close\r\n", response.status);
+
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+
+    if let [body] = &response.chunks[..] {
+        head.push_str(&format!("content-length: {}\r\n", body.len()));
+    }
+
+    head.push_str("\r\n");
+    let _ = stream.write_all(head.as_bytes()).await;
+
+    for chunk in response.chunks {
+        let _ = stream.write_all(&chunk).await;
+        let _ = stream.flush().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let _ = stream.shutdown().await;
+}
+
+/// Serve every request with the response returned by `handler`, returning the base URL of the
+/// server.
+pub async fn serve(handler: impl Fn(&Request) -> Response + Send + Sync + 'static) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("failed to bind mock server");
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let handler = Arc::new(handler);
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let handler = Arc::clone(&handler);
+
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+
+                if let Some(request) = read_request(&mut stream).await {
+                    let response = handler(&request);
+                    write_response(&mut stream, response).await;
+                }
+            });
+        }
+    });
+
+    base_url
+}
@@ -0,0 +1,40 @@
+use crate::prelude::{TextCompletion, TextCompletionStream};
+use futures::stream;
+
+pub fn text_completion(text: &str, reached_end: bool) -> TextCompletion {
+    let total_tokens = reached_end.then_some(text.len());
+    serde_json::from_value(serde_json::json!({
+        "text": text,
+        "reached_end": reached_end,
+        "total_tokens": total_tokens,
+    }))
+    .expect("invalid text completion")
+}
+
+pub fn from_texts(texts: &[&str]) -> impl TextCompletionStream {
+    let text_completions = texts
+        .iter()
+        .enumerate()
+        .map(|(index, text)| text_completion(text, index + 1 == texts.len()))
+        .map(|text_completion| Ok(Ok(Ok(text_completion))))
+        .collect::<Vec<_>>();
+
+    stream::iter(text_completions)
+}
+
+pub fn from_indexed_texts(texts: &[(usize, &str)]) -> impl TextCompletionStream {
+    let text_completions = texts
+        .iter()
+        .map(|&(index, text)| {
+            serde_json::from_value(serde_json::json!({
+                "text": text,
+                "reached_end": false,
+                "index": index,
+            }))
+            .expect("invalid text completion")
+        })
+        .map(|text_completion: TextCompletion| Ok(Ok(Ok(text_completion))))
+        .collect::<Vec<_>>();
+
+    stream::iter(text_completions)
+}
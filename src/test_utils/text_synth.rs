@@ -7,10 +7,36 @@ pub const ENGINE_DEFINITION: EngineDefinition = EngineDefinition::GptJ6B;
 static TEXT_SYNTH: OnceCell<TextSynth> = OnceCell::new();
 static ENGINE: Lazy<Engine> = Lazy::new(|| get().engine(ENGINE_DEFINITION));
 
+/// The cassette the tests calling the live API record to, or replay from if it exists, with the
+/// `vcr` feature. It isn't committed, so it must be recorded from the live API with an api key.
+#[cfg(feature = "vcr")]
+const CASSETTE_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/cassettes/live.jsonl");
+
+#[cfg(feature = "vcr")]
+pub fn replaying() -> bool {
+    std::path::Path::new(CASSETTE_PATH).exists()
+}
+
 pub fn get() -> &'static TextSynth {
-    TEXT_SYNTH.get_or_init(|| TextSynth::new(super::api_key().into()))
+    TEXT_SYNTH.get_or_init(|| {
+        let text_synth = TextSynth::new(super::api_key());
+
+        #[cfg(feature = "vcr")]
+        let text_synth = text_synth.with_cassette(
+            crate::vcr::Cassette::load_or_record(CASSETTE_PATH).expect("failed to load cassette"),
+        );
+
+        text_synth
+    })
 }
 
 pub fn engine() -> &'static Engine<'static> {
     &ENGINE
 }
+
+pub fn with_base_url(base_url: &str) -> TextSynth {
+    TextSynth::builder(super::api_key())
+        .base_url(base_url)
+        .build()
+        .expect("failed to build textsynth client")
+}
@@ -1,10 +1,45 @@
-use serde::Deserialize;
+use crate::clock::Clock;
+use crate::engine::text_completion::LogProb;
+use bytes::Bytes;
+use futures::future::{self, Either};
+use futures::{stream, StreamExt, TryStream};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Body, Response, ResponseBuilderExt, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::num::NonZeroU16;
+use std::sync::Arc;
+use std::time::Duration;
+use tap::Pipe;
 
+/// What secrets, such as api keys, are replaced with in logs and debug output.
+pub const REDACTED: &str = "<redacted>";
+
+/// Debug formats as [`REDACTED`], to stand in for a secret in [`fmt::Debug`] implementations.
+#[derive(Copy, Clone)]
+pub struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+/// Either a value or an error, deserialized from the same body.
+///
+/// The error is tried first, so that a body of the shape of an error is never mistaken for a value
+/// whose fields are all optional, even if the response had a success status code, as some
+/// gateways return.
 #[derive(Deserialize)]
 #[serde(untagged)]
 pub enum UntaggedResult<T, E> {
-    Ok(T),
     Err(E),
+    Ok(T),
 }
 
 impl<T, E> From<Result<T, E>> for UntaggedResult<T, E> {
@@ -24,3 +59,262 @@ impl<T, E> From<UntaggedResult<T, E>> for Result<T, E> {
         }
     }
 }
+
+/// A response with the given status code and a JSON error body with the given message, for
+/// errors detected client side which are reported like errors returned by the API.
+pub(crate) fn error_response(status_code: StatusCode, message: &str) -> Response {
+    http::Response::builder()
+        .status(status_code)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::json!({ "error": message }).to_string())
+        .expect("invalid error response")
+        .pipe(Response::from)
+}
+
+/// Replace the body of the response with the stream returned by `body`, which is given the
+/// response to read its body from. Its status code, version, headers, URL and extensions, such as
+/// its remote address, are kept.
+pub(crate) fn map_body<S>(mut response: Response, body: impl FnOnce(Response) -> S) -> Response
+where
+    S: TryStream + Send + 'static,
+    S::Error: Into<Box<dyn StdError + Send + Sync>>,
+    Bytes: From<S::Ok>,
+{
+    let mut builder = http::Response::builder()
+        .status(response.status())
+        .version(response.version());
+
+    if let Some(headers) = builder.headers_mut() {
+        headers.extend(response.headers().clone());
+    }
+
+    if let Some(extensions) = builder.extensions_mut() {
+        *extensions = mem::take(response.extensions_mut());
+    }
+
+    builder
+        .url(response.url().clone())
+        .body(Body::wrap_stream(body(response)))
+        .expect("failed to rebuild response")
+        .pipe(Response::from)
+}
+
+/// Fail reading the body of the response with a timeout error, for which
+/// [`reqwest::Error::is_timeout`] is `true`, if no bytes are received for `idle_timeout`, as
+/// measured by `clock`. The response is otherwise unchanged, see [`map_body`], and it can still be
+/// streamed.
+pub(crate) fn with_idle_timeout(
+    response: Response,
+    idle_timeout: Duration,
+    clock: Arc<dyn Clock>,
+) -> Response {
+    map_body(response, move |response| {
+        stream::unfold(Some(Box::pin(response.bytes_stream())), move |body| {
+            let idle = clock.sleep(idle_timeout);
+
+            async move {
+                let mut body = body?;
+
+                match future::select(body.next(), idle).await {
+                    Either::Left((chunk, _)) => {
+                        let chunk = chunk?.map_err(Box::<dyn StdError + Send + Sync>::from);
+                        Some((chunk, Some(body)))
+                    }
+                    Either::Right(_) => {
+                        let error = io::Error::new(io::ErrorKind::TimedOut, "idle timeout elapsed");
+                        Some((Err(error.into()), None))
+                    }
+                }
+            }
+        })
+    })
+}
+
+/// Deserialize JSON from a buffer, with [`simd_json`] if the `simd-json` feature is enabled, whose
+/// errors are then converted to [`serde_json::Error`]s reporting the line and column they occurred
+/// at, like serde_json's own.
+///
+/// simd-json parses in place, so the buffer is only copied if it's shared. Escape sequences in the
+/// strings of an invalid buffer may be decoded in place when the error is returned.
+///
+/// This is meant for whole response bodies. The short records of streams are parsed with
+/// serde_json, since simd-json is no faster on them (see `benches/json.rs`).
+pub(crate) fn from_json_bytes<T: DeserializeOwned>(json: &mut Bytes) -> serde_json::Result<T> {
+    #[cfg(feature = "simd-json")]
+    {
+        let mut buffer = Vec::from(std::mem::take(json));
+        // decoding escape sequences in place can add newlines, so find them beforehand
+        let newlines = buffer
+            .iter()
+            .enumerate()
+            .filter_map(|(index, &byte)| (byte == b'\n').then_some(index))
+            .collect::<Vec<_>>();
+        let result = simd_json::serde::from_slice(&mut buffer)
+            .map_err(|error| simd_json_error(&error, &newlines));
+        *json = Bytes::from(buffer);
+        result
+    }
+
+    #[cfg(not(feature = "simd-json"))]
+    serde_json::from_slice(json)
+}
+
+/// Convert a [`simd_json::Error`] to a [`serde_json::Error`], reporting its byte index as a line and
+/// column given the byte indices of the newlines of the JSON.
+#[cfg(feature = "simd-json")]
+fn simd_json_error(error: &simd_json::Error, newlines: &[usize]) -> serde_json::Error {
+    match error.error() {
+        // raised by the deserialized type, so it has no position
+        simd_json::ErrorType::Serde(message) => serde_json::Error::custom(message),
+        error_type => {
+            let line = newlines.partition_point(|&newline| newline < error.index());
+            let line_start = line.checked_sub(1).map_or(0, |line| newlines[line] + 1);
+            let column = error.index() - line_start + 1;
+            serde_json::Error::custom(format_args!(
+                "{error_type:?} at line {} column {column}",
+                line + 1
+            ))
+        }
+    }
+}
+
+/// Deserialize the body of a response from the API, falling back to the HTTP status code of the
+/// response if an error body lacks its own.
+///
+/// If the body is neither valid JSON of the expected shape nor an error, an error with the
+/// [`StatusCode::BAD_GATEWAY`](reqwest::StatusCode::BAD_GATEWAY) status code carrying the body is
+/// returned, see [`crate::Error::body`].
+pub async fn json<T: DeserializeOwned>(response: Response) -> reqwest::Result<crate::Result<T>> {
+    let status_code = response.status();
+    let mut body = response.bytes().await?;
+
+    let result = match from_json_bytes::<crate::UntaggedResult<T>>(&mut body) {
+        Ok(result) => Result::from(result).map_err(|error| error.or_status(status_code)),
+        Err(error) => Err(crate::Error::invalid_json(
+            error,
+            String::from_utf8_lossy(&body),
+        )),
+    };
+
+    Ok(result)
+}
+
+/// Same as [`json`], but also returns the raw JSON the typed value was deserialized from, without
+/// parsing the body twice.
+///
+/// Invalid bodies are reported like in [`json`].
+pub async fn json_with_raw<T: DeserializeOwned>(
+    response: Response,
+) -> reqwest::Result<crate::Result<(T, serde_json::Value)>> {
+    let status_code = response.status();
+    let mut body = response.bytes().await?;
+    let raw = match from_json_bytes::<serde_json::Value>(&mut body) {
+        Ok(raw) => raw,
+        Err(error) => {
+            let body = String::from_utf8_lossy(&body);
+            return Ok(Err(crate::Error::invalid_json(error, body)));
+        }
+    };
+
+    let result = match crate::UntaggedResult::<T>::deserialize(&raw) {
+        Ok(result) => Result::from(result).map_err(|error| error.or_status(status_code)),
+        Err(error) => Err(crate::Error::invalid_json(error, raw.to_string())),
+    };
+
+    Ok(result.map(|value| (value, raw)))
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LenientNumber {
+    Integer(u64),
+    Float(f64),
+    String(String),
+}
+
+impl LenientNumber {
+    fn into_u64(self) -> Option<u64> {
+        match self {
+            Self::Integer(integer) => Some(integer),
+            Self::Float(float) => (float.is_finite() && float >= 0.0).then_some(float as u64),
+            Self::String(string) => {
+                let string = string.trim();
+                string
+                    .parse::<u64>()
+                    .ok()
+                    .or_else(|| Self::Float(string.parse().ok()?).into_u64())
+            }
+        }
+    }
+}
+
+/// Deserialize an optional unsigned integer which may be represented as an integer, a float
+/// (which is truncated) or a string containing either, since deployments differ in how they
+/// represent numbers.
+pub fn lenient_option<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    match Option::<LenientNumber>::deserialize(deserializer)? {
+        Some(number) => number
+            .into_u64()
+            .and_then(|number| T::try_from(number).ok())
+            .map(Some)
+            .ok_or_else(|| D::Error::custom("expected an unsigned integer")),
+        None => Ok(None),
+    }
+}
+
+/// The alternative tokens at a position along with their log probabilities.
+type Alternatives = Vec<(String, LogProb)>;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TopLogprobs {
+    Pairs(Alternatives),
+    Map(std::collections::HashMap<String, LogProb>),
+}
+
+/// Deserialize the most likely alternative tokens at each position, which may be represented
+/// either as a list of `[token, logprob]` pairs or as an object mapping each token to its log
+/// probability. Alternatives given as an object are sorted from most to least likely, since
+/// objects don't preserve order.
+pub fn top_logprobs<'de, D>(deserializer: D) -> Result<Option<Vec<Alternatives>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let positions = match Option::<Vec<TopLogprobs>>::deserialize(deserializer)? {
+        Some(positions) => positions,
+        None => return Ok(None),
+    };
+
+    let positions = positions
+        .into_iter()
+        .map(|position| match position {
+            TopLogprobs::Pairs(pairs) => pairs,
+            TopLogprobs::Map(map) => {
+                let mut pairs = map.into_iter().collect::<Vec<_>>();
+                pairs.sort_by(|(_, a), (_, b)| b.cmp(a));
+                pairs
+            }
+        })
+        .collect();
+
+    Ok(Some(positions))
+}
+
+/// Same as [`lenient_option`], for a status code, which must be between 100 and 999 inclusive like
+/// [`StatusCode::from_u16`](reqwest::StatusCode::from_u16) requires.
+pub fn lenient_status<'de, D>(deserializer: D) -> Result<Option<NonZeroU16>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match lenient_option::<D, u16>(deserializer)? {
+        Some(status @ 100..=999) => Ok(NonZeroU16::new(status)),
+        Some(status) => Err(D::Error::custom(format_args!(
+            "expected a status code between 100 and 999, got {status}"
+        ))),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,314 @@
+//! Recording and replaying HTTP interactions, enabled by the `vcr` feature, so that code using this
+//! crate can be tested reproducibly without network access or an api key. See
+//! [`TextSynth::with_cassette`].
+//!
+//! [`TextSynth::with_cassette`]: crate::core::TextSynth::with_cassette
+
+use crate::utils;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// Whether a [`Cassette`] records or replays interactions.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Mode {
+    /// Requests are sent and their responses are saved to the cassette.
+    Record,
+
+    /// Requests aren't sent, the responses saved to the cassette are returned instead.
+    Replay,
+}
+
+/// A request and its response, as saved to a cassette. The headers of the request, which include
+/// the api key, are never saved.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Interaction {
+    method: String,
+    path: String,
+    request_body: Option<String>,
+    status: u16,
+    content_type: Option<String>,
+    response_body: String,
+}
+
+impl Interaction {
+    fn matches(&self, method: &str, path: &str, request_body: Option<&str>) -> bool {
+        self.method == method && self.path == path && self.request_body.as_deref() == request_body
+    }
+
+    fn into_response(self) -> Response {
+        let mut builder = http::Response::builder().status(self.status);
+
+        if let Some(content_type) = &self.content_type {
+            builder = builder.header(CONTENT_TYPE, content_type);
+        }
+
+        builder
+            .body(self.response_body)
+            .expect("invalid recorded response")
+            .into()
+    }
+}
+
+/// The state of a [`Cassette`], depending on its [`Mode`].
+#[derive(Debug)]
+enum State {
+    /// The file interactions are appended to, created once the first one is recorded.
+    Record(Mutex<Option<File>>),
+
+    /// The interactions left to replay, in the order they were recorded.
+    Replay(Mutex<Vec<Interaction>>),
+}
+
+/// A file of recorded HTTP interactions, one JSON object per line.
+///
+/// Interactions are matched by method, path relative to the base URL and request body, so the
+/// same cassette can be replayed against any base URL. Each recorded interaction is replayed once,
+/// in the order it was recorded, so identical requests replay their own responses. Responses are
+/// recorded in full before being returned, so streamed responses are replayed as a single chunk.
+#[derive(Debug)]
+pub struct Cassette {
+    path: PathBuf,
+    state: State,
+}
+
+impl Cassette {
+    /// Replay the cassette at the given path if it exists, otherwise record a new one there.
+    pub fn load_or_record(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+
+        if path.exists() {
+            Self::replay(path)
+        } else {
+            Ok(Self::record(path))
+        }
+    }
+
+    /// Record a new cassette at the given path, overwriting it once the first interaction is
+    /// recorded.
+    pub fn record(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            state: State::Record(Mutex::new(None)),
+        }
+    }
+
+    /// Replay the cassette at the given path, returning an error if it can't be read.
+    pub fn replay(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let interactions = BufReader::new(std::fs::File::open(&path)?)
+            .lines()
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect::<io::Result<_>>()?;
+
+        Ok(Self {
+            path,
+            state: State::Replay(Mutex::new(interactions)),
+        })
+    }
+
+    /// Whether this cassette records or replays interactions.
+    pub const fn mode(&self) -> Mode {
+        match self.state {
+            State::Record(_) => Mode::Record,
+            State::Replay(_) => Mode::Replay,
+        }
+    }
+
+    /// The path of this cassette.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append the interaction to the file, creating it if it's the first one recorded.
+    async fn save(&self, file: &mut Option<File>, interaction: &Interaction) -> io::Result<()> {
+        let file = match file {
+            Some(file) => file,
+            None => {
+                if let Some(parent) = self.path.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
+
+                file.insert(File::create(&self.path).await?)
+            }
+        };
+        let mut line = serde_json::to_vec(interaction)?;
+        line.push(b'\n');
+
+        file.write_all(&line).await?;
+        file.flush().await
+    }
+
+    /// Record or replay the given request.
+    ///
+    /// If a recorded interaction can't be saved, an error response with the
+    /// [`StatusCode::INTERNAL_SERVER_ERROR`] status code is returned instead of the recorded one.
+    pub(crate) async fn send(
+        &self,
+        client: &reqwest::Client,
+        request: Request,
+        base_url: &str,
+    ) -> reqwest::Result<Response> {
+        let method = request.method().to_string();
+        let url = request.url().as_str();
+        let path = url.strip_prefix(base_url).unwrap_or(url).to_owned();
+        let request_body = request
+            .body()
+            .and_then(Body::as_bytes)
+            .map(|body| String::from_utf8_lossy(body).into_owned());
+
+        let file = match &self.state {
+            State::Record(file) => file,
+            State::Replay(interactions) => {
+                let mut interactions = interactions.lock().await;
+                let position = interactions.iter().position(|interaction| {
+                    interaction.matches(&method, &path, request_body.as_deref())
+                });
+
+                return Ok(match position {
+                    Some(position) => interactions.remove(position).into_response(),
+                    None => utils::error_response(
+                        StatusCode::NOT_FOUND,
+                        &format!("no recorded interaction left for {method} {path}"),
+                    ),
+                });
+            }
+        };
+
+        let response = client.execute(request).await?;
+        let status = response.status().as_u16();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .map(str::to_owned);
+        let response_body = response.text().await?;
+
+        let interaction = Interaction {
+            method,
+            path,
+            request_body,
+            status,
+            content_type,
+            response_body,
+        };
+
+        if let Err(error) = self.save(&mut *file.lock().await, &interaction).await {
+            let message = format!("failed to save cassette {}: {error}", self.path.display());
+            return Ok(utils::error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &message,
+            ));
+        }
+
+        Ok(interaction.into_response())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::server::{self, Response};
+    use crate::test_utils::text_synth;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn temporary_path() -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let counter = COUNTER.fetch_add(1, Ordering::SeqCst);
+        std::env::temp_dir().join(format!(
+            "textsynth-cassette-{}-{counter}.jsonl",
+            std::process::id()
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_cassette_record_and_replay() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let base_url = server::serve({
+            let requests = Arc::clone(&requests);
+            move |_| {
+                let credits = 42 + requests.fetch_add(1, Ordering::SeqCst);
+                Response::json(200, format!(r#"{{"credits": {credits}}}"#))
+            }
+        })
+        .await;
+        let path = temporary_path();
+
+        let cassette = Cassette::load_or_record(&path).unwrap();
+        assert_eq!(cassette.mode(), Mode::Record);
+        let text_synth = text_synth::with_base_url(&base_url).with_cassette(cassette);
+
+        for expected in [42, 43] {
+            let credits = text_synth
+                .credits()
+                .await
+                .expect("network error")
+                .expect("api error");
+            assert_eq!(credits, expected);
+        }
+
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+        assert!(!std::fs::read_to_string(&path)
+            .unwrap()
+            .contains(crate::test_utils::api_key()));
+
+        // the recorded path is relative to the base url, so any base url replays it
+        let cassette = Cassette::load_or_record(&path).unwrap();
+        assert_eq!(cassette.mode(), Mode::Replay);
+        let text_synth = text_synth::with_base_url("http://127.0.0.1:1").with_cassette(cassette);
+
+        // identical requests replay the recorded responses in order, each once
+        for expected in [42, 43] {
+            let credits = text_synth
+                .credits()
+                .await
+                .expect("network error")
+                .expect("api error");
+            assert_eq!(credits, expected);
+        }
+
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        let error = text_synth
+            .credits()
+            .await
+            .expect("network error")
+            .expect_err("expected the interactions to be used up");
+        assert_eq!(error.status_code(), StatusCode::NOT_FOUND);
+
+        let error = text_synth
+            .engines()
+            .await
+            .expect("network error")
+            .expect_err("expected a missing interaction");
+        assert_eq!(error.status_code(), StatusCode::NOT_FOUND);
+        assert!(error.message().contains("GET /v1/engines"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cassette_save_error() {
+        let base_url = server::serve(|_| Response::json(200, r#"{"credits": 42}"#)).await;
+        let path = temporary_path();
+        std::fs::create_dir_all(&path).unwrap();
+
+        // the cassette can't be written over a directory
+        let error = text_synth::with_base_url(&base_url)
+            .with_cassette(Cassette::record(&path))
+            .credits()
+            .await
+            .expect("network error")
+            .expect_err("expected an error saving the cassette");
+        assert_eq!(error.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(error.message().contains("failed to save cassette"));
+
+        std::fs::remove_dir(path).unwrap();
+    }
+}
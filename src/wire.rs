@@ -0,0 +1,230 @@
+//! The request bodies sent to the API.
+//!
+//! Every body serialized by this crate is defined here, so that the wire format can be checked
+//! against the [API documentation] in one place. Field names match the documented parameters
+//! exactly, and optional parameters which aren't set are omitted rather than sent as `null`, so
+//! that the API applies its own defaults.
+//!
+//! [API documentation]: https://textsynth.com/documentation.html
+
+use crate::engine::log_probabilities::NonEmptyString;
+use crate::engine::text_completion::{MaxTokens, Stop, TopK, TopP};
+use crate::engine::tokenize::TokenId;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// The prompt of a text completion, either text or token ids.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub(crate) enum Prompt {
+    Text(String),
+    Tokens(Vec<TokenId>),
+}
+
+/// The body of `POST /v1/engines/{engine_id}/completions`.
+#[derive(Debug, Serialize)]
+pub(crate) struct TextCompletionRequest {
+    pub(crate) prompt: Prompt,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) max_tokens: Option<MaxTokens>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) temperature: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) top_k: Option<TopK>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) top_p: Option<TopP>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) stream: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) stop: Option<Stop>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) schema: Option<serde_json::Value>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) grammar: Option<String>,
+
+    /// Sorted by token, so that identical requests serialize to identical bodies.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) logit_bias: Option<BTreeMap<TokenId, f64>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) echo: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) logprobs: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) logprobs_top_n: Option<usize>,
+}
+
+/// The body of `POST /v1/engines/{engine_id}/logprob`.
+#[derive(Debug, Serialize)]
+pub(crate) struct LogProbabilitiesRequest {
+    pub(crate) context: String,
+    pub(crate) continuation: NonEmptyString,
+}
+
+/// The body of `POST /v1/engines/{engine_id}/translate`.
+#[derive(Debug, Serialize)]
+pub(crate) struct TranslateRequest {
+    pub(crate) text: Vec<String>,
+    pub(crate) source_lang: String,
+    pub(crate) target_lang: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) num_beams: Option<u8>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) split_sentences: Option<bool>,
+}
+
+/// The body of `POST /v1/engines/{engine_id}/tokenize`.
+#[derive(Debug, Serialize)]
+pub(crate) struct TokenizeRequest<'a> {
+    pub(crate) text: &'a str,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::text_synth;
+    use serde_json::json;
+
+    fn text_completion_request() -> TextCompletionRequest {
+        TextCompletionRequest {
+            prompt: Prompt::Text("Once upon a time".into()),
+            max_tokens: None,
+            temperature: None,
+            top_k: None,
+            top_p: None,
+            stream: None,
+            stop: None,
+            schema: None,
+            grammar: None,
+            logit_bias: None,
+            echo: None,
+            logprobs: None,
+            logprobs_top_n: None,
+        }
+    }
+
+    #[test]
+    fn test_text_completion_request_omits_unset_fields() {
+        let request = serde_json::to_value(text_completion_request()).unwrap();
+        assert_eq!(request, json!({ "prompt": "Once upon a time" }));
+    }
+
+    #[test]
+    fn test_text_completion_request_all_fields() {
+        let stop = Stop::try_new(["\n"]).unwrap();
+
+        let request = TextCompletionRequest {
+            max_tokens: MaxTokens::new(100, &text_synth::ENGINE_DEFINITION),
+            temperature: Some(0.5),
+            top_k: TopK::new(40),
+            top_p: TopP::new(0.9),
+            stream: Some(true),
+            stop: Some(stop),
+            schema: Some(json!({ "type": "object" })),
+            grammar: Some("root ::= \"yes\" | \"no\"".into()),
+            logit_bias: Some(BTreeMap::from([(50256, -100.0)])),
+            echo: Some(true),
+            logprobs: Some(true),
+            logprobs_top_n: Some(5),
+            ..text_completion_request()
+        };
+
+        assert_eq!(
+            serde_json::to_value(request).unwrap(),
+            json!({
+                "prompt": "Once upon a time",
+                "max_tokens": 100,
+                "temperature": 0.5,
+                "top_k": 40,
+                "top_p": 0.9,
+                "stream": true,
+                "stop": ["\n"],
+                "schema": { "type": "object" },
+                "grammar": "root ::= \"yes\" | \"no\"",
+                "logit_bias": { "50256": -100.0 },
+                "echo": true,
+                "logprobs": true,
+                "logprobs_top_n": 5,
+            })
+        );
+    }
+
+    #[test]
+    fn test_text_completion_request_logit_bias_is_sorted() {
+        let request = TextCompletionRequest {
+            logit_bias: Some((0..64).rev().map(|token| (token * 997, -1.0)).collect()),
+            ..text_completion_request()
+        };
+        let body = serde_json::to_string(&request).unwrap();
+        let tokens = (0..64).map(|token| format!(r#""{}":-1.0"#, token * 997));
+        let logit_bias = tokens.collect::<Vec<_>>().join(",");
+        assert!(body.contains(&format!(r#""logit_bias":{{{logit_bias}}}"#)));
+    }
+
+    #[test]
+    fn test_log_probabilities_request() {
+        let request = LogProbabilitiesRequest {
+            context: "The quick brown fox".into(),
+            continuation: NonEmptyString::new(" jumps".into()).unwrap(),
+        };
+
+        assert_eq!(
+            serde_json::to_value(request).unwrap(),
+            json!({ "context": "The quick brown fox", "continuation": " jumps" })
+        );
+    }
+
+    #[test]
+    fn test_translate_request() {
+        let request = TranslateRequest {
+            text: vec!["Hello".into()],
+            source_lang: "auto".into(),
+            target_lang: "fr".into(),
+            num_beams: None,
+            split_sentences: None,
+        };
+
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            json!({ "text": ["Hello"], "source_lang": "auto", "target_lang": "fr" })
+        );
+
+        let request = TranslateRequest {
+            num_beams: Some(4),
+            split_sentences: Some(false),
+            ..request
+        };
+
+        assert_eq!(
+            serde_json::to_value(request).unwrap(),
+            json!({
+                "text": ["Hello"],
+                "source_lang": "auto",
+                "target_lang": "fr",
+                "num_beams": 4,
+                "split_sentences": false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_tokenize_request() {
+        let request = TokenizeRequest { text: "hello" };
+        assert_eq!(
+            serde_json::to_value(request).unwrap(),
+            json!({ "text": "hello" })
+        );
+    }
+}